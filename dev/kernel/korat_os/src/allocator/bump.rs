@@ -0,0 +1,119 @@
+/*
+
+    Bump allocator
+
+    ----------------------------------------------------------------------------
+
+    The bump allocator hands out memory linearly. It only remembers the
+    address of the next free byte (`next`) and the number of live
+    allocations. Allocation bumps `next` past the new block, and memory is
+    only reclaimed when every allocation has been freed.
+
+           heap_start            next                       heap_end
+               |                  |                             |
+               v                  v                             v
+               +------------------+-----------------------------+
+               |    allocated     |            free             |
+               +------------------+-----------------------------+
+
+    It is the fastest design, but a single long-lived allocation keeps the
+    whole heap from being reused.
+
+*/
+
+use super::{ align_up, HeapAllocator };
+
+use alloc::alloc::Layout;
+use core::ptr;
+
+//------------------------------------------------------------------------------
+//  A heap allocator that never reuses memory until everything is freed.
+//------------------------------------------------------------------------------
+pub struct BumpAllocator
+{
+    heap_start: usize,
+    heap_end: usize,
+    next: usize,
+    allocations: usize,
+}
+
+impl BumpAllocator
+{
+    //--------------------------------------------------------------------------
+    //  Creates a new empty bump allocator.
+    //--------------------------------------------------------------------------
+    pub const fn new() -> Self
+    {
+        BumpAllocator
+        {
+            heap_start: 0,
+            heap_end: 0,
+            next: 0,
+            allocations: 0,
+        }
+    }
+}
+
+impl Default for BumpAllocator
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for BumpAllocator
+{
+    //--------------------------------------------------------------------------
+    //  init
+    //--------------------------------------------------------------------------
+    unsafe fn init( &mut self, heap_start: usize, heap_size: usize )
+    {
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + heap_size;
+        self.next = heap_start;
+    }
+
+    //--------------------------------------------------------------------------
+    //  extend
+    //--------------------------------------------------------------------------
+    unsafe fn extend( &mut self, start: usize, size: usize )
+    {
+        assert_eq!(start, self.heap_end);
+        self.heap_end += size;
+    }
+
+    //--------------------------------------------------------------------------
+    //  allocate
+    //--------------------------------------------------------------------------
+    fn allocate( &mut self, layout: Layout ) -> *mut u8
+    {
+        let alloc_start = align_up(self.next, layout.align());
+        let alloc_end = match alloc_start.checked_add(layout.size())
+        {
+            Some(end) => end,
+            None => return ptr::null_mut(),
+        };
+
+        if alloc_end > self.heap_end
+        {
+            return ptr::null_mut();
+        }
+
+        self.next = alloc_end;
+        self.allocations += 1;
+        alloc_start as *mut u8
+    }
+
+    //--------------------------------------------------------------------------
+    //  deallocate
+    //--------------------------------------------------------------------------
+    unsafe fn deallocate( &mut self, _ptr: *mut u8, _layout: Layout )
+    {
+        self.allocations -= 1;
+        if self.allocations == 0
+        {
+            self.next = self.heap_start;
+        }
+    }
+}
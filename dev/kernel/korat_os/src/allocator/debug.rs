@@ -0,0 +1,210 @@
+/*
+
+    Debug heap
+
+    ----------------------------------------------------------------------------
+
+    With the `heap_debug` feature, `DebugHeap` wraps the heap allocator to
+    catch memory bugs where they happen, instead of letting them corrupt the
+    allocator's bookkeeping and crash somewhere else later.
+
+    Every block gets a header and a red zone (guard bytes) on each side:
+
+      +------+--------+---------------+-------------------+---------------+
+      | link | header | front guard   | data              | back guard    |
+      +------+--------+---------------+-------------------+---------------+
+                                      ^
+                                      pointer returned by `alloc`
+
+    The first bytes are left to the allocator, which stores its free list
+    links there, so the header survives a free. The header records the state
+    of the block and the layout it was allocated with.
+
+    | Pattern        | Byte   | Fills                 | Shows                  |
+    | -------------- | ------ | --------------------- | ---------------------- |
+    | `GUARD_BYTE`   | `0xfd` | Red zones             | Overflows, underflows  |
+    | `ALLOC_POISON` | `0xcd` | New data              | Uninitialized reads    |
+    | `FREE_POISON`  | `0xdd` | Freed blocks          | Use after free         |
+
+    On `dealloc`, the header and the red zones are checked. The kernel panics
+    with the address of the block if
+
+    - the block is already free (double free),
+    - the header is not one written by `alloc` (invalid pointer or underflow),
+    - the layout differs from the one it was allocated with,
+    - a guard byte has changed (buffer overflow or underflow).
+
+    A double free is only caught as long as the memory has not been handed
+    out again.
+
+*/
+
+use alloc::alloc::{ GlobalAlloc, Layout };
+use core::mem;
+use core::ptr;
+use core::slice;
+
+//  The byte that fills the red zones.
+pub const GUARD_BYTE: u8 = 0xfd;
+
+//  The byte that fills newly allocated memory.
+pub const ALLOC_POISON: u8 = 0xcd;
+
+//  The byte that fills freed memory.
+pub const FREE_POISON: u8 = 0xdd;
+
+//  The size of each red zone.
+pub const RED_ZONE_SIZE: usize = 16;
+
+//  The bytes at the start of a block that are left to the allocator.
+const LINK_SIZE: usize = 16;
+
+//  Header states.
+const ALLOCATED: u64 = 0xa110_ca7e_d0d0_beef;
+const FREED: u64 = 0xf4ee_d0d0_dead_beef;
+
+//------------------------------------------------------------------------------
+//  Stored in front of the front guard of every block.
+//------------------------------------------------------------------------------
+#[repr(C)]
+struct Header
+{
+    state: u64,
+    size: usize,
+    align: usize,
+}
+
+//------------------------------------------------------------------------------
+//  A GlobalAlloc that adds red zones and poisoning to the allocator it wraps,
+//  and checks every deallocation.
+//------------------------------------------------------------------------------
+pub struct DebugHeap<A>
+{
+    inner: A,
+}
+
+impl<A> DebugHeap<A>
+{
+    pub const fn new( inner: A ) -> Self
+    {
+        DebugHeap { inner }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the wrapped allocator.
+    //--------------------------------------------------------------------------
+    pub fn inner( &self ) -> &A
+    {
+        &self.inner
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns the offset of the data from the start of the block, and the layout
+//  of the whole block.
+//------------------------------------------------------------------------------
+fn block_layout( layout: Layout ) -> Option<(usize, Layout)>
+{
+    let align = layout.align().max(mem::align_of::<Header>());
+    let offset = super::align_up
+    (
+        LINK_SIZE + mem::size_of::<Header>() + RED_ZONE_SIZE,
+        align
+    );
+    let size = offset
+        .checked_add(layout.size())?
+        .checked_add(RED_ZONE_SIZE)?;
+    let block = Layout::from_size_align(size, align).ok()?;
+    Some((offset, block))
+}
+
+//------------------------------------------------------------------------------
+//  Returns the header of the block whose data starts at `ptr`.
+//------------------------------------------------------------------------------
+fn header( ptr: *mut u8 ) -> *mut Header
+{
+    ptr.wrapping_sub(RED_ZONE_SIZE + mem::size_of::<Header>()) as *mut Header
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugHeap<A>
+{
+    unsafe fn alloc( &self, layout: Layout ) -> *mut u8
+    {
+        let (offset, block) = match block_layout(layout)
+        {
+            Some(block) => block,
+            None => return ptr::null_mut(),
+        };
+        let start = self.inner.alloc(block);
+        if start.is_null()
+        {
+            return start;
+        }
+
+        let ptr = start.add(offset);
+        header(ptr).write(Header
+        {
+            state: ALLOCATED,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr.sub(RED_ZONE_SIZE).write_bytes(GUARD_BYTE, RED_ZONE_SIZE);
+        ptr.write_bytes(ALLOC_POISON, layout.size());
+        ptr.add(layout.size()).write_bytes(GUARD_BYTE, RED_ZONE_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc( &self, ptr: *mut u8, layout: Layout )
+    {
+        let header = header(ptr);
+        let Header { state, size, align } = header.read();
+        match state
+        {
+            ALLOCATED => {},
+            FREED => panic!("heap: double free of {:p}", ptr),
+            _ => panic!("heap: freeing {:p}, which is not allocated", ptr),
+        }
+        if size != layout.size() || align != layout.align()
+        {
+            panic!
+            (
+                "heap: freeing {:p} with size {} and align {}, but it was \
+                allocated with size {} and align {}",
+                ptr,
+                layout.size(),
+                layout.align(),
+                size,
+                align
+            );
+        }
+
+        let front =
+            slice::from_raw_parts(ptr.sub(RED_ZONE_SIZE), RED_ZONE_SIZE);
+        if let Some(i) = front.iter().position(|&b| b != GUARD_BYTE)
+        {
+            panic!
+            (
+                "heap: buffer underflow in {:p}: front guard byte {} changed",
+                ptr,
+                i
+            );
+        }
+        let back = slice::from_raw_parts(ptr.add(size), RED_ZONE_SIZE);
+        if let Some(i) = back.iter().position(|&b| b != GUARD_BYTE)
+        {
+            panic!
+            (
+                "heap: buffer overflow in {:p}: back guard byte {} changed",
+                ptr,
+                i
+            );
+        }
+
+        let (offset, block) = block_layout(layout)
+            .expect("layout was valid when allocated");
+        ptr.sub(RED_ZONE_SIZE)
+            .write_bytes(FREE_POISON, RED_ZONE_SIZE * 2 + size);
+        (*header).state = FREED;
+        self.inner.dealloc(ptr.sub(offset), block);
+    }
+}
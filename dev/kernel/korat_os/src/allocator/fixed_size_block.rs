@@ -0,0 +1,169 @@
+/*
+
+    Fixed-size block allocator
+
+    ----------------------------------------------------------------------------
+
+    The fixed-size block allocator rounds every allocation up to one of a few
+    size classes and keeps a separate free list per class.
+
+    | Class | Block size |
+    | ----- | ---------- |
+    | 0     | 8 B        |
+    | 1     | 16 B       |
+    | ...   | ...        |
+    | 8     | 2048 B     |
+
+    Allocating and freeing a block is a single push or pop on the list of its
+    class. Lists start out empty and are filled by freed blocks, so a new
+    block is carved from the fallback linked list allocator whenever its list
+    is empty. Allocations larger than the largest class go straight to the
+    fallback allocator.
+
+    Rounding up wastes some memory inside each block, but this design is fast
+    and does not fragment for the small allocations that are most common in
+    the kernel.
+
+*/
+
+use super::HeapAllocator;
+use super::linked_list::LinkedListAllocator;
+
+use alloc::alloc::Layout;
+use core::mem;
+use core::ptr::{ self, NonNull };
+
+//------------------------------------------------------------------------------
+//  The block sizes to use.
+//
+//  The sizes must each be power of 2 because they are also used as the block
+//  alignment (alignments must be always powers of 2).
+//------------------------------------------------------------------------------
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+//------------------------------------------------------------------------------
+//  A free block.
+//------------------------------------------------------------------------------
+struct ListNode
+{
+    next: Option<&'static mut ListNode>,
+}
+
+//------------------------------------------------------------------------------
+//  A heap allocator that serves small allocations from per-size free lists.
+//------------------------------------------------------------------------------
+pub struct FixedSizeBlockAllocator
+{
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+}
+
+impl FixedSizeBlockAllocator
+{
+    //--------------------------------------------------------------------------
+    //  Creates a new empty fixed-size block allocator.
+    //--------------------------------------------------------------------------
+    pub const fn new() -> Self
+    {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator
+        {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the index of the smallest block size class that can hold the
+    //  given layout, or `None` if it is too large for every class.
+    //--------------------------------------------------------------------------
+    fn list_index( layout: &Layout ) -> Option<usize>
+    {
+        let required_block_size = layout.size().max(layout.align());
+        BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+    }
+}
+
+impl Default for FixedSizeBlockAllocator
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for FixedSizeBlockAllocator
+{
+    //--------------------------------------------------------------------------
+    //  init
+    //--------------------------------------------------------------------------
+    unsafe fn init( &mut self, heap_start: usize, heap_size: usize )
+    {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    //--------------------------------------------------------------------------
+    //  extend
+    //--------------------------------------------------------------------------
+    unsafe fn extend( &mut self, start: usize, size: usize )
+    {
+        self.fallback_allocator.extend(start, size);
+    }
+
+    //--------------------------------------------------------------------------
+    //  allocate
+    //--------------------------------------------------------------------------
+    fn allocate( &mut self, layout: Layout ) -> *mut u8
+    {
+        let index = match Self::list_index(&layout)
+        {
+            Some(index) => index,
+            None => return self.fallback_allocator.allocate(layout),
+        };
+
+        match self.list_heads[index].take()
+        {
+            Some(node) =>
+            {
+                self.list_heads[index] = node.next.take();
+                node as *mut ListNode as *mut u8
+            },
+            None =>
+            {
+                //  No block exists in list, so allocate a new one. Only works
+                //  if all block sizes are a power of 2.
+                let block_size = BLOCK_SIZES[index];
+                let block_align = block_size;
+                let layout = Layout::from_size_align(block_size, block_align)
+                    .unwrap();
+                self.fallback_allocator.allocate(layout)
+            },
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  deallocate
+    //--------------------------------------------------------------------------
+    unsafe fn deallocate( &mut self, ptr: *mut u8, layout: Layout )
+    {
+        let index = match Self::list_index(&layout)
+        {
+            Some(index) => index,
+            None => return self.fallback_allocator.deallocate(ptr, layout),
+        };
+
+        //  Verify that block has size and alignment required for storing
+        //  node.
+        assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+        assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+
+        let new_node = ListNode
+        {
+            next: self.list_heads[index].take(),
+        };
+        let new_node_ptr = NonNull::new(ptr as *mut ListNode)
+            .expect("deallocating a null pointer");
+        ptr::write(new_node_ptr.as_ptr(), new_node);
+        self.list_heads[index] = Some(&mut *new_node_ptr.as_ptr());
+    }
+}
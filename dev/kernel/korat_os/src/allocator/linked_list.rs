@@ -0,0 +1,266 @@
+/*
+
+    Linked list allocator
+
+    ----------------------------------------------------------------------------
+
+    The linked list allocator keeps the free regions of the heap in a singly
+    linked list. Each free region stores its own size and the address of the
+    next free region in its first bytes, so no extra memory is needed.
+
+      head
+       |     +------+------+-------+       +------+------+-----------+
+       +---> | size | next |       |   +-> | size | next |           |
+             +------+------+-------+   |   +------+------+-----------+
+                       |               |              |
+                       +---------------+              +---> null
+
+    The list is sorted by address. When a region is freed it is merged with
+    the free regions directly before and after it, so the heap does not
+    break up into ever smaller pieces.
+
+    Allocation walks the list and takes the first region that is large
+    enough (first fit), which takes time linear in the number of regions.
+
+*/
+
+use super::{ align_up, HeapAllocator };
+
+use alloc::alloc::Layout;
+use core::mem;
+use core::ptr;
+
+//------------------------------------------------------------------------------
+//  A free region of the heap.
+//------------------------------------------------------------------------------
+struct ListNode
+{
+    size: usize,
+    next: *mut ListNode,
+}
+
+impl ListNode
+{
+    fn start_addr( &self ) -> usize
+    {
+        self as *const Self as usize
+    }
+
+    fn end_addr( &self ) -> usize
+    {
+        self.start_addr() + self.size
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A heap allocator that keeps an address-ordered list of free regions.
+//------------------------------------------------------------------------------
+pub struct LinkedListAllocator
+{
+    head: *mut ListNode,
+}
+
+//  The free list is only reachable through the allocator, which is always
+//  accessed through a lock.
+unsafe impl Send for LinkedListAllocator {}
+
+impl LinkedListAllocator
+{
+    //--------------------------------------------------------------------------
+    //  Creates a new empty linked list allocator.
+    //--------------------------------------------------------------------------
+    pub const fn new() -> Self
+    {
+        LinkedListAllocator
+        {
+            head: ptr::null_mut(),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Adds the given memory region to the free list, merging it with the
+    //  neighbouring free regions.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  region is unused and not already in the list.
+    //--------------------------------------------------------------------------
+    unsafe fn add_free_region( &mut self, addr: usize, size: usize )
+    {
+        assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
+        assert!(size >= mem::size_of::<ListNode>());
+
+        //  Find the free regions around the new one.
+        let mut prev: *mut ListNode = ptr::null_mut();
+        let mut next = self.head;
+        while !next.is_null() && (next as usize) < addr
+        {
+            prev = next;
+            next = (*next).next;
+        }
+
+        //  Merge with the following region.
+        let mut size = size;
+        if !next.is_null() && addr + size == next as usize
+        {
+            size += (*next).size;
+            next = (*next).next;
+        }
+
+        //  Merge with the preceding region.
+        if !prev.is_null() && (*prev).end_addr() == addr
+        {
+            (*prev).size += size;
+            (*prev).next = next;
+            return;
+        }
+
+        let node = addr as *mut ListNode;
+        node.write(ListNode { size, next });
+        if prev.is_null()
+        {
+            self.head = node;
+        }
+        else
+        {
+            (*prev).next = node;
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the address to use for an allocation of the given size and
+    //  alignment in the region, if it fits.
+    //
+    //  The unused memory before and after the allocation must either be empty
+    //  or large enough to be put back into the list.
+    //--------------------------------------------------------------------------
+    fn alloc_start( region: &ListNode, size: usize, align: usize )
+        -> Option<usize>
+    {
+        let node_size = mem::size_of::<ListNode>();
+
+        let mut alloc_start = align_up(region.start_addr(), align);
+        if alloc_start != region.start_addr()
+            && alloc_start - region.start_addr() < node_size
+        {
+            alloc_start = align_up(region.start_addr() + node_size, align);
+        }
+
+        let alloc_end = alloc_start.checked_add(size)?;
+        if alloc_end > region.end_addr()
+        {
+            return None;
+        }
+
+        let excess_size = region.end_addr() - alloc_end;
+        if excess_size > 0 && excess_size < node_size
+        {
+            return None;
+        }
+
+        Some(alloc_start)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Adjusts the given layout so that the allocated memory region is also
+    //  capable of storing a `ListNode`.
+    //--------------------------------------------------------------------------
+    fn size_align( layout: Layout ) -> (usize, usize)
+    {
+        let layout = layout
+            .align_to(mem::align_of::<ListNode>())
+            .expect("adjusting alignment failed")
+            .pad_to_align();
+        let size = layout.size().max(mem::size_of::<ListNode>());
+        (size, layout.align())
+    }
+}
+
+impl Default for LinkedListAllocator
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl HeapAllocator for LinkedListAllocator
+{
+    //--------------------------------------------------------------------------
+    //  init
+    //--------------------------------------------------------------------------
+    unsafe fn init( &mut self, heap_start: usize, heap_size: usize )
+    {
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    //--------------------------------------------------------------------------
+    //  extend
+    //--------------------------------------------------------------------------
+    unsafe fn extend( &mut self, start: usize, size: usize )
+    {
+        self.add_free_region(start, size);
+    }
+
+    //--------------------------------------------------------------------------
+    //  allocate
+    //--------------------------------------------------------------------------
+    fn allocate( &mut self, layout: Layout ) -> *mut u8
+    {
+        let (size, align) = Self::size_align(layout);
+
+        let mut prev: *mut ListNode = ptr::null_mut();
+        let mut current = self.head;
+        while !current.is_null()
+        {
+            let region = unsafe { &*current };
+            if let Some(alloc_start) = Self::alloc_start(region, size, align)
+            {
+                let region_start = region.start_addr();
+                let region_end = region.end_addr();
+                let alloc_end = alloc_start + size;
+
+                //  Unlink the region and give back the unused parts.
+                unsafe
+                {
+                    if prev.is_null()
+                    {
+                        self.head = region.next;
+                    }
+                    else
+                    {
+                        (*prev).next = region.next;
+                    }
+
+                    if alloc_start > region_start
+                    {
+                        self.add_free_region
+                        (
+                            region_start,
+                            alloc_start - region_start
+                        );
+                    }
+                    if region_end > alloc_end
+                    {
+                        self.add_free_region(alloc_end, region_end - alloc_end);
+                    }
+                }
+
+                return alloc_start as *mut u8;
+            }
+
+            prev = current;
+            current = region.next;
+        }
+
+        ptr::null_mut()
+    }
+
+    //--------------------------------------------------------------------------
+    //  deallocate
+    //--------------------------------------------------------------------------
+    unsafe fn deallocate( &mut self, ptr: *mut u8, layout: Layout )
+    {
+        let (size, _) = Self::size_align(layout);
+        self.add_free_region(ptr as usize, size);
+    }
+}
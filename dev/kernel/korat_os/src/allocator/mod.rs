@@ -1,11 +1,71 @@
+/*
+
+    Heap allocator
+
+    ----------------------------------------------------------------------------
+
+    The kernel heap is a virtual memory region starting at `HEAP_START`. At
+    boot, `init_heap` maps its first `HEAP_SIZE` bytes to physical frames and
+    hands them to the allocator chosen with a cargo feature.
+
+    When the allocator runs out of memory, the heap grows: more pages are
+    mapped right after the current heap end and given to the allocator, until
+    the heap reaches its maximum size (`HEAP_MAX_SIZE` by default, see
+    `set_heap_max_size`). Only then does an allocation fail.
+
+    | Feature                 | Design                           |
+    | ----------------------- | -------------------------------- |
+    | `heap_bump`             | Bump allocator                   |
+    | `heap_linked_list`      | Linked list allocator            |
+    | `heap_fixed_size_block` | Fixed-size block allocator       |
+
+    `heap_fixed_size_block` is enabled by default. To use another design,
+    disable the default features, e.g.
+    `cargo test --no-default-features --features heap_bump`.
+
+    The `heap_debug` feature can be added to any design. It wraps the heap in
+    `DebugHeap`, which adds red zones and poison patterns to every block and
+    panics on double frees and corrupted blocks, e.g.
+    `cargo test --features heap_debug`.
+
+    All designs implement `HeapAllocator` and are wrapped in `Locked`, which
+    serializes access and implements `GlobalAlloc`. The global allocator is
+    wrapped once more in `Tracking`, which keeps heap statistics (see
+    `heap_stats`).
+
+*/
+
+mod bump;
+#[cfg(feature = "heap_debug")]
+mod debug;
+mod fixed_size_block;
+mod linked_list;
+mod stats;
+
+pub use bump::BumpAllocator;
+#[cfg(feature = "heap_debug")]
+pub use debug::{
+    ALLOC_POISON,
+    DebugHeap,
+    FREE_POISON,
+    GUARD_BYTE,
+    RED_ZONE_SIZE,
+};
+pub use fixed_size_block::FixedSizeBlockAllocator;
+pub use linked_list::LinkedListAllocator;
+pub use stats::{ HeapStats, HeapStatsDiff, SIZE_CLASSES, Tracking };
+
+use crate::memory;
+
 use alloc::alloc::{ GlobalAlloc, Layout };
 use core::ptr::null_mut;
-use linked_list_allocator::LockedHeap;
+use core::sync::atomic::{ AtomicUsize, Ordering };
 use x86_64::VirtAddr;
 use x86_64::structures::paging::{
     FrameAllocator,
     Mapper,
     Page,
+    PageSize,
     PageTableFlags,
     Size4KiB,
 };
@@ -13,42 +73,305 @@ use x86_64::structures::paging::mapper::MapToError;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
+pub const HEAP_MAX_SIZE: usize = 32 * 1024 * 1024;
+
+//  The heap grows by at least this many bytes at a time.
+const HEAP_GROWTH_SIZE: usize = 64 * 1024;
+
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_START + HEAP_MAX_SIZE);
+
+#[cfg(any(
+    all(feature = "heap_bump", feature = "heap_linked_list"),
+    all(feature = "heap_bump", feature = "heap_fixed_size_block"),
+    all(feature = "heap_linked_list", feature = "heap_fixed_size_block"),
+    not(any(
+        feature = "heap_bump",
+        feature = "heap_linked_list",
+        feature = "heap_fixed_size_block",
+    )),
+))]
+compile_error!("exactly one heap allocator feature must be enabled");
+
+#[cfg(feature = "heap_bump")]
+type Heap = BumpAllocator;
 
+#[cfg(feature = "heap_linked_list")]
+type Heap = LinkedListAllocator;
+
+#[cfg(feature = "heap_fixed_size_block")]
+type Heap = FixedSizeBlockAllocator;
+
+#[cfg(not(feature = "heap_debug"))]
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Tracking<Locked<Heap>> =
+    Tracking::new(Locked::new(Heap::new()));
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>>
+#[cfg(feature = "heap_debug")]
+#[global_allocator]
+static ALLOCATOR: Tracking<DebugHeap<Locked<Heap>>> =
+    Tracking::new(DebugHeap::new(Locked::new(Heap::new())));
+
+//------------------------------------------------------------------------------
+//  Maps the initial heap and initializes the allocator.
+//
+//  The kernel memory must have been installed with `memory::install`.
+//------------------------------------------------------------------------------
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>>
+{
+    let (mapped, result) = map_heap_pages(HEAP_START, HEAP_SIZE);
+    HEAP_END.store(HEAP_START + mapped, Ordering::Relaxed);
+    result?;
+
+    unsafe
+    {
+        heap().lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of bytes currently mapped for the heap.
+//------------------------------------------------------------------------------
+pub fn heap_size() -> usize
+{
+    HEAP_END.load(Ordering::Relaxed) - HEAP_START
+}
+
+//------------------------------------------------------------------------------
+//  Returns the heap allocator below the tracking and debug layers.
+//------------------------------------------------------------------------------
+#[cfg(not(feature = "heap_debug"))]
+fn heap() -> &'static Locked<Heap>
+{
+    ALLOCATOR.inner()
+}
+
+#[cfg(feature = "heap_debug")]
+fn heap() -> &'static Locked<Heap>
+{
+    ALLOCATOR.inner().inner()
+}
+
+//------------------------------------------------------------------------------
+//  Returns a snapshot of the heap statistics.
+//------------------------------------------------------------------------------
+pub fn heap_stats() -> HeapStats
+{
+    ALLOCATOR.stats()
+}
+
+//------------------------------------------------------------------------------
+//  Sets the size the heap may grow to, at most `HEAP_MAX_SIZE` (the size of
+//  the virtual range reserved for the heap). Memory that is already mapped
+//  stays part of the heap.
+//------------------------------------------------------------------------------
+pub fn set_heap_max_size( max_size: usize )
+{
+    assert!(max_size <= HEAP_MAX_SIZE, "heap max size is too large");
+    HEAP_LIMIT.store(HEAP_START + max_size, Ordering::Relaxed);
+}
+
+//------------------------------------------------------------------------------
+//  Maps the pages of the heap region `start..start + size` to new frames,
+//  stopping at the first failure.
+//
+//  Returns the number of bytes mapped and the error that stopped it, if any.
+//------------------------------------------------------------------------------
+fn map_heap_pages( start: usize, size: usize )
+    -> (usize, Result<(), MapToError<Size4KiB>>)
 {
     let page_range =
     {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
+        let heap_start = VirtAddr::new(start as u64);
+        let heap_end = heap_start + size - 1u64;
+        let heap_start_page = Page::<Size4KiB>::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
-    for page in page_range
+    memory::with_kernel_memory(|memory|
     {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe
+        let mut mapped = 0;
+        for page in page_range
         {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush()
-        };
+            let frame = match memory.frame_allocator.allocate_frame()
+            {
+                Some(frame) => frame,
+                None => return (mapped, Err(MapToError::FrameAllocationFailed)),
+            };
+            let flags = memory::supported_flags
+            (
+                PageTableFlags::PRESENT
+                    | PageTableFlags::WRITABLE
+                    | PageTableFlags::NO_EXECUTE
+            );
+            let map_to_result = unsafe
+            {
+                memory.mapper.map_to
+                (
+                    page,
+                    frame,
+                    flags,
+                    &mut memory.frame_allocator
+                )
+            };
+            match map_to_result
+            {
+                Ok(flush) => flush.flush(),
+                Err(err) => return (mapped, Err(err)),
+            }
+            mapped += Size4KiB::SIZE as usize;
+        }
+        (mapped, Ok(()))
+    })
+    .expect("kernel memory is not installed")
+}
+
+//------------------------------------------------------------------------------
+//  Grows the heap so that an allocation with the given layout can succeed,
+//  and hands the new memory to the allocator.
+//
+//  Returns whether the heap has grown.
+//------------------------------------------------------------------------------
+fn grow_heap( allocator: &mut impl HeapAllocator, layout: Layout ) -> bool
+{
+    let heap_end = HEAP_END.load(Ordering::Relaxed);
+    let heap_limit = HEAP_LIMIT.load(Ordering::Relaxed);
+    let available = heap_limit.saturating_sub(heap_end)
+        & !(Size4KiB::SIZE as usize - 1);
+
+    //  Leave room for alignment padding and the allocator's bookkeeping.
+    let required = layout.size()
+        .saturating_add(layout.align())
+        .saturating_add(64);
+    if required > available
+    {
+        return false;
     }
 
+    let size = align_up
+    (
+        required.max(HEAP_GROWTH_SIZE),
+        Size4KiB::SIZE as usize
+    ).min(available);
+
+    let (mapped, _) = map_heap_pages(heap_end, size);
+    if mapped == 0
+    {
+        return false;
+    }
+
+    HEAP_END.store(heap_end + mapped, Ordering::Relaxed);
     unsafe
     {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        allocator.extend(heap_end, mapped);
     }
+    true
+}
 
-    Ok(())
+//------------------------------------------------------------------------------
+//  A heap allocator design that manages a region of memory.
+//
+//  Implementations do not need to synchronize, since they are only accessed
+//  through `Locked`.
+//------------------------------------------------------------------------------
+pub trait HeapAllocator
+{
+    //--------------------------------------------------------------------------
+    //  Initializes the allocator with the given heap bounds.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  given heap bounds are valid and that the heap is unused. This method
+    //  must be called only once.
+    //--------------------------------------------------------------------------
+    unsafe fn init( &mut self, heap_start: usize, heap_size: usize );
+
+    //--------------------------------------------------------------------------
+    //  Adds the memory region `start..start + size`, which directly follows
+    //  the end of the heap, to the allocator.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  region is mapped and unused.
+    //--------------------------------------------------------------------------
+    unsafe fn extend( &mut self, start: usize, size: usize );
+
+    //--------------------------------------------------------------------------
+    //  Allocates memory for the given layout, or returns a null pointer.
+    //--------------------------------------------------------------------------
+    fn allocate( &mut self, layout: Layout ) -> *mut u8;
+
+    //--------------------------------------------------------------------------
+    //  Frees memory previously returned by `allocate` with the same layout.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  memory is no longer in use.
+    //--------------------------------------------------------------------------
+    unsafe fn deallocate( &mut self, ptr: *mut u8, layout: Layout );
+}
+
+//------------------------------------------------------------------------------
+//  A wrapper around spin::Mutex to permit trait implementations.
+//------------------------------------------------------------------------------
+pub struct Locked<A>
+{
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A>
+{
+    pub const fn new( inner: A ) -> Self
+    {
+        Locked
+        {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock( &self ) -> spin::MutexGuard<'_, A>
+    {
+        self.inner.lock()
+    }
+}
+
+unsafe impl<A: HeapAllocator> GlobalAlloc for Locked<A>
+{
+    unsafe fn alloc( &self, layout: Layout ) -> *mut u8
+    {
+        let mut allocator = self.lock();
+
+        let ptr = allocator.allocate(layout);
+        if !ptr.is_null()
+        {
+            return ptr;
+        }
+
+        //  Out of memory, so grow the heap and try again.
+        if grow_heap(&mut *allocator, layout)
+        {
+            allocator.allocate(layout)
+        }
+        else
+        {
+            null_mut()
+        }
+    }
+
+    unsafe fn dealloc( &self, ptr: *mut u8, layout: Layout )
+    {
+        self.lock().deallocate(ptr, layout)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Align the given address `addr` upwards to alignment `align`.
+//
+//  Requires that `align` is a power of two.
+//------------------------------------------------------------------------------
+fn align_up( addr: usize, align: usize ) -> usize
+{
+    (addr + align - 1) & !(align - 1)
 }
 
 pub struct Dummy;
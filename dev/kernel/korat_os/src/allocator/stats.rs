@@ -0,0 +1,276 @@
+/*
+
+    Heap statistics
+
+    ----------------------------------------------------------------------------
+
+    `Tracking` wraps the global allocator and counts what goes through it:
+
+    | Statistic            | Meaning                                         |
+    | -------------------- | ----------------------------------------------- |
+    | `current_bytes`      | Bytes allocated and not freed yet               |
+    | `peak_bytes`         | Highest `current_bytes` so far                  |
+    | `allocations`        | Successful allocations                          |
+    | `frees`              | Deallocations                                   |
+    | `failures`           | Allocations that returned a null pointer        |
+    | `last_failure`       | Size and alignment of the last failed request   |
+    | `size_classes`       | Allocations per size class                      |
+
+    Size class `i` counts the allocations of up to `16 << i` bytes, and the
+    last class counts everything larger.
+
+    The counters are atomics, so updating them needs no lock and never
+    allocates. `heap_stats` takes a snapshot of them, and `HeapStats::diff`
+    compares two snapshots, e.g. to check that some code returned everything
+    it allocated:
+
+        let before = allocator::heap_stats();
+        run_something();
+        assert!(allocator::heap_stats().diff(&before).is_balanced());
+
+*/
+
+use alloc::alloc::{ GlobalAlloc, Layout };
+use core::fmt;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+//  The number of size classes in the histogram.
+pub const SIZE_CLASSES: usize = 10;
+
+//  The largest size of the first size class.
+const SMALLEST_CLASS_SIZE: usize = 16;
+
+//------------------------------------------------------------------------------
+//  A snapshot of the heap statistics.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats
+{
+    pub current_bytes: usize,
+    pub peak_bytes: usize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+    pub last_failure: Option<Layout>,
+    pub size_classes: [usize; SIZE_CLASSES],
+}
+
+impl HeapStats
+{
+    //--------------------------------------------------------------------------
+    //  Returns the number of allocations that have not been freed.
+    //--------------------------------------------------------------------------
+    pub fn live_allocations( &self ) -> usize
+    {
+        self.allocations.saturating_sub(self.frees)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns what changed since the `earlier` snapshot.
+    //--------------------------------------------------------------------------
+    pub fn diff( &self, earlier: &HeapStats ) -> HeapStatsDiff
+    {
+        HeapStatsDiff
+        {
+            bytes: self.current_bytes as isize - earlier.current_bytes as isize,
+            allocations: self.allocations - earlier.allocations,
+            frees: self.frees - earlier.frees,
+            failures: self.failures - earlier.failures,
+        }
+    }
+}
+
+impl fmt::Display for HeapStats
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        writeln!(f, "heap statistics:")?;
+        writeln!
+        (
+            f,
+            "  in use: {} bytes (peak {} bytes) in {} allocations",
+            self.current_bytes,
+            self.peak_bytes,
+            self.live_allocations()
+        )?;
+        writeln!
+        (
+            f,
+            "  allocations: {}, frees: {}, failures: {}",
+            self.allocations,
+            self.frees,
+            self.failures
+        )?;
+        if let Some(layout) = self.last_failure
+        {
+            writeln!
+            (
+                f,
+                "  last failure: size {}, align {}",
+                layout.size(),
+                layout.align()
+            )?;
+        }
+        for (class, &count) in self.size_classes.iter().enumerate()
+        {
+            if class == SIZE_CLASSES - 1
+            {
+                let size = class_size(class - 1);
+                writeln!(f, "  > {:>5} bytes: {}", size, count)?;
+            }
+            else
+            {
+                writeln!(f, "  <= {:>4} bytes: {}", class_size(class), count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+//  The change of the heap statistics between two snapshots.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStatsDiff
+{
+    pub bytes: isize,
+    pub allocations: usize,
+    pub frees: usize,
+    pub failures: usize,
+}
+
+impl HeapStatsDiff
+{
+    //--------------------------------------------------------------------------
+    //  Returns whether everything allocated in between was freed again.
+    //--------------------------------------------------------------------------
+    pub fn is_balanced( &self ) -> bool
+    {
+        self.bytes == 0 && self.allocations == self.frees
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A GlobalAlloc that records heap statistics for the allocator it wraps.
+//------------------------------------------------------------------------------
+pub struct Tracking<A>
+{
+    inner: A,
+    current_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    failures: AtomicUsize,
+    last_failure_size: AtomicUsize,
+    last_failure_align: AtomicUsize,
+    size_classes: [AtomicUsize; SIZE_CLASSES],
+}
+
+impl<A> Tracking<A>
+{
+    pub const fn new( inner: A ) -> Self
+    {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicUsize = AtomicUsize::new(0);
+
+        Tracking
+        {
+            inner,
+            current_bytes: ZERO,
+            peak_bytes: ZERO,
+            allocations: ZERO,
+            frees: ZERO,
+            failures: ZERO,
+            last_failure_size: ZERO,
+            last_failure_align: ZERO,
+            size_classes: [ZERO; SIZE_CLASSES],
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the wrapped allocator.
+    //--------------------------------------------------------------------------
+    pub fn inner( &self ) -> &A
+    {
+        &self.inner
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns a snapshot of the statistics.
+    //--------------------------------------------------------------------------
+    pub fn stats( &self ) -> HeapStats
+    {
+        let mut size_classes = [0; SIZE_CLASSES];
+        for (count, class) in size_classes.iter_mut().zip(&self.size_classes)
+        {
+            *count = class.load(Ordering::Relaxed);
+        }
+
+        let last_failure = Layout::from_size_align
+        (
+            self.last_failure_size.load(Ordering::Relaxed),
+            self.last_failure_align.load(Ordering::Relaxed)
+        )
+        .ok();
+
+        HeapStats
+        {
+            current_bytes: self.current_bytes.load(Ordering::Relaxed),
+            peak_bytes: self.peak_bytes.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+            last_failure,
+            size_classes,
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracking<A>
+{
+    unsafe fn alloc( &self, layout: Layout ) -> *mut u8
+    {
+        let ptr = self.inner.alloc(layout);
+        if ptr.is_null()
+        {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+            self.last_failure_size.store(layout.size(), Ordering::Relaxed);
+            self.last_failure_align.store(layout.align(), Ordering::Relaxed);
+            return ptr;
+        }
+
+        let current = self.current_bytes
+            .fetch_add(layout.size(), Ordering::Relaxed)
+            + layout.size();
+        self.peak_bytes.fetch_max(current, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        self.size_classes[size_class(layout.size())]
+            .fetch_add(1, Ordering::Relaxed);
+        ptr
+    }
+
+    unsafe fn dealloc( &self, ptr: *mut u8, layout: Layout )
+    {
+        self.inner.dealloc(ptr, layout);
+        self.current_bytes.fetch_sub(layout.size(), Ordering::Relaxed);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns the size class of an allocation of `size` bytes.
+//------------------------------------------------------------------------------
+fn size_class( size: usize ) -> usize
+{
+    (0..SIZE_CLASSES - 1)
+        .find(|&class| size <= class_size(class))
+        .unwrap_or(SIZE_CLASSES - 1)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the largest allocation size of the given size class.
+//------------------------------------------------------------------------------
+fn class_size( class: usize ) -> usize
+{
+    SMALLEST_CLASS_SIZE << class
+}
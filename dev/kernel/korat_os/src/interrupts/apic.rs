@@ -0,0 +1,376 @@
+/*
+
+    APIC
+
+    ----------------------------------------------------------------------------
+
+    The Advanced Programmable Interrupt Controller (APIC) replaces the 8259
+    PIC. Every CPU has a Local APIC, which receives the interrupts for that
+    CPU, and the I/O APIC routes the device interrupts (IRQs) to them.
+
+                        ____________              ____________
+      Timer ---------> |            |            |            |      ______
+      Keyboard ------> |  I/O APIC  | ---------> | Local APIC | --> |     |
+      Other IRQs ----> |            |    (bus)   |            |     | CPU |
+                       |____________|            |____________|     |_____|
+
+    Both are programmed through memory-mapped registers, which `init_apic`
+    maps with `map_mmio`. The Local APIC's base address is read from the
+    `IA32_APIC_BASE` MSR. The I/O APIC's address is described by the ACPI
+    MADT table, which is not parsed yet, so the standard address
+    `0xfec0_0000` is used.
+
+    | Local APIC register | Offset  | Use                                    |
+    | ------------------- | ------- | -------------------------------------- |
+    | ID                  | `0x020` | The APIC ID of the CPU (bits 24..32)   |
+    | TPR                 | `0x080` | Task priority, 0 accepts everything    |
+    | EOI                 | `0x0b0` | Written at the end of an interrupt     |
+    | SVR                 | `0x0f0` | Enable bit and spurious vector         |
+
+    The I/O APIC has one 64-bit redirection entry per input pin, which holds
+    the vector, the destination APIC ID and a mask bit. The ISA IRQs are
+    connected to the pins with the same number, except IRQ 0 (the timer),
+    which is connected to pin 2 on PCs.
+
+    `init_apic` masks every pin, routes the IRQs that have handlers to their
+    vectors on the current CPU and masks the 8259 PIC. From then on, the end
+    of an interrupt is signaled to the Local APIC instead of the PIC. Without
+    an APIC, the PIC stays in use.
+
+    - [APIC(OSDev Wiki)](https://wiki.osdev.org/APIC)
+    - [IOAPIC(OSDev Wiki)](https://wiki.osdev.org/IOAPIC)
+
+*/
+
+use super::{ IRQ_LINES, IRQ_VECTOR_BASE, PICS, irq_has_handlers };
+use crate::memory::{ self, MapError, MmioRegion };
+
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{ AtomicBool, Ordering };
+use spin::{ Mutex, Once };
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::PageTableFlags;
+
+//  The MSR holding the Local APIC's base address and enable bit.
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+//  Local APIC registers.
+const LOCAL_APIC_SIZE: usize = 0x400;
+const LOCAL_APIC_ID: usize = 0x020;
+const LOCAL_APIC_TPR: usize = 0x080;
+const LOCAL_APIC_EOI: usize = 0x0b0;
+const LOCAL_APIC_SVR: usize = 0x0f0;
+const SVR_ENABLE: u32 = 1 << 8;
+
+//  The vector the Local APIC raises for spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+//  The standard physical address of the I/O APIC.
+pub const IO_APIC_BASE: u64 = 0xfec0_0000;
+
+//  I/O APIC registers. They are accessed by writing the register number to
+//  `IOREGSEL` and then reading or writing `IOWIN`.
+const IO_APIC_SIZE: usize = 0x20;
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+//  Redirection entry bits.
+const REDIRECTION_VECTOR: u64 = 0xff;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u64 = 56;
+
+static LOCAL_APIC: Once<MmioRegion> = Once::new();
+static IO_APIC: Mutex<Option<IoApic>> = Mutex::new(None);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+//------------------------------------------------------------------------------
+//  An error returned when the APIC cannot be used.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApicError
+{
+    //  The CPU has no APIC.
+    NotSupported,
+
+    //  The registers could not be mapped.
+    Map(MapError),
+}
+
+impl From<MapError> for ApicError
+{
+    fn from( err: MapError ) -> Self
+    {
+        ApicError::Map(err)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  The registers of the I/O APIC.
+//------------------------------------------------------------------------------
+struct IoApic
+{
+    region: MmioRegion,
+}
+
+impl IoApic
+{
+    //--------------------------------------------------------------------------
+    //  Reads a register.
+    //--------------------------------------------------------------------------
+    fn read( &mut self, register: u32 ) -> u32
+    {
+        self.region.write(IOREGSEL, register);
+        self.region.read(IOWIN)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Writes a register.
+    //--------------------------------------------------------------------------
+    fn write( &mut self, register: u32, value: u32 )
+    {
+        self.region.write(IOREGSEL, register);
+        self.region.write(IOWIN, value);
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of input pins.
+    //--------------------------------------------------------------------------
+    fn pins( &mut self ) -> u8
+    {
+        ((self.read(IOAPICVER) >> 16) & 0xff) as u8 + 1
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the redirection entry of a pin.
+    //--------------------------------------------------------------------------
+    fn redirection( &mut self, pin: u8 ) -> u64
+    {
+        let register = IOREDTBL + pin as u32 * 2;
+        let low = self.read(register) as u64;
+        let high = self.read(register + 1) as u64;
+        low | high << 32
+    }
+
+    //--------------------------------------------------------------------------
+    //  Sets the redirection entry of a pin. The low half holds the mask bit,
+    //  so it is written last.
+    //--------------------------------------------------------------------------
+    fn set_redirection( &mut self, pin: u8, entry: u64 )
+    {
+        let register = IOREDTBL + pin as u32 * 2;
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether the CPU has a Local APIC.
+//------------------------------------------------------------------------------
+#[allow(unused_unsafe)]
+pub fn apic_supported() -> bool
+{
+    let edx = unsafe { __cpuid(1) }.edx;
+    edx & (1 << 9) != 0
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether interrupts are delivered through the APIC.
+//------------------------------------------------------------------------------
+pub fn apic_enabled() -> bool
+{
+    ENABLED.load(Ordering::Acquire)
+}
+
+//------------------------------------------------------------------------------
+//  Switches from the 8259 PIC to the APIC: maps the Local APIC and the I/O
+//  APIC, enables the Local APIC, routes the IRQs that have handlers through
+//  the I/O APIC and masks the PIC.
+//
+//  Does nothing if the APIC is already enabled. Returns
+//  `ApicError::NotSupported` if the CPU has no APIC, in which case the PIC
+//  stays in use. Needs the kernel memory to be installed.
+//------------------------------------------------------------------------------
+pub fn init_apic() -> Result<(), ApicError>
+{
+    if !apic_supported()
+    {
+        return Err(ApicError::NotSupported);
+    }
+    if apic_enabled()
+    {
+        return Ok(());
+    }
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { apic_base.read() };
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    let local_apic = unsafe
+    {
+        memory::map_mmio
+        (
+            PhysAddr::new(base & APIC_BASE_ADDRESS_MASK),
+            LOCAL_APIC_SIZE,
+            flags
+        )?
+    };
+    let io_apic = unsafe
+    {
+        memory::map_mmio(PhysAddr::new(IO_APIC_BASE), IO_APIC_SIZE, flags)?
+    };
+
+    interrupts::without_interrupts(||
+    {
+        unsafe
+        {
+            PICS.lock().disable();
+            apic_base.write(base | APIC_BASE_ENABLE);
+        }
+
+        LOCAL_APIC.call_once(|| local_apic);
+        write_local_apic(LOCAL_APIC_TPR, 0);
+        write_local_apic(LOCAL_APIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        let mut io_apic = IoApic { region: io_apic };
+        for pin in 0..io_apic.pins()
+        {
+            io_apic.set_redirection(pin, REDIRECTION_MASKED);
+        }
+        *IO_APIC.lock() = Some(io_apic);
+
+        for irq in (0..IRQ_LINES as u8).filter(|&irq| irq_has_handlers(irq))
+        {
+            route_irq(irq, IRQ_VECTOR_BASE + irq);
+        }
+
+        ENABLED.store(true, Ordering::Release);
+    });
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+//  Returns the APIC ID of the current CPU, if the APIC is enabled.
+//------------------------------------------------------------------------------
+pub fn local_apic_id() -> Option<u8>
+{
+    LOCAL_APIC.wait()?;
+    Some((read_local_apic(LOCAL_APIC_ID) >> 24) as u8)
+}
+
+//------------------------------------------------------------------------------
+//  Routes an ISA IRQ through the I/O APIC to `vector` on the current CPU, as
+//  an edge triggered, active high interrupt. Does nothing if the APIC is not
+//  enabled.
+//------------------------------------------------------------------------------
+pub fn route_irq( irq: u8, vector: u8 )
+{
+    let destination = match local_apic_id()
+    {
+        Some(id) => id,
+        None => return,
+    };
+    let entry = vector as u64
+        | (destination as u64) << REDIRECTION_DESTINATION_SHIFT;
+    interrupts::without_interrupts(||
+    {
+        if let Some(io_apic) = IO_APIC.lock().as_mut()
+        {
+            io_apic.set_redirection(isa_irq_pin(irq), entry);
+        }
+    });
+}
+
+//------------------------------------------------------------------------------
+//  Masks an ISA IRQ in the I/O APIC. Does nothing if the APIC is not enabled.
+//------------------------------------------------------------------------------
+pub fn mask_irq( irq: u8 )
+{
+    interrupts::without_interrupts(||
+    {
+        if let Some(io_apic) = IO_APIC.lock().as_mut()
+        {
+            io_apic.set_redirection(isa_irq_pin(irq), REDIRECTION_MASKED);
+        }
+    });
+}
+
+//------------------------------------------------------------------------------
+//  Returns the vector an ISA IRQ is routed to, or `None` if it is masked or
+//  the APIC is not enabled.
+//------------------------------------------------------------------------------
+pub fn irq_vector( irq: u8 ) -> Option<u8>
+{
+    let entry = interrupts::without_interrupts(||
+    {
+        IO_APIC.lock()
+            .as_mut()
+            .map(|io_apic| io_apic.redirection(isa_irq_pin(irq)))
+    })?;
+    if entry & REDIRECTION_MASKED != 0
+    {
+        return None;
+    }
+    Some((entry & REDIRECTION_VECTOR) as u8)
+}
+
+//------------------------------------------------------------------------------
+//  Signals the end of an interrupt to the Local APIC.
+//------------------------------------------------------------------------------
+pub(super) fn end_of_interrupt()
+{
+    write_local_apic(LOCAL_APIC_EOI, 0);
+}
+
+//------------------------------------------------------------------------------
+//  Returns the I/O APIC pin an ISA IRQ is connected to.
+//------------------------------------------------------------------------------
+fn isa_irq_pin( irq: u8 ) -> u8
+{
+    match irq
+    {
+        0 => 2,
+        _ => irq,
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Reads a Local APIC register.
+//
+//  The registers are only accessed with volatile 32-bit reads and writes,
+//  which need no lock, so that the end of an interrupt can be signaled from
+//  any handler.
+//------------------------------------------------------------------------------
+fn read_local_apic( register: usize ) -> u32
+{
+    let local_apic = LOCAL_APIC.wait().expect("local APIC is not mapped");
+    unsafe
+    {
+        local_apic.as_mut_ptr::<u8>()
+            .add(register)
+            .cast::<u32>()
+            .read_volatile()
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Writes a Local APIC register.
+//------------------------------------------------------------------------------
+fn write_local_apic( register: usize, value: u32 )
+{
+    let local_apic = LOCAL_APIC.wait().expect("local APIC is not mapped");
+    unsafe
+    {
+        local_apic.as_mut_ptr::<u8>()
+            .add(register)
+            .cast::<u32>()
+            .write_volatile(value);
+    }
+}
@@ -0,0 +1,554 @@
+/*
+
+    Exceptions
+
+    ----------------------------------------------------------------------------
+
+    The CPU raises an exception when an instruction cannot be executed. Every
+    architectural vector in the table in `interrupts` gets a handler, so that
+    a fault is reported as itself instead of escalating into a double fault
+    that hides the cause.
+
+    | Kind  | Saved instruction pointer      | Exceptions                    |
+    | ----- | ------------------------------ | ----------------------------- |
+    | Fault | The faulting instruction       | Most of them                  |
+    | Trap  | The instruction after the trap | #DB, #BP, #OF, `int n`        |
+    | Abort | Not reliable                   | #DF, #MC                      |
+
+    Breakpoints, debug exceptions and NMIs are reported and execution goes
+    on. Page faults are resolved by the lazy region and copy-on-write code if
+    possible. Every other exception is fatal: the kernel panics with a report
+    of the vector, the error code, the stack frame and the control registers.
+
+    The error code of #TS, #NP, #SS and #GP refers to a segment selector, or
+    to an IDT entry if the exception happened while delivering an interrupt.
+    It is decoded in the report:
+
+      15                            3     2     1     0
+      +-----------------------------+-----+-----+-----+
+      | index                       | TI  | IDT | EXT |
+      +-----------------------------+-----+-----+-----+
+
+    `expect_exception` lets code survive one exception that it raises on
+    purpose, e.g. to test the handlers: the handler records the exception,
+    skips the faulting instruction and returns. `take_caught_exception`
+    returns what was recorded.
+
+    - [Exceptions(OSDev Wiki)](https://wiki.osdev.org/Exceptions)
+
+*/
+
+use crate::{ println, gdt, memory };
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{ Cr0, Cr2, Cr3, Cr4 };
+use x86_64::structures::idt::{
+    InterruptDescriptorTable,
+    InterruptStackFrame,
+    PageFaultErrorCode,
+    SelectorErrorCode,
+};
+
+static EXPECTED: Mutex<Option<(Exception, u64)>> = Mutex::new(None);
+static CAUGHT: Mutex<Option<CaughtException>> = Mutex::new(None);
+
+//------------------------------------------------------------------------------
+//  The architectural exception vectors.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exception
+{
+    DivideError = 0x00,
+    Debug = 0x01,
+    NonMaskableInterrupt = 0x02,
+    Breakpoint = 0x03,
+    Overflow = 0x04,
+    BoundRangeExceeded = 0x05,
+    InvalidOpcode = 0x06,
+    DeviceNotAvailable = 0x07,
+    DoubleFault = 0x08,
+    CoprocessorSegmentOverrun = 0x09,
+    InvalidTss = 0x0a,
+    SegmentNotPresent = 0x0b,
+    StackSegmentFault = 0x0c,
+    GeneralProtectionFault = 0x0d,
+    PageFault = 0x0e,
+    X87FloatingPoint = 0x10,
+    AlignmentCheck = 0x11,
+    MachineCheck = 0x12,
+    SimdFloatingPoint = 0x13,
+    Virtualization = 0x14,
+    ControlProtection = 0x15,
+}
+
+impl Exception
+{
+    //--------------------------------------------------------------------------
+    //  Returns the vector number.
+    //--------------------------------------------------------------------------
+    pub fn vector( self ) -> u8
+    {
+        self as u8
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the name of the exception.
+    //--------------------------------------------------------------------------
+    pub fn name( self ) -> &'static str
+    {
+        match self
+        {
+            Exception::DivideError => "DIVIDE ERROR",
+            Exception::Debug => "DEBUG",
+            Exception::NonMaskableInterrupt => "NON-MASKABLE INTERRUPT",
+            Exception::Breakpoint => "BREAKPOINT",
+            Exception::Overflow => "OVERFLOW",
+            Exception::BoundRangeExceeded => "BOUND RANGE EXCEEDED",
+            Exception::InvalidOpcode => "INVALID OPCODE",
+            Exception::DeviceNotAvailable => "DEVICE NOT AVAILABLE",
+            Exception::DoubleFault => "DOUBLE FAULT",
+            Exception::CoprocessorSegmentOverrun =>
+                "COPROCESSOR SEGMENT OVERRUN",
+            Exception::InvalidTss => "INVALID TSS",
+            Exception::SegmentNotPresent => "SEGMENT NOT PRESENT",
+            Exception::StackSegmentFault => "STACK SEGMENT FAULT",
+            Exception::GeneralProtectionFault => "GENERAL PROTECTION FAULT",
+            Exception::PageFault => "PAGE FAULT",
+            Exception::X87FloatingPoint => "X87 FLOATING POINT EXCEPTION",
+            Exception::AlignmentCheck => "ALIGNMENT CHECK",
+            Exception::MachineCheck => "MACHINE CHECK",
+            Exception::SimdFloatingPoint => "SIMD FLOATING POINT EXCEPTION",
+            Exception::Virtualization => "VIRTUALIZATION EXCEPTION",
+            Exception::ControlProtection => "CONTROL PROTECTION EXCEPTION",
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the short name of the exception, e.g. `#GP`.
+    //--------------------------------------------------------------------------
+    pub fn mnemonic( self ) -> &'static str
+    {
+        match self
+        {
+            Exception::DivideError => "#DE",
+            Exception::Debug => "#DB",
+            Exception::NonMaskableInterrupt => "NMI",
+            Exception::Breakpoint => "#BP",
+            Exception::Overflow => "#OF",
+            Exception::BoundRangeExceeded => "#BR",
+            Exception::InvalidOpcode => "#UD",
+            Exception::DeviceNotAvailable => "#NM",
+            Exception::DoubleFault => "#DF",
+            Exception::CoprocessorSegmentOverrun => "CSO",
+            Exception::InvalidTss => "#TS",
+            Exception::SegmentNotPresent => "#NP",
+            Exception::StackSegmentFault => "#SS",
+            Exception::GeneralProtectionFault => "#GP",
+            Exception::PageFault => "#PF",
+            Exception::X87FloatingPoint => "#MF",
+            Exception::AlignmentCheck => "#AC",
+            Exception::MachineCheck => "#MC",
+            Exception::SimdFloatingPoint => "#XM",
+            Exception::Virtualization => "#VE",
+            Exception::ControlProtection => "#CP",
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the error code refers to a segment selector.
+    //--------------------------------------------------------------------------
+    fn has_selector_error_code( self ) -> bool
+    {
+        matches!
+        (
+            self,
+            Exception::InvalidTss
+                | Exception::SegmentNotPresent
+                | Exception::StackSegmentFault
+                | Exception::GeneralProtectionFault
+        )
+    }
+}
+
+//------------------------------------------------------------------------------
+//  An exception that was expected with `expect_exception` and survived.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaughtException
+{
+    pub exception: Exception,
+    pub error_code: Option<u64>,
+    pub instruction_pointer: VirtAddr,
+}
+
+//------------------------------------------------------------------------------
+//  What the handlers report about an exception.
+//------------------------------------------------------------------------------
+struct ExceptionReport<'a>
+{
+    exception: Exception,
+    error_code: Option<u64>,
+    stack_frame: &'a InterruptStackFrame,
+    reason: Option<&'a dyn fmt::Display>,
+}
+
+impl fmt::Display for ExceptionReport<'_>
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        writeln!
+        (
+            f,
+            "EXCEPTION: {} ({}, vector {:#04x})",
+            self.exception.name(),
+            self.exception.mnemonic(),
+            self.exception.vector()
+        )?;
+
+        if let Some(error_code) = self.error_code
+        {
+            write!(f, "Error Code: {:#x}", error_code)?;
+            if self.exception == Exception::PageFault
+            {
+                let flags = PageFaultErrorCode::from_bits_truncate(error_code);
+                write!(f, " ({:?})", flags)?;
+            }
+            else if self.exception.has_selector_error_code() && error_code != 0
+            {
+                let selector = SelectorErrorCode::new_truncate(error_code);
+                write!
+                (
+                    f,
+                    " ({:?} index {}{})",
+                    selector.descriptor_table(),
+                    selector.index(),
+                    if selector.external() { ", external" } else { "" }
+                )?;
+            }
+            writeln!(f)?;
+        }
+
+        if let Some(reason) = self.reason
+        {
+            writeln!(f, "Reason: {}", reason)?;
+        }
+
+        let (level_4_frame, cr3_flags) = Cr3::read();
+        writeln!(f, "CR0: {:?}", Cr0::read())?;
+        writeln!(f, "CR2: {:?}", Cr2::read())?;
+        writeln!(f, "CR3: {:?} {:?}", level_4_frame, cr3_flags)?;
+        writeln!(f, "CR4: {:?}", Cr4::read())?;
+        write!(f, "{:#?}", self.stack_frame)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Defines a handler for an exception that is fatal unless it is expected.
+//------------------------------------------------------------------------------
+macro_rules! fault_handler
+{
+    ($name:ident, $exception:expr) =>
+    {
+        extern "x86-interrupt" fn $name( mut stack_frame: InterruptStackFrame )
+        {
+            handle_fault($exception, None, &mut stack_frame);
+        }
+    };
+    ($name:ident, $exception:expr, error_code) =>
+    {
+        extern "x86-interrupt" fn $name
+        (
+            mut stack_frame: InterruptStackFrame,
+            error_code: u64,
+        )
+        {
+            handle_fault($exception, Some(error_code), &mut stack_frame);
+        }
+    };
+}
+
+//------------------------------------------------------------------------------
+//  Defines a handler for an exception that is reported and then ignored.
+//------------------------------------------------------------------------------
+macro_rules! trap_handler
+{
+    ($name:ident, $exception:expr) =>
+    {
+        extern "x86-interrupt" fn $name( mut stack_frame: InterruptStackFrame )
+        {
+            if !recover($exception, None, &mut stack_frame)
+            {
+                println!("{}", report($exception, None, &stack_frame, None));
+            }
+        }
+    };
+}
+
+trap_handler!(debug_handler, Exception::Debug);
+trap_handler!(nmi_handler, Exception::NonMaskableInterrupt);
+trap_handler!(breakpoint_handler, Exception::Breakpoint);
+
+fault_handler!(divide_error_handler, Exception::DivideError);
+fault_handler!(overflow_handler, Exception::Overflow);
+fault_handler!(bound_range_exceeded_handler, Exception::BoundRangeExceeded);
+fault_handler!(invalid_opcode_handler, Exception::InvalidOpcode);
+fault_handler!(device_not_available_handler, Exception::DeviceNotAvailable);
+fault_handler!
+(
+    coprocessor_segment_overrun_handler,
+    Exception::CoprocessorSegmentOverrun
+);
+fault_handler!(invalid_tss_handler, Exception::InvalidTss, error_code);
+fault_handler!
+(
+    segment_not_present_handler,
+    Exception::SegmentNotPresent,
+    error_code
+);
+fault_handler!
+(
+    stack_segment_fault_handler,
+    Exception::StackSegmentFault,
+    error_code
+);
+fault_handler!
+(
+    general_protection_fault_handler,
+    Exception::GeneralProtectionFault,
+    error_code
+);
+fault_handler!(x87_floating_point_handler, Exception::X87FloatingPoint);
+fault_handler!(alignment_check_handler, Exception::AlignmentCheck, error_code);
+fault_handler!(simd_floating_point_handler, Exception::SimdFloatingPoint);
+fault_handler!(virtualization_handler, Exception::Virtualization);
+fault_handler!
+(
+    control_protection_handler,
+    Exception::ControlProtection,
+    error_code
+);
+
+//------------------------------------------------------------------------------
+//  Installs a handler for every architectural exception vector.
+//------------------------------------------------------------------------------
+pub(super) fn set_exception_handlers( idt: &mut InterruptDescriptorTable )
+{
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    unsafe
+    {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    //  The `coprocessor_segment_overrun` field is private in x86_64 0.14,
+    //  since the vector is reserved on current CPUs, so it is only reachable
+    //  by its index.
+    idt[Exception::CoprocessorSegmentOverrun.vector() as usize]
+        .set_handler_fn(coprocessor_segment_overrun_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception.set_handler_fn(control_protection_handler);
+}
+
+//------------------------------------------------------------------------------
+//  Makes the handler of `exception` recover from it once: the next time it is
+//  raised, the handler records it in `take_caught_exception`, advances the
+//  saved instruction pointer by `skip` bytes and returns. Use a `skip` of 0
+//  for traps, whose saved instruction pointer is already past the trap.
+//
+//  This function is unsafe because the caller must guarantee that the next
+//  instruction raising `exception` is `skip` bytes long, and that going on
+//  after it leaves the program in a valid state.
+//------------------------------------------------------------------------------
+pub unsafe fn expect_exception( exception: Exception, skip: u64 )
+{
+    interrupts::without_interrupts(||
+    {
+        *EXPECTED.lock() = Some((exception, skip));
+        *CAUGHT.lock() = None;
+    });
+}
+
+//------------------------------------------------------------------------------
+//  Returns the exception recovered from since `expect_exception`, if any, and
+//  forgets it.
+//------------------------------------------------------------------------------
+pub fn take_caught_exception() -> Option<CaughtException>
+{
+    interrupts::without_interrupts(||
+    {
+        EXPECTED.lock().take();
+        CAUGHT.lock().take()
+    })
+}
+
+//------------------------------------------------------------------------------
+//  Recovers from `exception` if it is expected, and panics with a report
+//  otherwise.
+//------------------------------------------------------------------------------
+fn handle_fault
+(
+    exception: Exception,
+    error_code: Option<u64>,
+    stack_frame: &mut InterruptStackFrame,
+)
+{
+    if !recover(exception, error_code, stack_frame)
+    {
+        panic!("{}", report(exception, error_code, stack_frame, None));
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether `exception` was expected, after recording it and skipping
+//  the faulting instruction.
+//
+//  The locks are only tried, since the exception may have been raised while
+//  they are held.
+//------------------------------------------------------------------------------
+fn recover
+(
+    exception: Exception,
+    error_code: Option<u64>,
+    stack_frame: &mut InterruptStackFrame,
+) -> bool
+{
+    let mut expected = match EXPECTED.try_lock()
+    {
+        Some(expected) => expected,
+        None => return false,
+    };
+    let skip = match *expected
+    {
+        Some((expected_exception, skip)) if expected_exception == exception =>
+            skip,
+        _ => return false,
+    };
+    let mut caught = match CAUGHT.try_lock()
+    {
+        Some(caught) => caught,
+        None => return false,
+    };
+
+    *expected = None;
+    *caught = Some(CaughtException
+    {
+        exception,
+        error_code,
+        instruction_pointer: stack_frame.instruction_pointer,
+    });
+    unsafe
+    {
+        stack_frame.as_mut().update(|frame| frame.instruction_pointer += skip);
+    }
+    true
+}
+
+//------------------------------------------------------------------------------
+//  Returns the report of an exception.
+//------------------------------------------------------------------------------
+fn report<'a>
+(
+    exception: Exception,
+    error_code: Option<u64>,
+    stack_frame: &'a InterruptStackFrame,
+    reason: Option<&'a dyn fmt::Display>,
+) -> ExceptionReport<'a>
+{
+    ExceptionReport { exception, error_code, stack_frame, reason }
+}
+
+//------------------------------------------------------------------------------
+//  A page fault is a hardware-generated interrupt (or exception) when a
+//  program accesses a page in a virtual address space that is not mapped to
+//  physical memory.
+//
+//  Faults in lazy regions are resolved by mapping the page, and writes to
+//  copy-on-write pages by giving the address space its own frame. The
+//  faulting instruction then runs again. Any other fault is fatal.
+//------------------------------------------------------------------------------
+extern "x86-interrupt" fn page_fault_handler
+(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+)
+{
+    let addr = Cr2::read();
+    let write_protected = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::CAUSED_BY_WRITE;
+    let result = if error_code.contains(write_protected)
+    {
+        memory::handle_cow_fault(addr, error_code)
+    }
+    else
+    {
+        memory::handle_page_fault(addr, error_code)
+    };
+
+    if let Err(err) = result
+    {
+        let error_code = Some(error_code.bits());
+        if !recover(Exception::PageFault, error_code, &mut stack_frame)
+        {
+            panic!
+            (
+                "{}",
+                report
+                (
+                    Exception::PageFault,
+                    error_code,
+                    &stack_frame,
+                    Some(&err)
+                )
+            );
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A double-fault exception is executed when the CPU fails to call an
+//  exception handler. If the call to the double-fault exception fails, a more
+//  fatal triple fault exception is raised and attempts to reset the system.
+//------------------------------------------------------------------------------
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> !
+{
+    panic!
+    (
+        "{}",
+        report(Exception::DoubleFault, Some(error_code), &stack_frame, None)
+    );
+}
+
+//------------------------------------------------------------------------------
+//  A machine check is raised when the CPU detects a hardware error. The state
+//  of the CPU may be corrupted, so it is always fatal.
+//------------------------------------------------------------------------------
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame
+) -> !
+{
+    panic!("{}", report(Exception::MachineCheck, None, &stack_frame, None));
+}
@@ -0,0 +1,438 @@
+/*
+
+    IRQ handlers
+
+    ----------------------------------------------------------------------------
+
+    The 16 ISA IRQ lines are delivered at the vectors `IRQ_VECTOR_BASE +
+    irq`, both by the 8259 PIC and by the I/O APIC. Every vector has a stub in
+    the IDT that calls a common dispatcher, so drivers hook into a line at
+    runtime instead of editing the IDT:
+
+                  ______       ______________       ___________
+      IRQ n -->  | stub | --> | dispatch(n)  | --> | handler 1 |
+                 |______|     |              | --> | handler 2 |
+                              |              | --> | ...       |
+                              |______________|
+                                     |
+                                     +--> end of interrupt
+
+    A line can be shared by up to `MAX_IRQ_HANDLERS` handlers. The dispatcher
+    runs all of them, since any of the devices on the line may have raised
+    the interrupt, and each handler returns whether its device had something
+    to do. The dispatcher then signals the end of the interrupt to the
+    interrupt controller.
+
+    | Statistic    | Meaning                                                  |
+    | ------------ | -------------------------------------------------------- |
+    | `interrupts` | Interrupts dispatched on the line                        |
+    | `unhandled`  | Interrupts that no handler claimed                       |
+    | spurious     | Interrupts the controller raised without a cause (IRQ 7  |
+    |              | and 15 of the PIC, and the APIC's spurious vector)       |
+
+    The line is unmasked when its first handler is registered, and masked
+    again when its last handler is unregistered.
+
+    Handlers run with interrupts disabled and the line locked. They must not
+    block, allocate or register handlers. Registering a function or a closure
+    that captures nothing does not allocate, so it works before the heap is
+    initialized.
+
+*/
+
+use super::{ InterruptController, PIC_1_OFFSET, PICS };
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{ InterruptDescriptorTable, InterruptStackFrame };
+
+//  The number of IRQ lines.
+pub const IRQ_LINES: usize = 16;
+
+//  The vector of IRQ 0.
+pub const IRQ_VECTOR_BASE: u8 = PIC_1_OFFSET;
+
+//  The maximum number of handlers sharing one line.
+pub const MAX_IRQ_HANDLERS: usize = 4;
+
+//  The PIC ports and the command to read the in-service register.
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_2_COMMAND: u16 = 0xa0;
+const PIC_READ_ISR: u8 = 0x0b;
+
+//  The IRQ that connects the secondary PIC to the primary one.
+const CASCADE_IRQ: u8 = 2;
+
+static LINES: [Mutex<IrqLine>; IRQ_LINES] =
+{
+    #[allow(clippy::declare_interior_mutable_const)]
+    const LINE: Mutex<IrqLine> = Mutex::new(IrqLine::new());
+    [LINE; IRQ_LINES]
+};
+static SPURIOUS: AtomicU64 = AtomicU64::new(0);
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+//------------------------------------------------------------------------------
+//  What a handler did with an interrupt.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqStatus
+{
+    //  The device of the handler raised the interrupt and was serviced.
+    Handled,
+
+    //  The interrupt was not meant for the device of the handler.
+    NotHandled,
+}
+
+//------------------------------------------------------------------------------
+//  An error returned when a handler cannot be registered.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError
+{
+    //  The IRQ is not below `IRQ_LINES`.
+    InvalidIrq,
+
+    //  The line already has `MAX_IRQ_HANDLERS` handlers.
+    TooManyHandlers,
+}
+
+impl fmt::Display for IrqError
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        let reason = match self
+        {
+            IrqError::InvalidIrq => "no such IRQ line",
+            IrqError::TooManyHandlers => "too many handlers on the IRQ line",
+        };
+        f.write_str(reason)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Identifies a registered handler, to unregister it.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandlerId
+{
+    irq: u8,
+    id: usize,
+}
+
+impl IrqHandlerId
+{
+    //--------------------------------------------------------------------------
+    //  Returns the IRQ the handler is registered for.
+    //--------------------------------------------------------------------------
+    pub fn irq( &self ) -> u8
+    {
+        self.irq
+    }
+}
+
+//------------------------------------------------------------------------------
+//  The interrupt counts of an IRQ line.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqStats
+{
+    pub interrupts: u64,
+    pub unhandled: u64,
+}
+
+//------------------------------------------------------------------------------
+//  A registered handler.
+//------------------------------------------------------------------------------
+struct IrqHandler
+{
+    id: usize,
+    handler: Box<dyn Fn() -> IrqStatus + Send + Sync>,
+}
+
+//------------------------------------------------------------------------------
+//  The handlers and statistics of an IRQ line.
+//------------------------------------------------------------------------------
+struct IrqLine
+{
+    handlers: [Option<IrqHandler>; MAX_IRQ_HANDLERS],
+    stats: IrqStats,
+}
+
+impl IrqLine
+{
+    const fn new() -> Self
+    {
+        const NONE: Option<IrqHandler> = None;
+
+        IrqLine
+        {
+            handlers: [NONE; MAX_IRQ_HANDLERS],
+            stats: IrqStats { interrupts: 0, unhandled: 0 },
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the line has a handler.
+    //--------------------------------------------------------------------------
+    fn has_handlers( &self ) -> bool
+    {
+        self.handlers.iter().any(Option::is_some)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Defines the IDT stub of an IRQ line.
+//------------------------------------------------------------------------------
+macro_rules! irq_stub
+{
+    ($name:ident, $irq:expr) =>
+    {
+        extern "x86-interrupt" fn $name( _stack_frame: InterruptStackFrame )
+        {
+            dispatch($irq);
+        }
+    };
+}
+
+irq_stub!(irq_0, 0);
+irq_stub!(irq_1, 1);
+irq_stub!(irq_2, 2);
+irq_stub!(irq_3, 3);
+irq_stub!(irq_4, 4);
+irq_stub!(irq_5, 5);
+irq_stub!(irq_6, 6);
+irq_stub!(irq_7, 7);
+irq_stub!(irq_8, 8);
+irq_stub!(irq_9, 9);
+irq_stub!(irq_10, 10);
+irq_stub!(irq_11, 11);
+irq_stub!(irq_12, 12);
+irq_stub!(irq_13, 13);
+irq_stub!(irq_14, 14);
+irq_stub!(irq_15, 15);
+
+const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] =
+[
+    irq_0, irq_1, irq_2, irq_3, irq_4, irq_5, irq_6, irq_7,
+    irq_8, irq_9, irq_10, irq_11, irq_12, irq_13, irq_14, irq_15,
+];
+
+//------------------------------------------------------------------------------
+//  Installs the stubs of all IRQ lines.
+//------------------------------------------------------------------------------
+pub(super) fn set_irq_handlers( idt: &mut InterruptDescriptorTable )
+{
+    for (irq, stub) in IRQ_STUBS.iter().enumerate()
+    {
+        idt[usize::from(IRQ_VECTOR_BASE) + irq].set_handler_fn(*stub);
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Registers `handler` for an IRQ line and unmasks the line.
+//------------------------------------------------------------------------------
+pub fn register_irq_handler<F>
+(
+    irq: u8,
+    handler: F,
+) -> Result<IrqHandlerId, IrqError>
+where
+    F: Fn() -> IrqStatus + Send + Sync + 'static,
+{
+    let line = LINES.get(usize::from(irq)).ok_or(IrqError::InvalidIrq)?;
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let handler = IrqHandler { id, handler: Box::new(handler) };
+
+    interrupts::without_interrupts(||
+    {
+        let mut line = line.lock();
+        let slot = line.handlers.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        *slot = Some(handler);
+        enable_line(irq);
+        Ok(IrqHandlerId { irq, id })
+    })
+}
+
+//------------------------------------------------------------------------------
+//  Unregisters a handler, and masks the line if it was the last one. Returns
+//  whether the handler was registered.
+//------------------------------------------------------------------------------
+pub fn unregister_irq_handler( id: IrqHandlerId ) -> bool
+{
+    let handler = interrupts::without_interrupts(||
+    {
+        let mut line = LINES[usize::from(id.irq)].lock();
+        let handler = line.handlers.iter_mut()
+            .find(|slot| slot.as_ref().is_some_and(|h| h.id == id.id))?
+            .take();
+        if !line.has_handlers()
+        {
+            disable_line(id.irq);
+        }
+        handler
+    });
+
+    //  Dropped outside of the lock, since it may free memory.
+    handler.is_some()
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether an IRQ line has a handler.
+//------------------------------------------------------------------------------
+pub fn irq_has_handlers( irq: u8 ) -> bool
+{
+    LINES.get(usize::from(irq)).is_some_and(|line|
+        interrupts::without_interrupts(|| line.lock().has_handlers())
+    )
+}
+
+//------------------------------------------------------------------------------
+//  Returns the statistics of an IRQ line.
+//------------------------------------------------------------------------------
+pub fn irq_stats( irq: u8 ) -> Option<IrqStats>
+{
+    let line = LINES.get(usize::from(irq))?;
+    Some(interrupts::without_interrupts(|| line.lock().stats))
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of spurious interrupts.
+//------------------------------------------------------------------------------
+pub fn spurious_irqs() -> u64
+{
+    SPURIOUS.load(Ordering::Relaxed)
+}
+
+//------------------------------------------------------------------------------
+//  Counts a spurious interrupt.
+//------------------------------------------------------------------------------
+pub(super) fn count_spurious()
+{
+    SPURIOUS.fetch_add(1, Ordering::Relaxed);
+}
+
+//------------------------------------------------------------------------------
+//  Runs the handlers of an IRQ line and signals the end of the interrupt.
+//------------------------------------------------------------------------------
+fn dispatch( irq: u8 )
+{
+    if is_spurious(irq)
+    {
+        count_spurious();
+
+        //  The primary PIC did deliver the cascade IRQ of the secondary one.
+        if irq >= 8
+        {
+            unsafe
+            {
+                PICS.lock()
+                    .notify_end_of_interrupt(IRQ_VECTOR_BASE + CASCADE_IRQ);
+            }
+        }
+        return;
+    }
+
+    {
+        let mut line = LINES[usize::from(irq)].lock();
+        let mut handled = false;
+        for handler in line.handlers.iter().flatten()
+        {
+            if (handler.handler)() == IrqStatus::Handled
+            {
+                handled = true;
+            }
+        }
+        line.stats.interrupts += 1;
+        if !handled
+        {
+            line.stats.unhandled += 1;
+        }
+    }
+
+    super::notify_end_of_interrupt(irq);
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether an interrupt on the line is a spurious one from the PIC,
+//  i.e. IRQ 7 or 15 while it is not in service.
+//------------------------------------------------------------------------------
+fn is_spurious( irq: u8 ) -> bool
+{
+    if irq % 8 != 7 || super::interrupt_controller() != InterruptController::Pic
+    {
+        return false;
+    }
+
+    let mut command = Port::<u8>::new
+    (
+        if irq < 8 { PIC_1_COMMAND } else { PIC_2_COMMAND }
+    );
+    let in_service = unsafe
+    {
+        command.write(PIC_READ_ISR);
+        command.read()
+    };
+    in_service & 0x80 == 0
+}
+
+//------------------------------------------------------------------------------
+//  Unmasks an IRQ line in the interrupt controller.
+//------------------------------------------------------------------------------
+fn enable_line( irq: u8 )
+{
+    match super::interrupt_controller()
+    {
+        InterruptController::Apic =>
+        {
+            super::route_irq(irq, IRQ_VECTOR_BASE + irq);
+        },
+        InterruptController::Pic => unsafe
+        {
+            let mut pics = PICS.lock();
+            let [mut primary, mut secondary] = pics.read_masks();
+            if irq < 8
+            {
+                primary &= !(1 << irq);
+            }
+            else
+            {
+                primary &= !(1 << CASCADE_IRQ);
+                secondary &= !(1 << (irq - 8));
+            }
+            pics.write_masks(primary, secondary);
+        },
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Masks an IRQ line in the interrupt controller.
+//------------------------------------------------------------------------------
+fn disable_line( irq: u8 )
+{
+    match super::interrupt_controller()
+    {
+        InterruptController::Apic => super::mask_irq(irq),
+        InterruptController::Pic => unsafe
+        {
+            let mut pics = PICS.lock();
+            let [mut primary, mut secondary] = pics.read_masks();
+            if irq < 8
+            {
+                primary |= 1 << irq;
+            }
+            else
+            {
+                secondary |= 1 << (irq - 8);
+            }
+            pics.write_masks(primary, secondary);
+        },
+    }
+}
@@ -41,18 +41,72 @@
     | 0x14   | Virtualization Exception      |
     | 0x15   | Control Protection Exception  |
 
+    Each of them has a handler in `exceptions`.
+
     - [Interrupt Descriptor Table(wikipedia)](https://en.wikipedia.org/wiki/Interrupt_descriptor_table)
 
+
+    # Interrupt controllers
+
+    `init` starts with the 8259 PIC. Once the kernel memory is installed,
+    `init_apic` switches to the Local APIC and the I/O APIC if the CPU has
+    them. The end of an interrupt is signaled with `notify_end_of_interrupt`,
+    which talks to whichever controller is in use.
+
+    Drivers hook into IRQ lines with `register_irq_handler` (see `irq`), and
+    leave what can wait to deferred work with `queue_work` (see `work`).
+
 */
 
-use crate::{ print, println, gdt, hlt_loop };
+mod apic;
+mod exceptions;
+mod irq;
+mod work;
+
+pub use apic::{
+    ApicError,
+    IO_APIC_BASE,
+    SPURIOUS_VECTOR,
+    apic_enabled,
+    apic_supported,
+    init_apic,
+    irq_vector,
+    local_apic_id,
+    mask_irq,
+    route_irq,
+};
+pub use exceptions::{
+    CaughtException,
+    Exception,
+    expect_exception,
+    take_caught_exception,
+};
+pub use irq::{
+    IrqError,
+    IrqHandlerId,
+    IrqStats,
+    IrqStatus,
+    IRQ_LINES,
+    IRQ_VECTOR_BASE,
+    MAX_IRQ_HANDLERS,
+    irq_has_handlers,
+    irq_stats,
+    register_irq_handler,
+    spurious_irqs,
+    unregister_irq_handler,
+};
+pub use work::{
+    WORK_QUEUE_SIZE,
+    WorkStats,
+    has_pending_work,
+    queue_work,
+    run_pending_work,
+    wait_for_interrupt,
+    work_stats,
+};
 
 use lazy_static::lazy_static;
-use x86_64::structures::idt::{
-    InterruptDescriptorTable,
-    InterruptStackFrame,
-    PageFaultErrorCode,
-};
+use x86_64::structures::idt::{ InterruptDescriptorTable, InterruptStackFrame };
 use pic8259::ChainedPics;
 use spin;
 
@@ -63,20 +117,14 @@ lazy_static!
         let mut idt = InterruptDescriptorTable::new();
 
         //  Exception handler
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe
-        {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
+        exceptions::set_exception_handlers(&mut idt);
+
+        //  IRQ dispatch stubs
+        irq::set_irq_handlers(&mut idt);
 
-        //  Hook handler functions
-        idt[InterruptIndex::Timer.as_usize()]
-            .set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()]
-            .set_handler_fn(keyboard_interrupt_handler);
+        //  Spurious interrupts of the Local APIC
+        idt[usize::from(SPURIOUS_VECTOR)]
+            .set_handler_fn(spurious_interrupt_handler);
 
         idt
     };
@@ -106,139 +154,86 @@ pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 //------------------------------------------------------------------------------
-//  Various interrupt processing.
+//  The interrupt controller that delivers the IRQs.
 //------------------------------------------------------------------------------
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController
 {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+    Pic,
+    Apic,
 }
 
-impl InterruptIndex
+//------------------------------------------------------------------------------
+//  Returns the interrupt controller in use.
+//------------------------------------------------------------------------------
+pub fn interrupt_controller() -> InterruptController
 {
-    fn as_u8( self ) -> u8
+    if apic_enabled()
     {
-        self as u8
+        InterruptController::Apic
     }
-
-    fn as_usize( self ) -> usize
+    else
     {
-        usize::from(self.as_u8())
+        InterruptController::Pic
     }
 }
 
 //------------------------------------------------------------------------------
-//  A exception breakpoint is executed by suspending the program when the 
-//  breakpoint instruction `int3` is executed.
+//  Signals the end of an interrupt on the given IRQ line to the interrupt
+//  controller, so that it delivers the next one.
 //------------------------------------------------------------------------------
-extern "x86-interrupt" fn breakpoint_handler( stack_frame: InterruptStackFrame )
+pub fn notify_end_of_interrupt( irq: u8 )
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-//------------------------------------------------------------------------------
-//  A page fault is a hardware-generated interrupt (or exception) when a 
-//  program accesses a page in a virtual address space that is not mapped to 
-//  physical memory.
-//------------------------------------------------------------------------------
-extern "x86-interrupt" fn page_fault_handler
-(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-)
-{
-    use x86_64::registers::control::Cr2;
-
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    match interrupt_controller()
+    {
+        InterruptController::Apic => apic::end_of_interrupt(),
+        InterruptController::Pic => unsafe
+        {
+            PICS.lock().notify_end_of_interrupt(IRQ_VECTOR_BASE + irq);
+        },
+    }
 }
 
 //------------------------------------------------------------------------------
-//  A double-fault exception is executed when the CPU fails to call an 
-//  exception handler. If the call to the double-fault exception fails, a more 
-//  fatal triple fault exception is raised and attempts to reset the system.
+//  The vectors of the built-in devices.
 //------------------------------------------------------------------------------
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) -> !
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex
 {
-    panic!("EXCEPTION: DOUBLE FAULT(code: {})\n{:#?}", error_code, stack_frame);
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    RealTimeClock = PIC_2_OFFSET,
 }
 
-//------------------------------------------------------------------------------
-//  A timer interrupt hander.
-//------------------------------------------------------------------------------
-extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame
-)
+impl InterruptIndex
 {
-    print!(".");
+    fn as_u8( self ) -> u8
+    {
+        self as u8
+    }
 
-    unsafe
+    //--------------------------------------------------------------------------
+    //  Returns the IRQ line of the device.
+    //--------------------------------------------------------------------------
+    pub fn irq( self ) -> u8
     {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        self.as_u8() - IRQ_VECTOR_BASE
     }
 }
 
 //------------------------------------------------------------------------------
-//  A keyboard interrupt hander.
+//  A spurious interrupt handler.
 //
-//  Keyboard input will not receive further input until the scan code is read.
+//  The Local APIC raises its spurious vector when an interrupt goes away
+//  before it is delivered. No interrupt is in service, so the end of the
+//  interrupt must not be signaled.
 //------------------------------------------------------------------------------
-extern "x86-interrupt" fn keyboard_interrupt_handler(
+extern "x86-interrupt" fn spurious_interrupt_handler(
     _stack_frame: InterruptStackFrame
 )
 {
-    use pc_keyboard::{
-        layouts,
-        DecodedKey,
-        HandleControl,
-        Keyboard,
-        ScancodeSet1
-    };
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    lazy_static!
-    {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(
-                Keyboard::new(
-                    layouts::Us104Key,
-                    ScancodeSet1,
-                    HandleControl::Ignore,
-                )
-            );
-    }
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
-    {
-        if let Some(key) = keyboard.process_keyevent(key_event)
-        {
-            match key
-            {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
-    }
-
-    unsafe
-    {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    irq::count_spurious();
 }
 
 //------------------------------------------------------------------------------
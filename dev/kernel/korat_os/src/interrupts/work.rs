@@ -0,0 +1,192 @@
+/*
+
+    Deferred work
+
+    ----------------------------------------------------------------------------
+
+    Interrupt handlers run with interrupts disabled, so they should only do
+    what cannot wait, and leave the rest (printing, taking contended locks,
+    allocating) to a later point with interrupts enabled. They queue that work
+    with `queue_work`: a function and a `usize` argument, stored in one of
+    `WORK_QUEUE_SIZE` pre-allocated slots, so queueing never allocates.
+
+                   queue_work              run_pending_work
+      handler  ---------------->  slots  --------------------> work(data)
+      (interrupts off)                     (interrupts on)
+
+    The work runs in order at the safe points of the kernel, where the CPU
+    waits for an interrupt: `idle_loop`, `sleep` and `read_key`, all through
+    `wait_for_interrupt`. Code that runs for long without waiting can call
+    `run_pending_work` itself.
+
+    The work does not run on the return from an interrupt, since the
+    interrupted code may hold a lock that is taken with interrupts enabled,
+    such as the heap's. For the same reason it does not run after a panic:
+    `hlt_loop` halts with interrupts disabled.
+
+    | Statistic | Meaning                                       |
+    | --------- | --------------------------------------------- |
+    | `queued`  | Work put into a slot                          |
+    | `run`     | Work that has run                             |
+    | `dropped` | Work not queued because every slot was in use |
+
+*/
+
+use core::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+//  The number of work items that can wait at the same time.
+pub const WORK_QUEUE_SIZE: usize = 64;
+
+static QUEUE: Mutex<WorkQueue> = Mutex::new(WorkQueue::new());
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static QUEUED: AtomicU64 = AtomicU64::new(0);
+static RUN: AtomicU64 = AtomicU64::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+//------------------------------------------------------------------------------
+//  The counts of the deferred work.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkStats
+{
+    pub queued: u64,
+    pub run: u64,
+    pub dropped: u64,
+}
+
+//------------------------------------------------------------------------------
+//  A queued function and its argument.
+//------------------------------------------------------------------------------
+#[derive(Clone, Copy)]
+struct Work
+{
+    function: fn(usize),
+    data: usize,
+}
+
+//------------------------------------------------------------------------------
+//  A ring of work slots.
+//------------------------------------------------------------------------------
+struct WorkQueue
+{
+    slots: [Option<Work>; WORK_QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl WorkQueue
+{
+    const fn new() -> Self
+    {
+        WorkQueue { slots: [None; WORK_QUEUE_SIZE], head: 0, len: 0 }
+    }
+
+    fn push( &mut self, work: Work ) -> bool
+    {
+        if self.len == WORK_QUEUE_SIZE
+        {
+            return false;
+        }
+        self.slots[(self.head + self.len) % WORK_QUEUE_SIZE] = Some(work);
+        self.len += 1;
+        true
+    }
+
+    fn pop( &mut self ) -> Option<Work>
+    {
+        let work = self.slots[self.head].take()?;
+        self.head = (self.head + 1) % WORK_QUEUE_SIZE;
+        self.len -= 1;
+        Some(work)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Queues `function(data)` to run later with interrupts enabled. Returns
+//  false, and counts the work as dropped, if every slot is in use.
+//
+//  Can be called from interrupt handlers, and never allocates.
+//------------------------------------------------------------------------------
+pub fn queue_work( function: fn(usize), data: usize ) -> bool
+{
+    let queued = interrupts::without_interrupts(||
+        QUEUE.lock().push(Work { function, data })
+    );
+    if queued
+    {
+        QUEUED.fetch_add(1, Ordering::Relaxed);
+    }
+    else
+    {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+    queued
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether work is waiting to run.
+//------------------------------------------------------------------------------
+pub fn has_pending_work() -> bool
+{
+    interrupts::without_interrupts(|| QUEUE.lock().len != 0)
+}
+
+//------------------------------------------------------------------------------
+//  Runs the queued work, including work queued meanwhile, until the queue is
+//  empty. Does nothing when called from running work.
+//
+//  Must be called with interrupts enabled, and not from an interrupt handler.
+//------------------------------------------------------------------------------
+pub fn run_pending_work()
+{
+    if RUNNING.swap(true, Ordering::Acquire)
+    {
+        return;
+    }
+
+    while let Some(work) = interrupts::without_interrupts(||
+        QUEUE.lock().pop()
+    )
+    {
+        (work.function)(work.data);
+        RUN.fetch_add(1, Ordering::Relaxed);
+    }
+
+    RUNNING.store(false, Ordering::Release);
+}
+
+//------------------------------------------------------------------------------
+//  Halts the CPU until the next interrupt, unless work is already pending,
+//  and then runs the pending work.
+//
+//  Must be called with interrupts disabled, after the caller has checked
+//  what it waits for, so that an interrupt arriving in between still ends
+//  the halt. Returns with interrupts enabled.
+//------------------------------------------------------------------------------
+pub fn wait_for_interrupt()
+{
+    if QUEUE.lock().len != 0
+    {
+        interrupts::enable();
+    }
+    else
+    {
+        interrupts::enable_and_hlt();
+    }
+    run_pending_work();
+}
+
+//------------------------------------------------------------------------------
+//  Returns the counts of the deferred work.
+//------------------------------------------------------------------------------
+pub fn work_stats() -> WorkStats
+{
+    WorkStats
+    {
+        queued: QUEUED.load(Ordering::Relaxed),
+        run: RUN.load(Ordering::Relaxed),
+        dropped: DROPPED.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,132 @@
+/*
+
+    Keyboard layouts
+
+    ----------------------------------------------------------------------------
+
+    `pc_keyboard` selects the layout with a type parameter, so `Decoder` wraps
+    one decoder per supported layout and forwards to the active one.
+
+    | Layout   | `pc_keyboard` layout | Keyboard                           |
+    | -------- | -------------------- | ---------------------------------- |
+    | `Us104`  | `Us104Key`           | US, 104 keys                       |
+    | `Uk105`  | `Uk105Key`           | UK, 105 keys                       |
+    | `Jis106` | `Jis109Key`          | Japanese JIS, 106 or 109 keys      |
+    | `Dvorak` | `Dvorak104Key`       | US Dvorak, 104 keys                |
+
+    The JIS 109 key layout is the JIS 106 key layout plus the three Windows
+    keys, which do not produce characters.
+
+*/
+
+use pc_keyboard::{
+    layouts,
+    DecodedKey,
+    Error,
+    HandleControl,
+    KeyEvent,
+    Keyboard,
+    ScancodeSet1,
+};
+
+//------------------------------------------------------------------------------
+//  A keyboard layout.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout
+{
+    Us104,
+    Uk105,
+    Jis106,
+    Dvorak,
+}
+
+//------------------------------------------------------------------------------
+//  A scancode decoder for any of the layouts.
+//------------------------------------------------------------------------------
+pub(super) enum Decoder
+{
+    Us104(Keyboard<layouts::Us104Key, ScancodeSet1>),
+    Uk105(Keyboard<layouts::Uk105Key, ScancodeSet1>),
+    Jis106(Keyboard<layouts::Jis109Key, ScancodeSet1>),
+    Dvorak(Keyboard<layouts::Dvorak104Key, ScancodeSet1>),
+}
+
+//  Runs `$body` with `$keyboard` bound to the decoder of the active layout.
+macro_rules! with_keyboard
+{
+    ($decoder:expr, $keyboard:ident => $body:expr) =>
+    {
+        match $decoder
+        {
+            Decoder::Us104($keyboard) => $body,
+            Decoder::Uk105($keyboard) => $body,
+            Decoder::Jis106($keyboard) => $body,
+            Decoder::Dvorak($keyboard) => $body,
+        }
+    };
+}
+
+impl Decoder
+{
+    //--------------------------------------------------------------------------
+    //  Creates a decoder for `layout` with no modifier pressed and the default
+    //  lock keys (Num Lock on).
+    //--------------------------------------------------------------------------
+    pub(super) fn new( layout: Layout, handle_ctrl: HandleControl ) -> Self
+    {
+        match layout
+        {
+            Layout::Us104 => Decoder::Us104
+            (
+                Keyboard::new(layouts::Us104Key, ScancodeSet1, handle_ctrl)
+            ),
+            Layout::Uk105 => Decoder::Uk105
+            (
+                Keyboard::new(layouts::Uk105Key, ScancodeSet1, handle_ctrl)
+            ),
+            Layout::Jis106 => Decoder::Jis106
+            (
+                Keyboard::new(layouts::Jis109Key, ScancodeSet1, handle_ctrl)
+            ),
+            Layout::Dvorak => Decoder::Dvorak
+            (
+                Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, handle_ctrl)
+            ),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the layout of the decoder.
+    //--------------------------------------------------------------------------
+    pub(super) fn layout( &self ) -> Layout
+    {
+        match self
+        {
+            Decoder::Us104(_) => Layout::Us104,
+            Decoder::Uk105(_) => Layout::Uk105,
+            Decoder::Jis106(_) => Layout::Jis106,
+            Decoder::Dvorak(_) => Layout::Dvorak,
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Adds a byte of a scancode. Returns the key event once the scancode is
+    //  complete.
+    //--------------------------------------------------------------------------
+    pub(super) fn add_byte( &mut self, byte: u8 )
+        -> Result<Option<KeyEvent>, Error>
+    {
+        with_keyboard!(self, keyboard => keyboard.add_byte(byte))
+    }
+
+    //--------------------------------------------------------------------------
+    //  Updates the modifiers with a key event, and returns the key it
+    //  produces, if any.
+    //--------------------------------------------------------------------------
+    pub(super) fn process_keyevent( &mut self, event: KeyEvent )
+        -> Option<DecodedKey>
+    {
+        with_keyboard!(self, keyboard => keyboard.process_keyevent(event))
+    }
+}
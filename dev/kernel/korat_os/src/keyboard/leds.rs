@@ -0,0 +1,212 @@
+/*
+
+    Keyboard LEDs
+
+    ----------------------------------------------------------------------------
+
+    The LEDs are set with the `0xed` command followed by a data byte. The
+    keyboard acknowledges each byte with `0xfa`, or asks for it again with
+    `0xfe`, and the next byte must not be sent before the acknowledgement.
+
+    | Bit | LED         |
+    | --- | ----------- |
+    | 0   | Scroll Lock |
+    | 1   | Num Lock    |
+    | 2   | Caps Lock   |
+
+    The responses arrive through IRQ 1 like scancodes, so the exchange is
+    driven by the interrupt handler instead of waiting for them:
+
+                 set_leds            ACK                ACK
+      Idle --------------> Command -------> Data ---------------> Idle
+                   0xed sent        data sent      done; send again if the
+                                                   LEDs changed meanwhile
+
+*/
+
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+use super::DATA_PORT;
+
+//  The status port of the PS/2 controller, and its bit that is set while the
+//  controller has not taken the last byte written to the data port.
+const STATUS_PORT: u16 = 0x64;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+//  How many times the status is read before writing anyway.
+const WRITE_TIMEOUT: usize = 100_000;
+
+//  The command and the responses of the keyboard.
+const SET_LEDS: u8 = 0xed;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+//  How many times a byte is sent again before giving up.
+const MAX_RESENDS: u8 = 3;
+
+pub(super) const SCROLL_LOCK_LED: u8 = 1 << 0;
+pub(super) const NUM_LOCK_LED: u8 = 1 << 1;
+pub(super) const CAPS_LOCK_LED: u8 = 1 << 2;
+
+static LEDS: Mutex<LedController> = Mutex::new(LedController::new());
+
+//------------------------------------------------------------------------------
+//  The step of the LED command.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase
+{
+    Idle,
+
+    //  `SET_LEDS` was sent.
+    Command,
+
+    //  The data byte was sent.
+    Data,
+}
+
+//------------------------------------------------------------------------------
+//  The state of the LED command.
+//------------------------------------------------------------------------------
+struct LedController
+{
+    //  The LEDs that should be on.
+    requested: u8,
+
+    //  The LEDs the keyboard has acknowledged, if any.
+    current: Option<u8>,
+
+    //  The LEDs being sent.
+    sending: u8,
+
+    phase: Phase,
+    resends: u8,
+}
+
+impl LedController
+{
+    const fn new() -> Self
+    {
+        LedController
+        {
+            requested: 0,
+            current: None,
+            sending: 0,
+            phase: Phase::Idle,
+            resends: 0,
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Starts sending the requested LEDs, unless the keyboard has them on.
+    //--------------------------------------------------------------------------
+    fn start( &mut self )
+    {
+        if self.current == Some(self.requested)
+        {
+            return;
+        }
+
+        self.sending = self.requested;
+        self.resends = 0;
+        self.phase = Phase::Command;
+        write_data(SET_LEDS);
+    }
+
+    //--------------------------------------------------------------------------
+    //  Sends the byte of the current phase again.
+    //--------------------------------------------------------------------------
+    fn resend( &mut self )
+    {
+        if self.resends == MAX_RESENDS
+        {
+            //  Do not try again until the LEDs change.
+            self.current = Some(self.sending);
+            self.phase = Phase::Idle;
+            return;
+        }
+
+        self.resends += 1;
+        match self.phase
+        {
+            Phase::Idle => {},
+            Phase::Command => write_data(SET_LEDS),
+            Phase::Data => write_data(self.sending),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Asks the keyboard to turn on the given LEDs, and the others off.
+//------------------------------------------------------------------------------
+pub(super) fn set_leds( leds: u8 )
+{
+    interrupts::without_interrupts(||
+    {
+        let mut controller = LEDS.lock();
+        controller.requested = leds;
+        if controller.phase == Phase::Idle
+        {
+            controller.start();
+        }
+    });
+}
+
+//------------------------------------------------------------------------------
+//  Handles a byte from the keyboard while an LED command is in progress.
+//  Returns whether the byte was a response, not a scancode.
+//
+//  Called by the interrupt handler.
+//------------------------------------------------------------------------------
+pub(super) fn handle_response( byte: u8 ) -> bool
+{
+    if byte != ACK && byte != RESEND
+    {
+        return false;
+    }
+
+    let mut controller = LEDS.lock();
+    match (controller.phase, byte)
+    {
+        //  A stray acknowledgement.
+        (Phase::Idle, _) => {},
+        (Phase::Command, ACK) =>
+        {
+            controller.resends = 0;
+            controller.phase = Phase::Data;
+            write_data(controller.sending);
+        },
+        (Phase::Data, ACK) =>
+        {
+            controller.current = Some(controller.sending);
+            controller.phase = Phase::Idle;
+            controller.start();
+        },
+        _ => controller.resend(),
+    }
+    true
+}
+
+//------------------------------------------------------------------------------
+//  Writes a byte to the keyboard once the controller can take it.
+//------------------------------------------------------------------------------
+fn write_data( byte: u8 )
+{
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+
+    unsafe
+    {
+        for _ in 0..WRITE_TIMEOUT
+        {
+            if status.read() & STATUS_INPUT_FULL == 0
+            {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        data.write(byte);
+    }
+}
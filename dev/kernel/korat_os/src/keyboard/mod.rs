@@ -0,0 +1,369 @@
+/*
+
+    Keyboard
+
+    ----------------------------------------------------------------------------
+
+    The PS/2 keyboard raises IRQ 1 for every byte of a scancode it sends, and
+    does not send the next byte until the current one is read from port 0x60.
+    The interrupt handler does only that: it reads the byte and pushes it into
+    a lock-free queue, then wakes the task waiting for input.
+
+                  _________          _______          ____________
+      IRQ 1 -->  | handler | -----> | queue | -----> | read_key   |
+                 |_________|        |_______|        | KeyStream  |
+                      |                              |____________|
+                      +--> wake ------------------------^
+
+    Scancodes are decoded into keys by the consumer, outside of the interrupt,
+    with `pc_keyboard`. Keys are read either by blocking on `read_key`, or by
+    polling a `KeyStream` from an async task. All readers share one queue, so
+    each key goes to only one of them, and only the last task that polled a
+    `KeyStream` is woken.
+
+    When the queue is full, new scancodes are dropped and counted, and a
+    warning is printed once per overflow, as deferred work so the handler
+    does not take the lock of the screen.
+
+
+    # Layouts and modifiers
+
+    The layout is chosen at runtime with `set_layout` (see `layout`). Keys
+    pressed together with Ctrl come through as control codes, e.g. Ctrl+C as
+    `'\u{3}'`. The decoder toggles Caps Lock and Num Lock, and the keyboard
+    LEDs follow them and Scroll Lock (see `leds`).
+
+*/
+
+mod layout;
+mod leds;
+mod queue;
+
+pub use layout::Layout;
+pub use queue::SCANCODE_QUEUE_SIZE;
+
+use crate::interrupts::{ self, InterruptIndex, IrqStatus };
+use crate::println;
+use layout::Decoder;
+use queue::ScancodeQueue;
+
+use core::pin::Pin;
+use core::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use core::task::{ Context, Poll };
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use pc_keyboard::{ DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState };
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+//  The data port of the PS/2 controller.
+const DATA_PORT: u16 = 0x60;
+
+//  Keys pressed with Ctrl are mapped to control codes.
+const CTRL_HANDLING: HandleControl = HandleControl::MapLettersToUnicode;
+
+static QUEUE: ScancodeQueue = ScancodeQueue::new();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static OVERFLOWING: AtomicBool = AtomicBool::new(false);
+
+lazy_static!
+{
+    //  The decoder state, which also serializes the consumers of the queue.
+    static ref STATE: Mutex<KeyboardState> = Mutex::new(KeyboardState
+    {
+        decoder: Decoder::new(Layout::Us104, CTRL_HANDLING),
+        lock_keys: LockKeys::new(),
+    });
+}
+
+//------------------------------------------------------------------------------
+//  The state of the lock keys.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockKeys
+{
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl LockKeys
+{
+    //--------------------------------------------------------------------------
+    //  Returns the state of a new decoder, which starts with Num Lock on.
+    //--------------------------------------------------------------------------
+    const fn new() -> Self
+    {
+        LockKeys { caps_lock: false, num_lock: true, scroll_lock: false }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Toggles the lock of a pressed key. Returns false if it is no lock key.
+    //--------------------------------------------------------------------------
+    fn toggle( &mut self, code: KeyCode ) -> bool
+    {
+        match code
+        {
+            KeyCode::CapsLock => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock => self.scroll_lock = !self.scroll_lock,
+            _ => return false,
+        }
+        true
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the keyboard LEDs of the locks.
+    //--------------------------------------------------------------------------
+    fn leds( &self ) -> u8
+    {
+        let mut leds = 0;
+        if self.caps_lock
+        {
+            leds |= leds::CAPS_LOCK_LED;
+        }
+        if self.num_lock
+        {
+            leds |= leds::NUM_LOCK_LED;
+        }
+        if self.scroll_lock
+        {
+            leds |= leds::SCROLL_LOCK_LED;
+        }
+        leds
+    }
+}
+
+//------------------------------------------------------------------------------
+//  The decoder and the lock keys it has seen.
+//------------------------------------------------------------------------------
+struct KeyboardState
+{
+    decoder: Decoder,
+    lock_keys: LockKeys,
+}
+
+//------------------------------------------------------------------------------
+//  Registers the keyboard interrupt handler.
+//------------------------------------------------------------------------------
+pub fn init()
+{
+    interrupts::register_irq_handler
+    (
+        InterruptIndex::Keyboard.irq(),
+        keyboard_interrupt_handler
+    )
+    .expect("registering the keyboard handler failed");
+}
+
+//------------------------------------------------------------------------------
+//  A keyboard interrupt hander.
+//
+//  Keyboard input will not receive further input until the scan code is read.
+//------------------------------------------------------------------------------
+fn keyboard_interrupt_handler() -> IrqStatus
+{
+    let mut port = Port::new(DATA_PORT);
+    let scancode: u8 = unsafe { port.read() };
+
+    if !leds::handle_response(scancode)
+    {
+        push_scancode(scancode);
+    }
+    IrqStatus::Handled
+}
+
+//------------------------------------------------------------------------------
+//  Queues a scancode as if the keyboard had sent it, e.g. to feed input from
+//  another device.
+//------------------------------------------------------------------------------
+pub fn add_scancode( scancode: u8 )
+{
+    //  The interrupt handler is the other producer.
+    cpu_interrupts::without_interrupts(|| push_scancode(scancode));
+}
+
+//------------------------------------------------------------------------------
+//  Queues a scancode and wakes the waiting task, or counts it as dropped if
+//  the queue is full.
+//------------------------------------------------------------------------------
+fn push_scancode( scancode: u8 )
+{
+    if QUEUE.push(scancode)
+    {
+        OVERFLOWING.store(false, Ordering::Relaxed);
+        WAKER.wake();
+        return;
+    }
+
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+    if !OVERFLOWING.swap(true, Ordering::Relaxed)
+    {
+        interrupts::queue_work(warn_overflow, 0);
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Warns that scancodes are being dropped. Runs as deferred work.
+//------------------------------------------------------------------------------
+fn warn_overflow( _: usize )
+{
+    println!("WARNING: scancode queue full; dropping keyboard input");
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of scancodes dropped because the queue was full.
+//------------------------------------------------------------------------------
+pub fn dropped_scancodes() -> u64
+{
+    DROPPED.load(Ordering::Relaxed)
+}
+
+//------------------------------------------------------------------------------
+//  Decodes the queued scancodes until a key is complete. Returns `None` once
+//  the queue is empty.
+//------------------------------------------------------------------------------
+pub fn try_read_key() -> Option<DecodedKey>
+{
+    let mut state = STATE.lock();
+    while let Some(scancode) = QUEUE.pop()
+    {
+        if let Ok(Some(key_event)) = state.decoder.add_byte(scancode)
+        {
+            if key_event.state == KeyState::Down
+                && state.lock_keys.toggle(key_event.code)
+            {
+                leds::set_leds(state.lock_keys.leds());
+            }
+            if let Some(key) = state.decoder.process_keyevent(key_event)
+            {
+                return Some(key);
+            }
+        }
+    }
+    None
+}
+
+//------------------------------------------------------------------------------
+//  Returns the state of the lock keys.
+//------------------------------------------------------------------------------
+pub fn lock_keys() -> LockKeys
+{
+    STATE.lock().lock_keys
+}
+
+//------------------------------------------------------------------------------
+//  Returns the active keyboard layout.
+//------------------------------------------------------------------------------
+pub fn layout() -> Layout
+{
+    STATE.lock().decoder.layout()
+}
+
+//------------------------------------------------------------------------------
+//  Switches to another keyboard layout. The lock keys stay as they are, but
+//  modifiers held down at the time are released.
+//------------------------------------------------------------------------------
+pub fn set_layout( layout: Layout )
+{
+    let mut state = STATE.lock();
+    let mut decoder = Decoder::new(layout, CTRL_HANDLING);
+
+    //  Press the lock keys that differ from the new decoder's defaults.
+    let defaults = LockKeys::new();
+    let locks =
+    [
+        (KeyCode::CapsLock, state.lock_keys.caps_lock, defaults.caps_lock),
+        (KeyCode::NumpadLock, state.lock_keys.num_lock, defaults.num_lock),
+    ];
+    for (code, on, default) in locks
+    {
+        if on != default
+        {
+            for key_state in [KeyState::Down, KeyState::Up]
+            {
+                decoder.process_keyevent(KeyEvent::new(code, key_state));
+            }
+        }
+    }
+    state.decoder = decoder;
+}
+
+//------------------------------------------------------------------------------
+//  Waits for the next key, halting the CPU until the keyboard sends input,
+//  and running deferred work meanwhile.
+//
+//  Must be called with interrupts enabled, and not from an interrupt handler.
+//------------------------------------------------------------------------------
+pub fn read_key() -> DecodedKey
+{
+    loop
+    {
+        //  A scancode arriving between the check and `hlt` would not wake the
+        //  CPU, so interrupts are only enabled again by `hlt` itself.
+        cpu_interrupts::disable();
+        if let Some(key) = try_read_key()
+        {
+            cpu_interrupts::enable();
+            return key;
+        }
+        interrupts::wait_for_interrupt();
+    }
+}
+
+//------------------------------------------------------------------------------
+//  An endless stream of the keys typed on the keyboard.
+//------------------------------------------------------------------------------
+pub struct KeyStream
+{
+    _private: (),
+}
+
+impl KeyStream
+{
+    pub fn new() -> Self
+    {
+        KeyStream { _private: () }
+    }
+}
+
+impl Default for KeyStream
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+impl Stream for KeyStream
+{
+    type Item = DecodedKey;
+
+    fn poll_next
+    (
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+    ) -> Poll<Option<DecodedKey>>
+    {
+        if let Some(key) = try_read_key()
+        {
+            return Poll::Ready(Some(key));
+        }
+
+        //  Check again after registering, in case a scancode arrived in the
+        //  meantime and its wake was missed.
+        WAKER.register(cx.waker());
+        match try_read_key()
+        {
+            Some(key) =>
+            {
+                WAKER.take();
+                Poll::Ready(Some(key))
+            },
+            None => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+/*
+
+    Scancode queue
+
+    ----------------------------------------------------------------------------
+
+    A bounded single-producer single-consumer ring buffer of bytes. The
+    producer is the keyboard interrupt, so neither side may take a lock:
+    each side only writes its own index, and a slot is published by storing
+    the producer index with release ordering after the slot is written.
+
+          head (consumer)             tail (producer)
+              |                           |
+              v                           v
+      +-----+-----+-----+-----+-----+-----+-----+-----+
+      |     |  a  |  b  |  c  |  d  |  e  |     |     |
+      +-----+-----+-----+-----+-----+-----+-----+-----+
+
+    The indices count pushed and popped bytes and wrap around, so the queue is
+    empty when they are equal and full when they are `SCANCODE_QUEUE_SIZE`
+    apart.
+
+*/
+
+use core::sync::atomic::{ AtomicU8, AtomicUsize, Ordering };
+
+//  The number of scancodes the queue holds. A power of two.
+pub const SCANCODE_QUEUE_SIZE: usize = 128;
+
+//------------------------------------------------------------------------------
+//  A bounded lock-free queue of scancodes.
+//------------------------------------------------------------------------------
+pub(super) struct ScancodeQueue
+{
+    slots: [AtomicU8; SCANCODE_QUEUE_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+impl ScancodeQueue
+{
+    pub(super) const fn new() -> Self
+    {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: AtomicU8 = AtomicU8::new(0);
+
+        ScancodeQueue
+        {
+            slots: [EMPTY; SCANCODE_QUEUE_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Appends a scancode. Returns false if the queue is full.
+    //
+    //  Must only be called by one producer at a time.
+    //--------------------------------------------------------------------------
+    pub(super) fn push( &self, scancode: u8 ) -> bool
+    {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == SCANCODE_QUEUE_SIZE
+        {
+            return false;
+        }
+
+        self.slots[tail % SCANCODE_QUEUE_SIZE]
+            .store(scancode, Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    //--------------------------------------------------------------------------
+    //  Removes the oldest scancode.
+    //
+    //  Must only be called by one consumer at a time.
+    //--------------------------------------------------------------------------
+    pub(super) fn pop( &self ) -> Option<u8>
+    {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail
+        {
+            return None;
+        }
+
+        let scancode = self.slots[head % SCANCODE_QUEUE_SIZE]
+            .load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(scancode)
+    }
+}
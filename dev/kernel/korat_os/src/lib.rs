@@ -18,16 +18,19 @@
 #![reexport_test_harness_main = "test_main"]
 
 #[cfg(test)]
-use bootloader::{ BootInfo, entry_point };
+use bootloader::entry_point;
 
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
 
 pub mod serial;
 pub mod vga_buffer;
 pub mod interrupts;
+pub mod keyboard;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod time;
 
 extern crate alloc;
 
@@ -106,11 +109,12 @@ pub fn exit_qemu( exit_code: QemuExitCode )
 //  Entry point for `cargo test`.
 //------------------------------------------------------------------------------
 #[cfg(test)]
-fn test_kernel_main( _boot_info: &'static BootInfo ) -> !
+fn test_kernel_main( boot_info: &'static BootInfo ) -> !
 {
     init();
+    init_memory(boot_info);
     test_main();
-    hlt_loop();
+    idle_loop();
 }
 
 //------------------------------------------------------------------------------
@@ -130,26 +134,107 @@ pub fn init()
 {
     gdt::init_gdt();
     interrupts::init_idt();
+    keyboard::init();
+    time::init();
     unsafe { interrupts::PICS.lock().initialize() }
     x86_64::instructions::interrupts::enable();
 }
 
 //------------------------------------------------------------------------------
-//  Stop CPU.
+//  Initializes paging, the frame allocator, the heap and the kernel VMA tree,
+//  enforces W^X on the kernel's memory, switches to the APIC and calibrates
+//  the TSC.
+//------------------------------------------------------------------------------
+pub fn init_memory( boot_info: &'static BootInfo )
+{
+    use memory::BuddyFrameAllocator;
+    use x86_64::VirtAddr;
+
+    memory::enable_no_execute();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator = unsafe
+    {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+    memory::install(mapper, frame_allocator);
+
+    allocator::init_heap().expect("heap initialization failed");
+    memory::init_vmas(&boot_info.memory_map, phys_mem_offset);
+    unsafe { memory::protect_kernel(&boot_info.memory_map) };
+
+    //  Without an APIC, the PIC set up by `init` stays in use.
+    match interrupts::init_apic()
+    {
+        Ok(()) | Err(interrupts::ApicError::NotSupported) => {},
+        Err(err) => panic!("APIC initialization failed: {:?}", err),
+    }
+
+    //  Without a TSC, the clock stays at the resolution of the PIT.
+    match time::calibrate_tsc()
+    {
+        Ok(_) | Err(time::TscError::NotSupported) => {},
+        Err(err) => panic!("TSC calibration failed: {:?}", err),
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Ends early boot: gives the memory the bootloader no longer needs to the
+//  frame allocator, and prints a summary of the boot memory map and the TSC
+//  frequency.
+//
+//  Must be called after `init` and `init_memory`, once the kernel runs on its
+//  own GDT and IDT.
+//------------------------------------------------------------------------------
+pub fn finish_early_boot( boot_info: &'static BootInfo )
+{
+    let memory_map = &boot_info.memory_map;
+    let summary = memory::BootMemorySummary::new(memory_map);
+    let reclaimed = unsafe { memory::reclaim_boot_memory(memory_map) };
+
+    println!("{}", summary);
+    println!("reclaimed {} KiB of boot memory", reclaimed / 1024);
+    if let Some(calibration) = time::tsc_calibration()
+    {
+        println!("{}", calibration);
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Stop CPU, with interrupts disabled. Used on the panic paths, where locks
+//  may still be held, so no interrupt handler or deferred work runs.
 //------------------------------------------------------------------------------
 pub fn hlt_loop() -> !
 {
     loop
     {
+        x86_64::instructions::interrupts::disable();
         x86_64::instructions::hlt();
     }
 }
 
 //------------------------------------------------------------------------------
-//  Alloc error handler.
+//  Idle when there is nothing left to do, halting the CPU between interrupts
+//  and running the deferred work they queue.
+//------------------------------------------------------------------------------
+pub fn idle_loop() -> !
+{
+    loop
+    {
+        x86_64::instructions::interrupts::disable();
+        interrupts::wait_for_interrupt();
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Alloc error handler. Prints the heap statistics before panicking.
 //------------------------------------------------------------------------------
 #[alloc_error_handler]
 fn alloc_error_handler( layout: alloc::alloc::Layout ) -> !
 {
+    let stats = allocator::heap_stats();
+    println!("{}", stats);
+    serial_println!("{}", stats);
     panic!("allocation error: {:?}", layout)
 }
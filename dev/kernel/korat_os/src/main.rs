@@ -15,7 +15,8 @@
 #![test_runner(korat_os::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
-use korat_os::println;
+use korat_os::{ keyboard, print, println };
+use korat_os::keyboard::Layout;
 
 use alloc::vec;
 use alloc::vec::Vec;
@@ -23,6 +24,7 @@ use alloc::rc::Rc;
 use alloc::boxed::Box;
 use core::panic::PanicInfo;
 use bootloader::{ BootInfo, entry_point };
+use pc_keyboard::{ DecodedKey, KeyCode };
 
 extern crate alloc;
 
@@ -35,31 +37,34 @@ entry_point!(kernel_main);
 //------------------------------------------------------------------------------
 fn kernel_main( boot_info: &'static BootInfo ) -> !
 {
-    use korat_os::allocator;
-    use korat_os::memory::{ self, BootInfoFrameAllocator };
-    use x86_64::VirtAddr;
-    use x86_64::structures::paging::Page;
+    use korat_os::memory;
+    use x86_64::PhysAddr;
+    use x86_64::structures::paging::PageTableFlags;
 
     println!("Hello, world");
     korat_os::init();
+    korat_os::init_memory(boot_info);
+    korat_os::finish_early_boot(boot_info);
 
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe
+    for vma in memory::kernel_layout()
     {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
+        println!("{}", vma);
+    }
 
-    //  Map an unused page.
-    let page = Page::containing_address(VirtAddr::new(0xdeadbeaf000));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    //  Map the VGA text buffer.
+    let mut vga_buffer = unsafe
+    {
+        memory::map_mmio
+        (
+            PhysAddr::new(0xb8000),
+            4096,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        )
+    }
+    .expect("mapping the VGA buffer failed");
 
     //  Write the string `New!` to the screen through the new mapping.
-    let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
-    unsafe { page_ptr.offset(400).write_volatile(0x_f021_f077_f065_f04e) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    vga_buffer.write::<u64>(400 * 8, 0x_f021_f077_f065_f04e);
 
     let heap_value = Box::new(41);
     println!("heap_value at {:p}", heap_value);
@@ -80,7 +85,28 @@ fn kernel_main( boot_info: &'static BootInfo ) -> !
     #[cfg(test)]
     test_main();
 
-    korat_os::hlt_loop();
+    //  Echo the keyboard input. F1 to F4 switch the layout.
+    loop
+    {
+        match keyboard::read_key()
+        {
+            DecodedKey::RawKey(KeyCode::F1) => set_layout(Layout::Us104),
+            DecodedKey::RawKey(KeyCode::F2) => set_layout(Layout::Uk105),
+            DecodedKey::RawKey(KeyCode::F3) => set_layout(Layout::Jis106),
+            DecodedKey::RawKey(KeyCode::F4) => set_layout(Layout::Dvorak),
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Switches the keyboard layout and tells the user.
+//------------------------------------------------------------------------------
+fn set_layout( layout: Layout )
+{
+    keyboard::set_layout(layout);
+    println!("\nkeyboard layout: {:?}", layout);
 }
 
 //------------------------------------------------------------------------------
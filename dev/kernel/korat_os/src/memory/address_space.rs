@@ -0,0 +1,547 @@
+/*
+
+    Address spaces
+
+    ----------------------------------------------------------------------------
+
+    Each process gets its own page table hierarchy, so that its memory is
+    invisible to every other process. An `AddressSpace` owns a level 4 table
+    and everything mapped below it in the user range.
+
+      level 4 table of a space
+      +-------------------------+
+      | kernel entries (copied) | ---> kernel level 3 tables (shared)
+      +-------------------------+
+      | user entries            | ---> level 3 tables owned by the space
+      +-------------------------+
+      | kernel entries (copied) | ---> kernel level 3 tables (shared)
+      +-------------------------+
+
+    The bootloader places the kernel in the lower half, so the kernel does
+    not live in the upper half of the level 4 table as on most systems.
+    Instead, the user range `USER_SPACE_START..USER_SPACE_END` is kept free
+    in the kernel's table, and a new space copies every other entry from it.
+    The copied entries point to the kernel's level 3 tables, so kernel
+    mappings made below them later show up in every space. The level 3 tables
+    of the dynamic VMA window are allocated at boot for this reason (see
+    `init_vmas`).
+
+    Pages mapped with `map` get a new zeroed frame, which the space owns and
+    marks with `OWNED` in a spare bit of the entry. Frames mapped with
+    `map_frame` are only borrowed. When the space is dropped, the owned frames
+    and all the page tables of the user range are given back to the frame
+    allocator.
+
+    `fork` creates a copy of a space that shares its owned frames
+    copy-on-write (see `cow`). Owned frames are reference counted by the
+    frame allocator, so a shared frame is only freed by the last space that
+    maps it.
+
+    `activate` loads the level 4 table into `Cr3`, and `activate_kernel`
+    switches back to the kernel's own table.
+
+*/
+
+use super::{ BuddyFrameAllocator, COPY_ON_WRITE, KernelMemory, MapError };
+
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    Mapper,
+    OffsetPageTable,
+    Page,
+    PageSize,
+    PageTable,
+    PageTableFlags,
+    PhysFrame,
+    Size4KiB,
+    Translate,
+};
+use x86_64::structures::paging::mapper::TranslateResult;
+
+//  The range of virtual memory that is private to each address space.
+pub const USER_SPACE_START: u64 = 0x_1000_0000_0000;
+pub const USER_SPACE_END: u64 = 0x_4000_0000_0000;
+
+//  Marks a page whose frame belongs to the address space.
+const OWNED: PageTableFlags = PageTableFlags::BIT_9;
+
+//  The size of the address range covered by one level 4 entry.
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+//  The level 4 entries of the user range.
+const USER_ENTRIES_START: usize =
+    (USER_SPACE_START / LEVEL_4_ENTRY_SIZE) as usize;
+const USER_ENTRIES_END: usize =
+    (USER_SPACE_END / LEVEL_4_ENTRY_SIZE) as usize;
+
+//------------------------------------------------------------------------------
+//  A page table hierarchy with the kernel mapped and a private user range.
+//------------------------------------------------------------------------------
+#[derive(Debug)]
+pub struct AddressSpace
+{
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+}
+
+impl AddressSpace
+{
+    //--------------------------------------------------------------------------
+    //  Creates an address space with the kernel mapped and nothing in the
+    //  user range.
+    //
+    //  Fails with `InvalidRange` if the kernel itself uses the user range.
+    //--------------------------------------------------------------------------
+    pub fn new() -> Result<AddressSpace, MapError>
+    {
+        super::with_kernel_memory(|memory|
+        {
+            let physical_memory_offset = memory.mapper.phys_offset();
+            let kernel_table = memory.mapper.level_4_table();
+            let user_range_used = kernel_table
+                .iter()
+                .skip(USER_ENTRIES_START)
+                .take(USER_ENTRIES_END - USER_ENTRIES_START)
+                .any(|entry| !entry.is_unused());
+            if user_range_used
+            {
+                return Err(MapError::InvalidRange);
+            }
+
+            let level_4_frame: PhysFrame = memory.frame_allocator
+                .allocate_frame()
+                .ok_or(MapError::FrameAllocationFailed)?;
+            let table_addr = physical_memory_offset
+                + level_4_frame.start_address().as_u64();
+            let table: *mut PageTable = table_addr.as_mut_ptr();
+            let mut level_4_table = PageTable::new();
+            for (i, entry) in kernel_table.iter().enumerate()
+            {
+                if !is_user_entry(i)
+                {
+                    level_4_table[i] = entry.clone();
+                }
+            }
+            unsafe { table.write(level_4_table) };
+
+            Ok(AddressSpace { level_4_frame, physical_memory_offset })
+        })
+        .unwrap_or(Err(MapError::NotInstalled))
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the frame of the level 4 table.
+    //--------------------------------------------------------------------------
+    pub fn level_4_frame( &self ) -> PhysFrame
+    {
+        self.level_4_frame
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the address space is loaded in `Cr3`.
+    //--------------------------------------------------------------------------
+    pub fn is_active( &self ) -> bool
+    {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    //--------------------------------------------------------------------------
+    //  Maps the given page of the user range to a new zeroed frame owned by
+    //  the address space. `PRESENT` is added to the flags.
+    //--------------------------------------------------------------------------
+    pub fn map
+    (
+        &mut self,
+        page: Page<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError>
+    {
+        check_user_page(page)?;
+        let flags = super::supported_flags
+        (
+            (flags | PageTableFlags::PRESENT | OWNED) - COPY_ON_WRITE
+        );
+
+        super::with_kernel_memory(|memory|
+        {
+            let frame: PhysFrame = memory.frame_allocator
+                .allocate_frame()
+                .ok_or(MapError::FrameAllocationFailed)?;
+            let frame_addr = self.physical_memory_offset
+                + frame.start_address().as_u64();
+            unsafe
+            {
+                frame_addr.as_mut_ptr::<u8>()
+                    .write_bytes(0, Size4KiB::SIZE as usize);
+            }
+
+            let result = unsafe { self.map_to(memory, page, frame, flags) };
+            if result.is_err()
+            {
+                unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            }
+            result
+        })
+        .unwrap_or(Err(MapError::NotInstalled))
+    }
+
+    //--------------------------------------------------------------------------
+    //  Maps the given page of the user range to the given frame. `PRESENT` is
+    //  added to the flags. The frame is not freed with the address space.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  frame stays valid as long as it is mapped, and that accessing it with
+    //  the given flags has no undefined behavior.
+    //--------------------------------------------------------------------------
+    pub unsafe fn map_frame
+    (
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError>
+    {
+        check_user_page(page)?;
+        let mut flags = flags | PageTableFlags::PRESENT;
+        flags.remove(OWNED | COPY_ON_WRITE);
+        let flags = super::supported_flags(flags);
+
+        super::with_kernel_memory(|memory|
+            self.map_to(memory, page, frame, flags)
+        )
+        .unwrap_or(Err(MapError::NotInstalled))
+    }
+
+    //--------------------------------------------------------------------------
+    //  Unmaps the given page, and drops the reference to its frame if the
+    //  address space owns it.
+    //
+    //  Returns whether the page was mapped.
+    //--------------------------------------------------------------------------
+    pub fn unmap( &mut self, page: Page<Size4KiB> ) -> bool
+    {
+        if check_user_page(page).is_err()
+        {
+            return false;
+        }
+
+        let mut mapper = unsafe { self.mapper() };
+        let owned = match mapper.translate(page.start_address())
+        {
+            TranslateResult::Mapped { flags, .. } => flags.contains(OWNED),
+            _ => return false,
+        };
+        let frame = match mapper.unmap(page)
+        {
+            Ok((frame, flush)) =>
+            {
+                flush.flush();
+                frame
+            },
+            Err(_) => return false,
+        };
+
+        if owned
+        {
+            super::with_kernel_memory(|memory| unsafe
+            {
+                memory.frame_allocator.release(frame, 0)
+            });
+        }
+        true
+    }
+
+    //--------------------------------------------------------------------------
+    //  Creates a copy of the address space. The owned frames are shared with
+    //  the copy, and the writable ones are made copy-on-write in both spaces.
+    //  Borrowed frames are mapped in the copy as they are.
+    //--------------------------------------------------------------------------
+    pub fn fork( &mut self ) -> Result<AddressSpace, MapError>
+    {
+        let child = AddressSpace::new()?;
+        let level_4_table = self.level_4_frame.start_address();
+        let child_table = child.level_4_frame.start_address();
+
+        //  On failure, dropping the child releases what was shared so far.
+        super::with_kernel_memory(|memory| unsafe
+        {
+            self.fork_table(memory, level_4_table, child_table, 4)
+        })
+        .unwrap_or(Err(MapError::NotInstalled))?;
+
+        //  Writable pages of this space became read-only.
+        if self.is_active()
+        {
+            tlb::flush_all();
+        }
+        Ok(child)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the physical address the given address is mapped to in this
+    //  address space.
+    //--------------------------------------------------------------------------
+    pub fn translate( &self, addr: VirtAddr ) -> Option<PhysAddr>
+    {
+        let table = unsafe { &mut *self.level_4_table() };
+        let mapper = unsafe
+        {
+            OffsetPageTable::new(table, self.physical_memory_offset)
+        };
+        mapper.translate_addr(addr)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Loads the address space into `Cr3`.
+    //
+    //  This function is unsafe because the caller must guarantee that no
+    //  reference into the user range of the current space is used afterwards.
+    //--------------------------------------------------------------------------
+    pub unsafe fn activate( &self )
+    {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    //--------------------------------------------------------------------------
+    //  Maps `page` to `frame` with the given flags, allocating page tables
+    //  from the kernel's frame allocator.
+    //--------------------------------------------------------------------------
+    unsafe fn map_to
+    (
+        &mut self,
+        memory: &mut KernelMemory,
+        page: Page<Size4KiB>,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError>
+    {
+        let table_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | (flags & PageTableFlags::USER_ACCESSIBLE);
+        let mut mapper = self.mapper();
+        let flush = mapper.map_to_with_table_flags
+        (
+            page,
+            frame,
+            flags,
+            table_flags,
+            &mut memory.frame_allocator
+        )?;
+
+        //  The space may not be active, in which case flushing does no harm.
+        flush.flush();
+        Ok(())
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns a mapper for the address space.
+    //
+    //  This function is unsafe because only one mapper may exist at a time.
+    //--------------------------------------------------------------------------
+    unsafe fn mapper( &mut self ) -> OffsetPageTable<'_>
+    {
+        OffsetPageTable::new
+        (
+            &mut *self.level_4_table(),
+            self.physical_memory_offset
+        )
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns a pointer to the level 4 table.
+    //--------------------------------------------------------------------------
+    fn level_4_table( &self ) -> *mut PageTable
+    {
+        self.table(self.level_4_frame.start_address())
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns a pointer to the page table at the given physical address.
+    //--------------------------------------------------------------------------
+    fn table( &self, addr: PhysAddr ) -> *mut PageTable
+    {
+        (self.physical_memory_offset + addr.as_u64()).as_mut_ptr()
+    }
+
+    //--------------------------------------------------------------------------
+    //  Copies the user entries of the table at `addr` of the given level into
+    //  the empty table at `child_addr`, with new tables below it.
+    //--------------------------------------------------------------------------
+    unsafe fn fork_table
+    (
+        &self,
+        memory: &mut KernelMemory,
+        addr: PhysAddr,
+        child_addr: PhysAddr,
+        level: usize,
+    ) -> Result<(), MapError>
+    {
+        let table = &mut *self.table(addr);
+        let child_table = &mut *self.table(child_addr);
+        for (i, entry) in table.iter_mut().enumerate()
+        {
+            let mut flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT)
+                || (level == 4 && !is_user_entry(i))
+            {
+                continue;
+            }
+
+            if level > 1 && !flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                let frame: PhysFrame = memory.frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapError::FrameAllocationFailed)?;
+                self.table(frame.start_address()).write(PageTable::new());
+                child_table[i].set_frame(frame, flags);
+                self.fork_table
+                (
+                    memory,
+                    entry.addr(),
+                    frame.start_address(),
+                    level - 1
+                )?;
+                continue;
+            }
+
+            if flags.contains(OWNED)
+            {
+                if flags.contains(PageTableFlags::WRITABLE)
+                {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(COPY_ON_WRITE);
+                    entry.set_flags(flags);
+                }
+                let frame = PhysFrame::containing_address(entry.addr());
+                memory.frame_allocator.add_reference(frame);
+            }
+            child_table[i].set_addr(entry.addr(), flags);
+        }
+        Ok(())
+    }
+
+    //--------------------------------------------------------------------------
+    //  Frees the table at `addr` of the given level and every table below it,
+    //  and drops the references to the owned frames mapped by it.
+    //--------------------------------------------------------------------------
+    unsafe fn free_table
+    (
+        &self,
+        allocator: &mut BuddyFrameAllocator,
+        addr: PhysAddr,
+        level: usize,
+    )
+    {
+        let table = &*self.table(addr);
+        for entry in table.iter()
+        {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT)
+            {
+                continue;
+            }
+
+            if level == 1
+            {
+                if flags.contains(OWNED)
+                {
+                    let frame = PhysFrame::containing_address(entry.addr());
+                    allocator.release(frame, 0);
+                }
+            }
+            else if !flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                self.free_table(allocator, entry.addr(), level - 1);
+            }
+        }
+        let frame = PhysFrame::<Size4KiB>::containing_address(addr);
+        allocator.deallocate_frame(frame);
+    }
+}
+
+impl Drop for AddressSpace
+{
+    fn drop( &mut self )
+    {
+        if self.is_active()
+        {
+            unsafe { activate_kernel() };
+        }
+
+        let level_4_table = unsafe { &*self.level_4_table() };
+        super::with_kernel_memory(|memory|
+        {
+            for (i, entry) in level_4_table.iter().enumerate()
+            {
+                if is_user_entry(i) && !entry.is_unused()
+                {
+                    unsafe
+                    {
+                        self.free_table
+                        (
+                            &mut memory.frame_allocator,
+                            entry.addr(),
+                            3
+                        );
+                    }
+                }
+            }
+            unsafe
+            {
+                memory.frame_allocator.deallocate_frame(self.level_4_frame);
+            }
+        });
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Loads the kernel's own level 4 table into `Cr3`.
+//
+//  This function is unsafe because the caller must guarantee that no
+//  reference into the user range of the current space is used afterwards.
+//------------------------------------------------------------------------------
+pub unsafe fn activate_kernel()
+{
+    let frame = super::with_kernel_memory(|memory|
+    {
+        let physical_memory_offset = memory.mapper.phys_offset();
+        let table = VirtAddr::from_ptr(memory.mapper.level_4_table());
+        PhysFrame::containing_address
+        (
+            PhysAddr::new(table - physical_memory_offset)
+        )
+    })
+    .expect("kernel memory is not installed");
+
+    let (_, flags) = Cr3::read();
+    Cr3::write(frame, flags);
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether the given level 4 entry belongs to the user range.
+//------------------------------------------------------------------------------
+fn is_user_entry( index: usize ) -> bool
+{
+    (USER_ENTRIES_START..USER_ENTRIES_END).contains(&index)
+}
+
+//------------------------------------------------------------------------------
+//  Checks that the given page is in the user range.
+//------------------------------------------------------------------------------
+fn check_user_page( page: Page<Size4KiB> ) -> Result<(), MapError>
+{
+    let addr = page.start_address().as_u64();
+    if (USER_SPACE_START..USER_SPACE_END).contains(&addr)
+    {
+        Ok(())
+    }
+    else
+    {
+        Err(MapError::InvalidRange)
+    }
+}
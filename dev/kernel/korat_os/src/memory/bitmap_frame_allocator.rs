@@ -0,0 +1,264 @@
+/*
+
+    Bitmap frame allocator
+
+    ----------------------------------------------------------------------------
+
+    The bitmap frame allocator keeps one bit per physical frame. A set bit
+    means that the frame is in use (or is not usable RAM at all), a cleared bit
+    means that the frame is free.
+
+    | Word  | Bits                  | Frames                 |
+    | ----- | --------------------- | ---------------------- |
+    | 0     | 0 ~ 63                | 0x0000000 ~ 0x003f000  |
+    | 1     | 0 ~ 63                | 0x0040000 ~ 0x007f000  |
+    | ...   | ...                   | ...                    |
+
+    The bitmap itself is placed in the first usable region that is large
+    enough to hold it, and is accessed through the physical memory mapping.
+    The frames holding the bitmap are marked as used so that they are never
+    handed out.
+
+    Allocation scans the bitmap one 64 bit word at a time, starting from the
+    word where the last free frame was found. A full word is skipped with a
+    single comparison, so allocation takes near-constant time.
+
+    Since a set bit does not tell an allocated frame from one that is not
+    usable RAM, deallocation checks the frame against the usable regions of
+    the memory map, so memory such as the VGA buffer or the kernel image is
+    never freed into the allocator.
+
+    The kernel uses the buddy allocator (see `buddy_frame_allocator`), which
+    also serves contiguous blocks. This allocator is kept as a simpler
+    alternative for single frames.
+
+*/
+
+use super::usable_regions;
+
+use bootloader::bootinfo::MemoryMap;
+use core::ops::Range;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    PageSize,
+    PhysFrame,
+    Size4KiB,
+};
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+//------------------------------------------------------------------------------
+//  A FrameAllocator that tracks usable frames in a bitmap.
+//------------------------------------------------------------------------------
+pub struct BitmapFrameAllocator
+{
+    memory_map: &'static MemoryMap,
+    bitmap: &'static mut [u64],
+    bitmap_frames: Range<usize>,
+    total_frames: usize,
+    free_frames: usize,
+    next: usize,
+}
+
+impl BitmapFrameAllocator
+{
+    //--------------------------------------------------------------------------
+    //  Create a BitmapFrameAllocator from the passed memory map.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  passed memory map is valid, that all frames marked as `USABLE` in it
+    //  are really unused, and that all physical memory is mapped at the
+    //  passed `physical_memory_offset`. It should only be called once, since
+    //  the bitmap is stored in the usable memory itself.
+    //--------------------------------------------------------------------------
+    pub unsafe fn init
+    (
+        memory_map: &'static MemoryMap,
+        physical_memory_offset: VirtAddr,
+    ) -> BitmapFrameAllocator
+    {
+        //  Track every frame up to the end of the highest usable region.
+        let frame_count = usable_regions(memory_map)
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_size = (word_count * core::mem::size_of::<u64>()) as u64;
+
+        //  Place the bitmap at the start of the first region that can hold it.
+        let bitmap_region = usable_regions(memory_map)
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size)
+            .expect("no usable region is large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr: *mut u64 =
+            (physical_memory_offset + bitmap_start).as_mut_ptr();
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_ptr, word_count);
+        bitmap.fill(u64::MAX);
+
+        let first_bitmap_frame = (bitmap_start / Size4KiB::SIZE) as usize;
+        let bitmap_frame_count = bitmap_size.div_ceil(Size4KiB::SIZE) as usize;
+        let bitmap_frames =
+            first_bitmap_frame..first_bitmap_frame + bitmap_frame_count;
+        let mut allocator = BitmapFrameAllocator
+        {
+            memory_map,
+            bitmap,
+            bitmap_frames,
+            total_frames: 0,
+            free_frames: 0,
+            next: 0,
+        };
+
+        //  Release the usable frames.
+        for region in usable_regions(memory_map)
+        {
+            let frames = region.range.start_frame_number
+                ..region.range.end_frame_number;
+            for index in frames
+            {
+                allocator.set_free(index as usize);
+                allocator.total_frames += 1;
+                allocator.free_frames += 1;
+            }
+        }
+
+        //  Reserve the frames occupied by the bitmap itself.
+        for index in allocator.bitmap_frames.clone()
+        {
+            allocator.set_used(index);
+            allocator.free_frames -= 1;
+        }
+
+        allocator
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of usable frames managed by the allocator.
+    //--------------------------------------------------------------------------
+    pub fn total_frames( &self ) -> usize
+    {
+        self.total_frames
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of frames that can still be allocated.
+    //--------------------------------------------------------------------------
+    pub fn free_frames( &self ) -> usize
+    {
+        self.free_frames
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of allocated frames, including the bitmap frames.
+    //--------------------------------------------------------------------------
+    pub fn used_frames( &self ) -> usize
+    {
+        self.total_frames - self.free_frames
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the frame with the given index is usable RAM that the
+    //  allocator hands out, i.e. not the bitmap itself.
+    //--------------------------------------------------------------------------
+    fn is_managed( &self, index: usize ) -> bool
+    {
+        let frame_number = index as u64;
+        !self.bitmap_frames.contains(&index)
+            && usable_regions(self.memory_map).any(|r|
+                r.range.start_frame_number <= frame_number
+                    && frame_number < r.range.end_frame_number
+            )
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the frame with the given index is marked as used.
+    //--------------------------------------------------------------------------
+    fn is_used( &self, index: usize ) -> bool
+    {
+        let word = self.bitmap[index / BITS_PER_WORD];
+        word & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    //--------------------------------------------------------------------------
+    //  Marks the frame with the given index as used.
+    //--------------------------------------------------------------------------
+    fn set_used( &mut self, index: usize )
+    {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    //--------------------------------------------------------------------------
+    //  Marks the frame with the given index as free.
+    //--------------------------------------------------------------------------
+    fn set_free( &mut self, index: usize )
+    {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator
+{
+    //--------------------------------------------------------------------------
+    //  allocate_frame
+    //--------------------------------------------------------------------------
+    fn allocate_frame( &mut self ) -> Option<PhysFrame>
+    {
+        if self.free_frames == 0
+        {
+            return None;
+        }
+
+        let word_count = self.bitmap.len();
+        for offset in 0..word_count
+        {
+            let word_index = (self.next + offset) % word_count;
+            let word = self.bitmap[word_index];
+            if word == u64::MAX
+            {
+                continue;
+            }
+
+            let index = word_index * BITS_PER_WORD
+                + (!word).trailing_zeros() as usize;
+            self.set_used(index);
+            self.free_frames -= 1;
+            self.next = word_index;
+
+            let addr = PhysAddr::new(index as u64 * Size4KiB::SIZE);
+            return Some(PhysFrame::containing_address(addr));
+        }
+
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator
+{
+    //--------------------------------------------------------------------------
+    //  deallocate_frame
+    //
+    //  Panics if the frame is not managed by the allocator or is already free.
+    //--------------------------------------------------------------------------
+    unsafe fn deallocate_frame( &mut self, frame: PhysFrame )
+    {
+        let index = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+        assert!
+        (
+            self.is_managed(index),
+            "deallocating a frame that is not managed by the allocator: {:?}",
+            frame
+        );
+        assert!
+        (
+            self.is_used(index),
+            "deallocating a frame that is not allocated: {:?}",
+            frame
+        );
+
+        self.set_free(index);
+        self.free_frames += 1;
+        self.next = self.next.min(index / BITS_PER_WORD);
+    }
+}
@@ -0,0 +1,243 @@
+/*
+
+    Boot memory
+
+    ----------------------------------------------------------------------------
+
+    The bootloader describes physical memory with a memory map of typed
+    regions. The frame allocator starts out with the `Usable` regions only.
+    Some of the other regions are only needed until the kernel has taken
+    over:
+
+    | Region type       | Holds                           | After early boot |
+    | ----------------- | ------------------------------- | ---------------- |
+    | `Bootloader`      | Bootloader code, data and stack | Reclaimed        |
+    | `BootInfo`        | Boot info and the memory map    | Kept, referenced |
+    | `PageTable`       | The active page tables          | Kept, in use     |
+    | `KernelStack`     | The boot stack                  | Kept, in use     |
+    | `Kernel`          | The kernel ELF file and image   | Kept, in use     |
+    | `AcpiReclaimable` | ACPI tables                     | Kept, unparsed   |
+
+    `reclaim_boot_memory` gives the reclaimable regions to the frame
+    allocator. It must only run once the kernel runs on its own GDT and IDT,
+    since the bootloader's tables live in its region (see
+    `finish_early_boot`).
+
+    `BootMemorySummary` adds up the bytes of every region type, to show where
+    the RAM goes.
+
+*/
+
+use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+use core::fmt;
+use core::sync::atomic::{ AtomicBool, Ordering };
+use x86_64::PhysAddr;
+
+//  The region types that are counted separately, in the order they are
+//  printed. Any other type is counted as unknown.
+const REGION_TYPES: [MemoryRegionType; 14] = [
+    MemoryRegionType::Usable,
+    MemoryRegionType::InUse,
+    MemoryRegionType::Reserved,
+    MemoryRegionType::AcpiReclaimable,
+    MemoryRegionType::AcpiNvs,
+    MemoryRegionType::BadMemory,
+    MemoryRegionType::Kernel,
+    MemoryRegionType::KernelStack,
+    MemoryRegionType::PageTable,
+    MemoryRegionType::Bootloader,
+    MemoryRegionType::FrameZero,
+    MemoryRegionType::Empty,
+    MemoryRegionType::BootInfo,
+    MemoryRegionType::Package,
+];
+
+//  The region types that are no longer used after early boot.
+const RECLAIMABLE_TYPES: [MemoryRegionType; 1] = [
+    MemoryRegionType::Bootloader,
+];
+
+static RECLAIMED: AtomicBool = AtomicBool::new(false);
+
+//------------------------------------------------------------------------------
+//  The number and total size of the regions of one type.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegionTotal
+{
+    pub regions: usize,
+    pub bytes: u64,
+}
+
+//------------------------------------------------------------------------------
+//  The boot memory map added up by region type.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootMemorySummary
+{
+    totals: [RegionTotal; REGION_TYPES.len()],
+    unknown: RegionTotal,
+    reclaimable: RegionTotal,
+}
+
+impl BootMemorySummary
+{
+    //--------------------------------------------------------------------------
+    //  Adds up the regions of the given memory map.
+    //--------------------------------------------------------------------------
+    pub fn new( memory_map: &MemoryMap ) -> Self
+    {
+        let mut summary = BootMemorySummary
+        {
+            totals: [RegionTotal::default(); REGION_TYPES.len()],
+            unknown: RegionTotal::default(),
+            reclaimable: RegionTotal::default(),
+        };
+
+        for region in memory_map.iter()
+        {
+            let bytes = region.range.end_addr() - region.range.start_addr();
+            let total = match REGION_TYPES.iter()
+                .position(|&t| t == region.region_type)
+            {
+                Some(i) => &mut summary.totals[i],
+                None => &mut summary.unknown,
+            };
+            total.regions += 1;
+            total.bytes += bytes;
+
+            if RECLAIMABLE_TYPES.contains(&region.region_type)
+            {
+                summary.reclaimable.regions += 1;
+                summary.reclaimable.bytes += bytes;
+            }
+        }
+        summary
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the total of the given region type.
+    //--------------------------------------------------------------------------
+    pub fn total( &self, region_type: MemoryRegionType ) -> RegionTotal
+    {
+        REGION_TYPES.iter()
+            .position(|&t| t == region_type)
+            .map(|i| self.totals[i])
+            .unwrap_or(self.unknown)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the total of the region types that are reclaimed after early
+    //  boot.
+    //--------------------------------------------------------------------------
+    pub fn reclaimable( &self ) -> RegionTotal
+    {
+        self.reclaimable
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the size of all regions in bytes.
+    //--------------------------------------------------------------------------
+    pub fn total_bytes( &self ) -> u64
+    {
+        self.totals.iter().map(|t| t.bytes).sum::<u64>() + self.unknown.bytes
+    }
+}
+
+impl fmt::Display for BootMemorySummary
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        writeln!(f, "boot memory map:")?;
+        let totals = REGION_TYPES.iter()
+            .map(|t| region_type_name(*t))
+            .zip(self.totals.iter())
+            .chain(core::iter::once(("unknown", &self.unknown)));
+        for (name, total) in totals.filter(|(_, t)| t.regions != 0)
+        {
+            writeln!
+            (
+                f,
+                "  {:<17} {:>12} bytes {:>8} KiB  {} regions",
+                name,
+                total.bytes,
+                total.bytes / 1024,
+                total.regions
+            )?;
+        }
+        writeln!
+        (
+            f,
+            "  {:<17} {:>12} bytes {:>8} KiB",
+            "total",
+            self.total_bytes(),
+            self.total_bytes() / 1024
+        )?;
+        writeln!
+        (
+            f,
+            "  {:<17} {:>12} bytes {:>8} KiB",
+            "reclaimable",
+            self.reclaimable.bytes,
+            self.reclaimable.bytes / 1024
+        )
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Gives the regions that are no longer used after early boot to the frame
+//  allocator, and returns the number of bytes added. Does nothing if they
+//  have already been reclaimed.
+//
+//  This function is unsafe because the caller must guarantee that the
+//  memory map is the one passed by the bootloader, and that nothing the
+//  bootloader set up in its own memory (GDT, IDT, stack) is used anymore.
+//------------------------------------------------------------------------------
+pub unsafe fn reclaim_boot_memory( memory_map: &MemoryMap ) -> u64
+{
+    if RECLAIMED.swap(true, Ordering::AcqRel)
+    {
+        return 0;
+    }
+
+    super::with_kernel_memory(|memory|
+    {
+        let mut frames = 0;
+        for region in memory_map.iter()
+            .filter(|r| RECLAIMABLE_TYPES.contains(&r.region_type))
+        {
+            frames += memory.frame_allocator.add_region
+            (
+                PhysAddr::new(region.range.start_addr()),
+                PhysAddr::new(region.range.end_addr())
+            );
+        }
+        frames as u64 * 4096
+    })
+    .expect("kernel memory is not installed")
+}
+
+//------------------------------------------------------------------------------
+//  Returns the name of a region type.
+//------------------------------------------------------------------------------
+fn region_type_name( region_type: MemoryRegionType ) -> &'static str
+{
+    match region_type
+    {
+        MemoryRegionType::Usable => "usable",
+        MemoryRegionType::InUse => "in use",
+        MemoryRegionType::Reserved => "reserved",
+        MemoryRegionType::AcpiReclaimable => "acpi reclaimable",
+        MemoryRegionType::AcpiNvs => "acpi nvs",
+        MemoryRegionType::BadMemory => "bad memory",
+        MemoryRegionType::Kernel => "kernel",
+        MemoryRegionType::KernelStack => "kernel stack",
+        MemoryRegionType::PageTable => "page table",
+        MemoryRegionType::Bootloader => "bootloader",
+        MemoryRegionType::FrameZero => "frame zero",
+        MemoryRegionType::Empty => "empty",
+        MemoryRegionType::BootInfo => "boot info",
+        MemoryRegionType::Package => "package",
+        _ => "unknown",
+    }
+}
@@ -0,0 +1,542 @@
+/*
+
+    Buddy frame allocator
+
+    ----------------------------------------------------------------------------
+
+    The buddy allocator manages physical memory in blocks of `2^order`
+    contiguous frames. A block of order `n` always starts at a frame number
+    that is a multiple of `2^n`, so order 9 blocks are 2 MiB aligned and
+    order 18 blocks are 1 GiB aligned.
+
+    | Order | Frames | Size    |
+    | ----- | ------ | ------- |
+    | 0     | 1      | 4 KiB   |
+    | 1     | 2      | 8 KiB   |
+    | ...   | ...    | ...     |
+    | 9     | 512    | 2 MiB   |
+    | ...   | ...    | ...     |
+    | 18    | 262144 | 1 GiB   |
+
+    To allocate a block, the smallest free block that is large enough is
+    taken and split in halves until it has the requested order. The unused
+    halves ("buddies") are put back on the free lists. When a block is freed,
+    it is merged with its buddy as long as the buddy is free too, so large
+    contiguous blocks are rebuilt over time.
+
+    The free lists are intrusive: each free block stores the links to its
+    neighbours in its first bytes, accessed through the physical memory
+    mapping. One byte per frame records the order of the block starting at
+    that frame and whether it is allocated. That is how a buddy is found to be
+    free, and how `deallocate` checks that it gets an allocated block of the
+    order it was allocated with.
+
+    Frames can be shared, e.g. by address spaces that map the same frame
+    copy-on-write. Two more bytes per frame count the references to each
+    allocated block: `allocate` sets the count to 1, `add_reference` adds one,
+    and `release` drops one and frees the block when none is left.
+
+*/
+
+use super::usable_regions;
+
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    PageSize,
+    PhysFrame,
+    Size4KiB,
+};
+
+//  The largest block order, a 1 GiB block.
+pub const MAX_ORDER: usize = 18;
+
+//  Marks a frame that does not start a block, e.g. the upper half of two
+//  merged buddies.
+const NO_BLOCK: u8 = 0x7f;
+
+//  Set in the order of an allocated block.
+const ALLOCATED: u8 = 0x80;
+
+//  Terminates a free list.
+const NONE: usize = usize::MAX;
+
+//  The bytes of metadata kept for every frame: the order and the reference
+//  count.
+const METADATA_SIZE: u64 = 3;
+
+//------------------------------------------------------------------------------
+//  Links stored at the start of every free block.
+//------------------------------------------------------------------------------
+#[repr(C)]
+struct FreeBlock
+{
+    next: usize,
+    prev: usize,
+}
+
+//------------------------------------------------------------------------------
+//  Returns the smallest order whose blocks can hold `size` bytes.
+//------------------------------------------------------------------------------
+pub fn order_for_size( size: u64 ) -> usize
+{
+    let frames = size.div_ceil(Size4KiB::SIZE).max(1);
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+//------------------------------------------------------------------------------
+//  A FrameAllocator that serves power-of-two blocks of contiguous frames.
+//------------------------------------------------------------------------------
+pub struct BuddyFrameAllocator
+{
+    physical_memory_offset: VirtAddr,
+    orders: &'static mut [u8],
+    reference_counts: &'static mut [u16],
+    free_lists: [usize; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator
+{
+    //--------------------------------------------------------------------------
+    //  Create a BuddyFrameAllocator from the passed memory map.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  passed memory map is valid, that all frames marked as `USABLE` in it
+    //  are really unused, and that all physical memory is mapped at the
+    //  passed `physical_memory_offset`. It should only be called once, since
+    //  the free lists are stored in the usable memory itself.
+    //--------------------------------------------------------------------------
+    pub unsafe fn init
+    (
+        memory_map: &'static MemoryMap,
+        physical_memory_offset: VirtAddr,
+    ) -> BuddyFrameAllocator
+    {
+        //  Track every frame up to the end of the highest usable region.
+        let frame_count = usable_regions(memory_map)
+            .map(|r| r.range.end_frame_number)
+            .max()
+            .unwrap_or(0) as usize;
+        let metadata_size = frame_count as u64 * METADATA_SIZE;
+
+        //  Place the reference counts and the order map at the start of the
+        //  first region that can hold them.
+        let metadata_region = usable_regions(memory_map)
+            .find(|r|
+                r.range.end_addr() - r.range.start_addr() >= metadata_size
+            )
+            .expect("no usable region is large enough for the buddy metadata");
+        let metadata_start = metadata_region.range.start_addr();
+        let counts_ptr: *mut u16 =
+            (physical_memory_offset + metadata_start).as_mut_ptr();
+        let reference_counts =
+            core::slice::from_raw_parts_mut(counts_ptr, frame_count);
+        reference_counts.fill(0);
+        let orders_ptr = counts_ptr.add(frame_count) as *mut u8;
+        let orders = core::slice::from_raw_parts_mut(orders_ptr, frame_count);
+        orders.fill(NO_BLOCK);
+
+        let mut allocator = BuddyFrameAllocator
+        {
+            physical_memory_offset,
+            orders,
+            reference_counts,
+            free_lists: [NONE; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            total_frames: 0,
+            free_frames: 0,
+        };
+
+        //  Release the usable frames, except the ones holding the metadata.
+        for region in usable_regions(memory_map)
+        {
+            let mut start = region.range.start_frame_number as usize;
+            let end = region.range.end_frame_number as usize;
+            allocator.total_frames += end - start;
+
+            if region.range.start_addr() == metadata_start
+            {
+                start += metadata_size.div_ceil(Size4KiB::SIZE) as usize;
+            }
+            allocator.add_range(start, end);
+        }
+
+        allocator
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of usable frames managed by the allocator.
+    //--------------------------------------------------------------------------
+    pub fn total_frames( &self ) -> usize
+    {
+        self.total_frames
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of frames that can still be allocated.
+    //--------------------------------------------------------------------------
+    pub fn free_frames( &self ) -> usize
+    {
+        self.free_frames
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of allocated frames, including the metadata.
+    //--------------------------------------------------------------------------
+    pub fn used_frames( &self ) -> usize
+    {
+        self.total_frames - self.free_frames
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of free blocks of the given order.
+    //--------------------------------------------------------------------------
+    pub fn free_blocks( &self, order: usize ) -> usize
+    {
+        self.free_blocks[order]
+    }
+
+    //--------------------------------------------------------------------------
+    //  Allocates `2^order` contiguous frames and returns the physical address
+    //  of the first one. The address is aligned to the size of the block.
+    //--------------------------------------------------------------------------
+    pub fn allocate( &mut self, order: usize ) -> Option<PhysAddr>
+    {
+        if order > MAX_ORDER
+        {
+            return None;
+        }
+
+        let current = (order..=MAX_ORDER)
+            .find(|&o| self.free_lists[o] != NONE)?;
+        let index = self.free_lists[current];
+        Some(self.take(index, current, order))
+    }
+
+    //--------------------------------------------------------------------------
+    //  Allocates `2^order` contiguous frames that end at or below `limit`,
+    //  e.g. for devices that can only address the low 4 GiB.
+    //
+    //  The free lists are searched for a block below the limit, which takes
+    //  time linear in the number of free blocks.
+    //--------------------------------------------------------------------------
+    pub fn allocate_below( &mut self, order: usize, limit: PhysAddr )
+        -> Option<PhysAddr>
+    {
+        if order > MAX_ORDER
+        {
+            return None;
+        }
+
+        let limit = (limit.as_u64() / Size4KiB::SIZE) as usize;
+        for current in order..=MAX_ORDER
+        {
+            //  The block is split from its start, so only the first
+            //  `2^order` frames need to be below the limit.
+            let mut index = self.free_lists[current];
+            while index != NONE
+            {
+                if index + (1 << order) <= limit
+                {
+                    return Some(self.take(index, current, order));
+                }
+                index = unsafe { (*self.block(index)).next };
+            }
+        }
+        None
+    }
+
+    //--------------------------------------------------------------------------
+    //  Frees a block previously returned by `allocate` with the same order.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  block is no longer in use. Panics if the block is not aligned to its
+    //  order, is not allocated, was allocated with another order, or has
+    //  more than one reference; shared blocks are freed with `release`.
+    //--------------------------------------------------------------------------
+    pub unsafe fn deallocate( &mut self, addr: PhysAddr, order: usize )
+    {
+        let index = (addr.as_u64() / Size4KiB::SIZE) as usize;
+        assert!
+        (
+            order <= MAX_ORDER
+                && index.is_multiple_of(1 << order)
+                && index + (1 << order) <= self.orders.len(),
+            "deallocating an invalid block: {:?} (order {})",
+            addr,
+            order
+        );
+        let marker = self.orders[index];
+        assert!
+        (
+            marker & ALLOCATED != 0,
+            "deallocating a block that is not allocated: {:?}",
+            addr
+        );
+        assert!
+        (
+            marker & !ALLOCATED == order as u8,
+            "deallocating a block of order {} as order {}: {:?}",
+            marker & !ALLOCATED,
+            order,
+            addr
+        );
+        assert!
+        (
+            self.reference_counts[index] == 1,
+            "deallocating a shared block, which must be released: {:?}",
+            addr
+        );
+
+        self.free_frames += 1 << order;
+        self.orders[index] = NO_BLOCK;
+        self.reference_counts[index] = 0;
+        self.insert(index, order);
+    }
+
+    //--------------------------------------------------------------------------
+    //  Hands the frames in `start..end` to the allocator, e.g. memory that was
+    //  reserved during boot and is no longer used. Frames beyond the highest
+    //  usable region are ignored, since the allocator does not track them.
+    //
+    //  Returns the number of frames added.
+    //
+    //  This function is unsafe because the caller must guarantee that the
+    //  range is unused and not managed by the allocator already.
+    //--------------------------------------------------------------------------
+    pub unsafe fn add_region( &mut self, start: PhysAddr, end: PhysAddr )
+        -> usize
+    {
+        let start = start.align_up(Size4KiB::SIZE).as_u64() / Size4KiB::SIZE;
+        let end = (end.align_down(Size4KiB::SIZE).as_u64() / Size4KiB::SIZE)
+            .min(self.orders.len() as u64);
+        if start >= end
+        {
+            return 0;
+        }
+
+        let frames = (end - start) as usize;
+        self.total_frames += frames;
+        self.add_range(start as usize, end as usize);
+        frames
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the number of references to the block starting at the given
+    //  frame, or 0 if it is not allocated.
+    //--------------------------------------------------------------------------
+    pub fn reference_count( &self, frame: PhysFrame ) -> usize
+    {
+        let index = self.frame_index(frame);
+        self.reference_counts[index] as usize
+    }
+
+    //--------------------------------------------------------------------------
+    //  Adds a reference to the allocated block starting at the given frame.
+    //
+    //  Panics if the block is not allocated or has too many references.
+    //--------------------------------------------------------------------------
+    pub fn add_reference( &mut self, frame: PhysFrame )
+    {
+        let index = self.frame_index(frame);
+        let count = &mut self.reference_counts[index];
+        assert!
+        (
+            *count != 0,
+            "adding a reference to a free frame: {:?}",
+            frame
+        );
+        *count = count.checked_add(1).expect("too many frame references");
+    }
+
+    //--------------------------------------------------------------------------
+    //  Drops a reference to the block of the given order starting at
+    //  `frame`, and frees the block if it was the last one.
+    //
+    //  Returns whether the block was freed.
+    //
+    //  This function is unsafe because the caller must guarantee that it
+    //  no longer uses the block through this reference.
+    //--------------------------------------------------------------------------
+    pub unsafe fn release( &mut self, frame: PhysFrame, order: usize ) -> bool
+    {
+        let index = self.frame_index(frame);
+        match self.reference_counts[index]
+        {
+            0 => panic!("releasing a free frame: {:?}", frame),
+            1 =>
+            {
+                self.deallocate(frame.start_address(), order);
+                true
+            },
+            _ =>
+            {
+                self.reference_counts[index] -= 1;
+                false
+            },
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the frame number of the given frame.
+    //
+    //  Panics if the frame is not managed by the allocator.
+    //--------------------------------------------------------------------------
+    fn frame_index( &self, frame: PhysFrame ) -> usize
+    {
+        let index = (frame.start_address().as_u64() / Size4KiB::SIZE) as usize;
+        assert!
+        (
+            index < self.reference_counts.len(),
+            "frame is not managed by the allocator: {:?}",
+            frame
+        );
+        index
+    }
+
+    //--------------------------------------------------------------------------
+    //  Takes the free block of order `current` at the given frame off the free
+    //  lists, and splits it down to the given order.
+    //--------------------------------------------------------------------------
+    fn take( &mut self, index: usize, mut current: usize, order: usize )
+        -> PhysAddr
+    {
+        self.remove(index, current);
+
+        //  Split the block, giving the upper halves back to the free lists.
+        while current > order
+        {
+            current -= 1;
+            self.push(index + (1 << current), current);
+        }
+
+        self.free_frames -= 1 << order;
+        self.orders[index] = ALLOCATED | order as u8;
+        self.reference_counts[index] = 1;
+        PhysAddr::new(index as u64 * Size4KiB::SIZE)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Releases the frames in `start..end` (frame numbers) into the free
+    //  lists as the largest aligned blocks that fit.
+    //--------------------------------------------------------------------------
+    fn add_range( &mut self, mut start: usize, end: usize )
+    {
+        while start < end
+        {
+            let mut order = MAX_ORDER;
+            while !start.is_multiple_of(1 << order) || start + (1 << order) > end
+            {
+                order -= 1;
+            }
+
+            self.insert(start, order);
+            self.free_frames += 1 << order;
+            start += 1 << order;
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Puts a block on the free lists, merging it with its free buddies.
+    //--------------------------------------------------------------------------
+    fn insert( &mut self, mut index: usize, mut order: usize )
+    {
+        while order < MAX_ORDER
+        {
+            let buddy = index ^ (1 << order);
+            if buddy >= self.orders.len() || self.orders[buddy] != order as u8
+            {
+                break;
+            }
+
+            self.remove(buddy, order);
+            index = index.min(buddy);
+            order += 1;
+        }
+
+        self.push(index, order);
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns a pointer to the links of the free block at the given frame.
+    //--------------------------------------------------------------------------
+    fn block( &self, index: usize ) -> *mut FreeBlock
+    {
+        let addr = index as u64 * Size4KiB::SIZE;
+        (self.physical_memory_offset + addr).as_mut_ptr()
+    }
+
+    //--------------------------------------------------------------------------
+    //  Pushes a block to the front of the free list of its order.
+    //--------------------------------------------------------------------------
+    fn push( &mut self, index: usize, order: usize )
+    {
+        let head = self.free_lists[order];
+        unsafe
+        {
+            self.block(index).write(FreeBlock { next: head, prev: NONE });
+            if head != NONE
+            {
+                (*self.block(head)).prev = index;
+            }
+        }
+
+        self.free_lists[order] = index;
+        self.free_blocks[order] += 1;
+        self.orders[index] = order as u8;
+    }
+
+    //--------------------------------------------------------------------------
+    //  Unlinks a block from the free list of its order.
+    //--------------------------------------------------------------------------
+    fn remove( &mut self, index: usize, order: usize )
+    {
+        unsafe
+        {
+            let FreeBlock { next, prev } = self.block(index).read();
+            if prev == NONE
+            {
+                self.free_lists[order] = next;
+            }
+            else
+            {
+                (*self.block(prev)).next = next;
+            }
+            if next != NONE
+            {
+                (*self.block(next)).prev = prev;
+            }
+        }
+
+        self.free_blocks[order] -= 1;
+        self.orders[index] = NO_BLOCK;
+    }
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for BuddyFrameAllocator
+{
+    //--------------------------------------------------------------------------
+    //  allocate_frame
+    //--------------------------------------------------------------------------
+    fn allocate_frame( &mut self ) -> Option<PhysFrame<S>>
+    {
+        let addr = self.allocate(order_for_size(S::SIZE))?;
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BuddyFrameAllocator
+{
+    //--------------------------------------------------------------------------
+    //  deallocate_frame
+    //--------------------------------------------------------------------------
+    unsafe fn deallocate_frame( &mut self, frame: PhysFrame<S> )
+    {
+        self.deallocate(frame.start_address(), order_for_size(S::SIZE));
+    }
+}
@@ -0,0 +1,157 @@
+/*
+
+    Copy-on-write
+
+    ----------------------------------------------------------------------------
+
+    Forking an address space does not copy its pages. Both spaces map the
+    same frames instead, and each writable page is made read-only in both and
+    marked with `COPY_ON_WRITE`, a spare bit of the entry. The frame allocator
+    counts the references to every shared frame.
+
+      parent ---+                          parent ---> copy of A (RW)
+                |--> frame A (RO, COW)
+      child ----+                          child ----> frame A (RO, COW)
+
+                              write in parent
+
+    The first write to such a page raises a page fault with a protection
+    violation. `handle_cow_fault` then gives the faulting space its own
+    writable frame:
+
+    | References to the frame | Action                                   |
+    | ----------------------- | ---------------------------------------- |
+    | More than one           | Copy it to a new frame and map the copy  |
+    | One                     | Take it over: make the entry writable    |
+
+    In the example above, the next write in the child takes frame A over.
+
+    Read-only pages are shared as well, but never marked, since they are not
+    written to.
+
+*/
+
+use super::{ KERNEL_MEMORY, PageFaultError };
+
+use x86_64::VirtAddr;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameAllocator,
+    PageSize,
+    PageTable,
+    PageTableFlags,
+    PhysFrame,
+    Size4KiB,
+};
+use x86_64::structures::paging::page_table::PageTableEntry;
+
+//  Marks a shared page that is copied on the first write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_10;
+
+//------------------------------------------------------------------------------
+//  Resolves a write to a copy-on-write page of the active address space at
+//  `addr`, by copying the shared frame or taking it over.
+//
+//  Called from the page fault handler, so locks are only tried.
+//------------------------------------------------------------------------------
+pub fn handle_cow_fault
+(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), PageFaultError>
+{
+    let mut guard = KERNEL_MEMORY.try_lock().ok_or(PageFaultError::Busy)?;
+    let memory = guard.as_mut().ok_or(PageFaultError::Busy)?;
+    let physical_memory_offset = memory.mapper.phys_offset();
+
+    //  The fault happened in the active address space, which may not be the
+    //  kernel's. The kernel memory is locked, so nothing else changes it.
+    let entry = unsafe { level_1_entry(physical_memory_offset, addr) }
+        .ok_or(PageFaultError::ProtectionViolation)?;
+    let flags = entry.flags();
+    if !flags.contains(COPY_ON_WRITE)
+    {
+        return Err(PageFaultError::ProtectionViolation);
+    }
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        && !flags.contains(PageTableFlags::USER_ACCESSIBLE)
+    {
+        return Err(PageFaultError::AccessDenied);
+    }
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    let new_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    if memory.frame_allocator.reference_count(frame) == 1
+    {
+        //  No other space maps the frame anymore, so it is written in place.
+        entry.set_flags(new_flags);
+        tlb::flush(addr);
+        return Ok(());
+    }
+
+    let copy: PhysFrame = memory.frame_allocator
+        .allocate_frame()
+        .ok_or(PageFaultError::OutOfMemory)?;
+    unsafe
+    {
+        let src: *const u8 =
+            (physical_memory_offset + frame.start_address().as_u64())
+            .as_ptr();
+        let dst: *mut u8 =
+            (physical_memory_offset + copy.start_address().as_u64())
+            .as_mut_ptr();
+        dst.copy_from_nonoverlapping(src, Size4KiB::SIZE as usize);
+    }
+    entry.set_frame(copy, new_flags);
+    tlb::flush(addr);
+
+    //  Another space still maps the frame, so this only drops a reference.
+    unsafe { memory.frame_allocator.release(frame, 0) };
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+//  Returns the level 1 entry that maps `addr` in the active page tables, if
+//  it is mapped with a 4 KiB page.
+//
+//  This function is unsafe because the caller must guarantee that all
+//  physical memory is mapped at `physical_memory_offset` and that no one else
+//  modifies the page tables while the entry is in use.
+//------------------------------------------------------------------------------
+unsafe fn level_1_entry
+(
+    physical_memory_offset: VirtAddr,
+    addr: VirtAddr,
+) -> Option<&'static mut PageTableEntry>
+{
+    let (level_4_frame, _) = Cr3::read();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    let mut table_addr = level_4_frame.start_address();
+    for (i, &index) in indexes.iter().enumerate()
+    {
+        let table_ptr: *mut PageTable =
+            (physical_memory_offset + table_addr.as_u64()).as_mut_ptr();
+        let table = &mut *table_ptr;
+        let entry = &mut table[index];
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT)
+            || flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            return None;
+        }
+        if i == indexes.len() - 1
+        {
+            return Some(entry);
+        }
+        table_addr = entry.addr();
+    }
+    None
+}
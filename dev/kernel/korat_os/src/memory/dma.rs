@@ -0,0 +1,351 @@
+/*
+
+    DMA buffers
+
+    ----------------------------------------------------------------------------
+
+    Devices that use direct memory access (DMA) read and write physical memory
+    on their own, so a driver has to give them the physical address of its
+    buffers. A DMA buffer must be:
+
+    - physically contiguous, since the device does not use the page tables,
+    - below the highest address the device can reach (e.g. 4 GiB for 32-bit
+      devices, 16 MiB for ISA DMA),
+    - mapped with the caching the device needs.
+
+    `DmaRegion` allocates a block of contiguous frames below a limit from the
+    buddy allocator, maps it into a free range of kernel virtual memory and
+    zeroes it. It exposes both addresses, and frees the mapping and the frames
+    when it is dropped.
+
+      physical                      virtual
+      +-----------------+           +-----------------+
+      | frames < limit  | <-------- | DmaRegion       |
+      +-----------------+           +-----------------+
+        ^
+        device
+
+    Blocks are a power of two frames in size and aligned to their size, so a
+    region is always page and cache line aligned.
+
+    | Caching                  | Use                                         |
+    | ------------------------ | ------------------------------------------- |
+    | `DmaCaching::Uncached`   | Devices that do not snoop the CPU caches     |
+    | `DmaCaching::WriteBack`  | Cache coherent devices (most PCI devices)    |
+
+    An uncached region is zeroed through its own mapping after its cache
+    lines have been flushed. The frames also stay mapped write-back in the
+    physical memory map, so the kernel must only access the region through
+    `DmaRegion`, or the alias brings cached lines back.
+
+    `DmaBuffer<T>` is a typed `DmaRegion` holding a single `T`, e.g. a
+    descriptor ring. Fields written by the device should be read with
+    volatile reads.
+
+*/
+
+use super::{ MapError, MappingSize, VmaPurpose, order_for_size };
+
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{ Deref, DerefMut };
+use core::slice;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{ PageSize, PageTableFlags, Size4KiB };
+
+//  Common address limits.
+pub const DMA_LIMIT_16MIB: PhysAddr = PhysAddr::new_truncate(0x100_0000);
+pub const DMA_LIMIT_4GIB: PhysAddr = PhysAddr::new_truncate(0x1_0000_0000);
+
+//------------------------------------------------------------------------------
+//  How the CPU caches a DMA buffer.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaCaching
+{
+    Uncached,
+    WriteBack,
+}
+
+impl DmaCaching
+{
+    //--------------------------------------------------------------------------
+    //  Returns the page table flags for the caching mode.
+    //--------------------------------------------------------------------------
+    fn flags( self ) -> PageTableFlags
+    {
+        let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        match self
+        {
+            DmaCaching::Uncached => flags | PageTableFlags::NO_CACHE,
+            DmaCaching::WriteBack => flags,
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Physically contiguous memory mapped into kernel virtual memory.
+//------------------------------------------------------------------------------
+#[derive(Debug)]
+pub struct DmaRegion
+{
+    phys_addr: PhysAddr,
+    virt_addr: VirtAddr,
+    len: usize,
+    order: usize,
+}
+
+impl DmaRegion
+{
+    //--------------------------------------------------------------------------
+    //  Allocates at least `len` bytes of zeroed contiguous memory that ends at
+    //  or below `limit`, and maps it with the given caching.
+    //--------------------------------------------------------------------------
+    pub fn new
+    (
+        len: usize,
+        limit: PhysAddr,
+        caching: DmaCaching,
+    ) -> Result<DmaRegion, MapError>
+    {
+        if len == 0
+        {
+            return Err(MapError::InvalidRange);
+        }
+        let order = order_for_size(len as u64);
+        let size = Size4KiB::SIZE << order;
+
+        let phys_addr = super::with_kernel_memory(|memory|
+            memory.frame_allocator.allocate_below(order, limit)
+        )
+        .ok_or(MapError::NotInstalled)?
+        .ok_or(MapError::FrameAllocationFailed)?;
+
+        let free_frames = ||
+        {
+            super::with_kernel_memory(|memory| unsafe
+            {
+                memory.frame_allocator.deallocate(phys_addr, order)
+            });
+        };
+
+        let flags = caching.flags();
+        let vma = match super::allocate_vma
+        (
+            size,
+            Size4KiB::SIZE,
+            VmaPurpose::Dma,
+            super::supported_flags(flags | PageTableFlags::PRESENT)
+        )
+        {
+            Ok(vma) => vma,
+            Err(err) =>
+            {
+                free_frames();
+                return Err(MapError::from(err));
+            },
+        };
+
+        let map_result = unsafe
+        {
+            super::map_physical_region
+            (
+                vma.start(),
+                phys_addr,
+                size,
+                flags,
+                MappingSize::Size4KiB
+            )
+        };
+        if let Err(err) = map_result
+        {
+            super::free_vma(vma.start());
+            free_frames();
+            return Err(err);
+        }
+
+        //  Zero through the new mapping, so the writes use its memory type.
+        //  Uncached memory must not be left with cache lines of an earlier
+        //  write-back use, which could be written back over the device's data.
+        let start = vma.start().as_mut_ptr::<u8>();
+        unsafe
+        {
+            if caching == DmaCaching::Uncached
+            {
+                flush_cache_lines(start, size as usize);
+            }
+            start.write_bytes(0, size as usize);
+        }
+
+        Ok(DmaRegion
+        {
+            phys_addr,
+            virt_addr: vma.start(),
+            len,
+            order,
+        })
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the physical address of the region, for the device.
+    //--------------------------------------------------------------------------
+    pub fn phys_addr( &self ) -> PhysAddr
+    {
+        self.phys_addr
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the virtual address of the region, for the kernel.
+    //--------------------------------------------------------------------------
+    pub fn virt_addr( &self ) -> VirtAddr
+    {
+        self.virt_addr
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the requested size of the region in bytes.
+    //--------------------------------------------------------------------------
+    pub fn len( &self ) -> usize
+    {
+        self.len
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the region is empty, which it never is.
+    //--------------------------------------------------------------------------
+    pub fn is_empty( &self ) -> bool
+    {
+        self.len == 0
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the contents of the region.
+    //--------------------------------------------------------------------------
+    pub fn as_slice( &self ) -> &[u8]
+    {
+        unsafe { slice::from_raw_parts(self.virt_addr.as_ptr(), self.len) }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the contents of the region for writing.
+    //--------------------------------------------------------------------------
+    pub fn as_mut_slice( &mut self ) -> &mut [u8]
+    {
+        unsafe
+        {
+            slice::from_raw_parts_mut(self.virt_addr.as_mut_ptr(), self.len)
+        }
+    }
+}
+
+impl Drop for DmaRegion
+{
+    fn drop( &mut self )
+    {
+        let size = Size4KiB::SIZE << self.order;
+        unsafe { super::unmap_physical_region(self.virt_addr, size) };
+        super::free_vma(self.virt_addr);
+        super::with_kernel_memory(|memory| unsafe
+        {
+            memory.frame_allocator.deallocate(self.phys_addr, self.order)
+        });
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Writes back and invalidates the cache lines of `start..start + len` in
+//  every cache, whichever mapping they were cached through.
+//------------------------------------------------------------------------------
+unsafe fn flush_cache_lines( start: *mut u8, len: usize )
+{
+    //  CPUID.01H:EBX[15:8] is the size of a line flushed by `clflush`, in
+    //  units of 8 bytes.
+    #[allow(unused_unsafe)]
+    let line_size = (((unsafe { __cpuid(1) }.ebx >> 8) & 0xff) * 8) as usize;
+    let line_size = line_size.max(8);
+
+    for offset in (0..len).step_by(line_size)
+    {
+        asm!("clflush [{}]", in(reg) start.add(offset), options(nostack));
+    }
+    asm!("mfence", options(nostack));
+}
+
+//------------------------------------------------------------------------------
+//  A value of type `T` in physically contiguous memory.
+//------------------------------------------------------------------------------
+#[derive(Debug)]
+pub struct DmaBuffer<T>
+{
+    region: DmaRegion,
+    value: PhantomData<T>,
+}
+
+impl<T> DmaBuffer<T>
+{
+    //--------------------------------------------------------------------------
+    //  Moves `value` into new DMA memory that ends at or below `limit`.
+    //--------------------------------------------------------------------------
+    pub fn new
+    (
+        value: T,
+        limit: PhysAddr,
+        caching: DmaCaching,
+    ) -> Result<DmaBuffer<T>, MapError>
+    {
+        assert!
+        (
+            mem::align_of::<T>() as u64 <= Size4KiB::SIZE,
+            "DMA buffers are only page aligned"
+        );
+
+        let size = mem::size_of::<T>().max(1);
+        let region = DmaRegion::new(size, limit, caching)?;
+        unsafe { region.virt_addr.as_mut_ptr::<T>().write(value) };
+        Ok(DmaBuffer { region, value: PhantomData })
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the physical address of the value, for the device.
+    //--------------------------------------------------------------------------
+    pub fn phys_addr( &self ) -> PhysAddr
+    {
+        self.region.phys_addr()
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the virtual address of the value, for the kernel.
+    //--------------------------------------------------------------------------
+    pub fn virt_addr( &self ) -> VirtAddr
+    {
+        self.region.virt_addr()
+    }
+}
+
+impl<T> Deref for DmaBuffer<T>
+{
+    type Target = T;
+
+    fn deref( &self ) -> &T
+    {
+        unsafe { &*self.region.virt_addr.as_ptr::<T>() }
+    }
+}
+
+impl<T> DerefMut for DmaBuffer<T>
+{
+    fn deref_mut( &mut self ) -> &mut T
+    {
+        unsafe { &mut *self.region.virt_addr.as_mut_ptr::<T>() }
+    }
+}
+
+impl<T> Drop for DmaBuffer<T>
+{
+    fn drop( &mut self )
+    {
+        unsafe { self.region.virt_addr.as_mut_ptr::<T>().drop_in_place() };
+    }
+}
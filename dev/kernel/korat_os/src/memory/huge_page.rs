@@ -0,0 +1,415 @@
+/*
+
+    Huge pages
+
+    ----------------------------------------------------------------------------
+
+    Besides 4 KiB pages, x86_64 can map a whole 2 MiB range with a single
+    level 2 entry, and a whole 1 GiB range with a single level 3 entry, if
+    the CPU supports it (CPUID `0x8000_0001`, EDX bit 26).
+
+    | Page size | Mapped by       | Entries for 1 GiB |
+    | --------- | --------------- | ----------------- |
+    | 4 KiB     | Level 1 entry   | 262144            |
+    | 2 MiB     | Level 2 entry   | 512               |
+    | 1 GiB     | Level 3 entry   | 1                 |
+
+    Larger pages save page table memory and TLB entries, which matters for
+    big regions such as a large heap or a linear framebuffer.
+
+    `map_region` and `map_physical_region` map a range with the largest page
+    size that fits at each position: the virtual (and physical) address must
+    be aligned to the page size and the rest of the range must be at least
+    one page long. Anything else, such as an unaligned head or tail, falls
+    back to smaller pages. `map_region` also falls back when the frame
+    allocator has no free block of the larger size.
+
+*/
+
+use super::{ KernelMemory, MapError, OffsetPageTable };
+
+use core::arch::x86_64::__cpuid;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    Mapper,
+    Page,
+    PageSize,
+    PageTableFlags,
+    PhysFrame,
+    Size1GiB,
+    Size2MiB,
+    Size4KiB,
+    Translate,
+};
+use x86_64::structures::paging::mapper::{ MappedFrame, TranslateResult };
+
+//------------------------------------------------------------------------------
+//  A page size.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MappingSize
+{
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappingSize
+{
+    //--------------------------------------------------------------------------
+    //  Returns the largest page size the CPU supports.
+    //--------------------------------------------------------------------------
+    pub fn largest_supported() -> MappingSize
+    {
+        if huge_pages_1gib_supported()
+        {
+            MappingSize::Size1GiB
+        }
+        else
+        {
+            MappingSize::Size2MiB
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the size of a page in bytes.
+    //--------------------------------------------------------------------------
+    pub fn size( self ) -> u64
+    {
+        match self
+        {
+            MappingSize::Size4KiB => Size4KiB::SIZE,
+            MappingSize::Size2MiB => Size2MiB::SIZE,
+            MappingSize::Size1GiB => Size1GiB::SIZE,
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  The number of pages of each size used to map a region.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegionMapping
+{
+    pub pages_4kib: u64,
+    pub pages_2mib: u64,
+    pub pages_1gib: u64,
+}
+
+impl RegionMapping
+{
+    //--------------------------------------------------------------------------
+    //  Counts a page of the given size.
+    //--------------------------------------------------------------------------
+    fn add( &mut self, size: MappingSize )
+    {
+        match size
+        {
+            MappingSize::Size4KiB => self.pages_4kib += 1,
+            MappingSize::Size2MiB => self.pages_2mib += 1,
+            MappingSize::Size1GiB => self.pages_1gib += 1,
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether the CPU supports 1 GiB pages.
+//------------------------------------------------------------------------------
+#[allow(unused_unsafe)]
+pub fn huge_pages_1gib_supported() -> bool
+{
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended_leaf < 0x8000_0001
+    {
+        return false;
+    }
+
+    let edx = unsafe { __cpuid(0x8000_0001) }.edx;
+    edx & (1 << 26) != 0
+}
+
+//------------------------------------------------------------------------------
+//  Maps `start..start + size` to new zeroed frames, using pages up to
+//  `max_size` where possible. `PRESENT` is added to the flags.
+//
+//  The range must be page aligned and unmapped. On failure, nothing stays
+//  mapped.
+//------------------------------------------------------------------------------
+pub fn map_region
+(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    max_size: MappingSize,
+) -> Result<RegionMapping, MapError>
+{
+    map(start, None, size, flags, max_size)
+}
+
+//------------------------------------------------------------------------------
+//  Maps `start..start + size` to the physical range starting at `phys_addr`,
+//  using pages up to `max_size` where possible. `PRESENT` is added to the
+//  flags.
+//
+//  This function is unsafe because the caller must guarantee that the
+//  physical range is not managed by the frame allocator and that accessing
+//  it with the given flags has no undefined behavior.
+//------------------------------------------------------------------------------
+pub unsafe fn map_physical_region
+(
+    start: VirtAddr,
+    phys_addr: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+    max_size: MappingSize,
+) -> Result<RegionMapping, MapError>
+{
+    map(start, Some(phys_addr), size, flags, max_size)
+}
+
+//------------------------------------------------------------------------------
+//  Unmaps a region mapped with `map_region` and frees its frames.
+//
+//  This function is unsafe because the caller must guarantee that the region
+//  is no longer in use.
+//------------------------------------------------------------------------------
+pub unsafe fn unmap_region( start: VirtAddr, size: u64 )
+{
+    super::with_kernel_memory(|memory| unmap(memory, start, size, true));
+}
+
+//------------------------------------------------------------------------------
+//  Unmaps a region mapped with `map_physical_region`.
+//
+//  This function is unsafe because the caller must guarantee that the region
+//  is no longer in use.
+//------------------------------------------------------------------------------
+pub unsafe fn unmap_physical_region( start: VirtAddr, size: u64 )
+{
+    super::with_kernel_memory(|memory| unmap(memory, start, size, false));
+}
+
+//------------------------------------------------------------------------------
+//  Maps a region page by page, choosing the largest page size that fits.
+//
+//  The region is mapped to the physical range at `phys_start` if given, and
+//  to new zeroed frames otherwise.
+//------------------------------------------------------------------------------
+fn map
+(
+    start: VirtAddr,
+    phys_start: Option<PhysAddr>,
+    size: u64,
+    flags: PageTableFlags,
+    max_size: MappingSize,
+) -> Result<RegionMapping, MapError>
+{
+    let aligned = start.is_aligned(Size4KiB::SIZE)
+        && size.is_multiple_of(Size4KiB::SIZE)
+        && phys_start.is_none_or(|addr| addr.is_aligned(Size4KiB::SIZE));
+    if size == 0 || !aligned || start.as_u64().checked_add(size).is_none()
+    {
+        return Err(MapError::InvalidRange);
+    }
+
+    let flags = super::supported_flags(flags | PageTableFlags::PRESENT);
+    let max_size = max_size.min(MappingSize::largest_supported());
+
+    super::with_kernel_memory(|memory|
+    {
+        let mut mapping = RegionMapping::default();
+        let mut offset = 0;
+        while offset < size
+        {
+            let virt = start + offset;
+            let phys = phys_start.map(|addr| addr + offset);
+            let remaining = size - offset;
+            match map_next(memory, virt, phys, remaining, flags, max_size)
+            {
+                Ok(page_size) =>
+                {
+                    mapping.add(page_size);
+                    offset += page_size.size();
+                },
+                Err(err) =>
+                {
+                    let free = phys_start.is_none();
+                    unsafe { unmap(memory, start, offset, free) };
+                    return Err(err);
+                },
+            }
+        }
+        Ok(mapping)
+    })
+    .unwrap_or(Err(MapError::NotInstalled))
+}
+
+//------------------------------------------------------------------------------
+//  Maps one page at `virt` with the largest size that fits in `remaining`
+//  bytes, and returns its size.
+//------------------------------------------------------------------------------
+fn map_next
+(
+    memory: &mut KernelMemory,
+    virt: VirtAddr,
+    phys: Option<PhysAddr>,
+    remaining: u64,
+    flags: PageTableFlags,
+    max_size: MappingSize,
+) -> Result<MappingSize, MapError>
+{
+    let fits = |size: MappingSize|
+    {
+        size <= max_size
+            && remaining >= size.size()
+            && virt.is_aligned(size.size())
+            && phys.is_none_or(|addr| addr.is_aligned(size.size()))
+    };
+
+    if fits(MappingSize::Size1GiB)
+        && map_page::<Size1GiB>(memory, virt, phys, flags)?
+    {
+        return Ok(MappingSize::Size1GiB);
+    }
+    if fits(MappingSize::Size2MiB)
+        && map_page::<Size2MiB>(memory, virt, phys, flags)?
+    {
+        return Ok(MappingSize::Size2MiB);
+    }
+    if map_page::<Size4KiB>(memory, virt, phys, flags)?
+    {
+        return Ok(MappingSize::Size4KiB);
+    }
+    Err(MapError::FrameAllocationFailed)
+}
+
+//------------------------------------------------------------------------------
+//  Maps a single page of size `S`.
+//
+//  Returns `Ok(false)` if no frame of that size could be allocated, so that
+//  a smaller size can be tried.
+//------------------------------------------------------------------------------
+fn map_page<S: PageSize>
+(
+    memory: &mut KernelMemory,
+    virt: VirtAddr,
+    phys: Option<PhysAddr>,
+    flags: PageTableFlags,
+) -> Result<bool, MapError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let frame = match phys
+    {
+        Some(addr) => PhysFrame::<S>::containing_address(addr),
+        None =>
+        {
+            let frame: PhysFrame<S> =
+                match memory.frame_allocator.allocate_frame()
+                {
+                    Some(frame) => frame,
+                    None => return Ok(false),
+                };
+            let frame_addr = memory.mapper.phys_offset()
+                + frame.start_address().as_u64();
+            unsafe
+            {
+                frame_addr.as_mut_ptr::<u8>().write_bytes(0, S::SIZE as usize);
+            }
+            frame
+        },
+    };
+
+    let page = Page::<S>::containing_address(virt);
+    let map_to_result = unsafe
+    {
+        memory.mapper.map_to
+        (
+            page,
+            frame,
+            flags,
+            &mut memory.frame_allocator
+        )
+    };
+    match map_to_result
+    {
+        Ok(flush) =>
+        {
+            flush.flush();
+            Ok(true)
+        },
+        Err(err) =>
+        {
+            if phys.is_none()
+            {
+                unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            }
+            Err(MapError::from(err))
+        },
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Unmaps the pages in `start..start + size`, whatever their size, and frees
+//  their frames if `free` is set.
+//------------------------------------------------------------------------------
+unsafe fn unmap
+(
+    memory: &mut KernelMemory,
+    start: VirtAddr,
+    size: u64,
+    free: bool,
+)
+{
+    let mut offset = 0;
+    while offset < size
+    {
+        let virt = start + offset;
+        let frame = match memory.mapper.translate(virt)
+        {
+            TranslateResult::Mapped { frame, .. } => frame,
+            _ =>
+            {
+                offset += Size4KiB::SIZE;
+                continue;
+            },
+        };
+
+        match frame
+        {
+            MappedFrame::Size4KiB(_) =>
+                unmap_page::<Size4KiB>(memory, virt, free),
+            MappedFrame::Size2MiB(_) =>
+                unmap_page::<Size2MiB>(memory, virt, free),
+            MappedFrame::Size1GiB(_) =>
+                unmap_page::<Size1GiB>(memory, virt, free),
+        }
+        offset += frame.size();
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Unmaps the page of size `S` at `virt`, and frees its frame if `free` is
+//  set.
+//------------------------------------------------------------------------------
+unsafe fn unmap_page<S: PageSize>
+(
+    memory: &mut KernelMemory,
+    virt: VirtAddr,
+    free: bool,
+)
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::containing_address(virt);
+    if let Ok((frame, flush)) = memory.mapper.unmap(page)
+    {
+        flush.flush();
+        if free
+        {
+            memory.frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
@@ -0,0 +1,363 @@
+/*
+
+    Page table inspector
+
+    ----------------------------------------------------------------------------
+
+    The inspector walks the active 4-level page table hierarchy through the
+    physical memory mapping, for debugging.
+
+      CR3 ---> P4 ---> P3 ---> P2 ---> P1 ---> 4 KiB frame
+                        |       |
+                        |       +---> 2 MiB frame (HUGE_PAGE)
+                        +---> 1 GiB frame (HUGE_PAGE)
+
+    `for_each_range` visits every mapped range. Pages that follow each other
+    both virtually and physically, with the same size and flags, are merged
+    into one range. `ACCESSED` and `DIRTY` are ignored for this, since the CPU
+    sets them on its own.
+
+    `translate` shows how a single address is translated: the entry used at
+    each level and the resulting physical address.
+
+    The output only depends on `core::fmt`, so it can be printed over serial
+    in headless test runs (`serial_dump_page_tables`,
+    `serial_dump_translation`).
+
+*/
+
+use crate::serial_print;
+
+use core::fmt;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{ PageTable, PageTableFlags };
+
+//  Flags that the CPU updates on its own.
+const STATUS_FLAGS: PageTableFlags =
+    PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+//  The size of the range mapped by one entry at each level, from P1 to P4.
+const ENTRY_SIZES: [u64; 4] = [1 << 12, 1 << 21, 1 << 30, 1 << 39];
+
+//------------------------------------------------------------------------------
+//  A range of virtual memory mapped to contiguous physical memory.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedRange
+{
+    pub virt_start: VirtAddr,
+    pub phys_start: PhysAddr,
+    pub size: u64,
+    pub page_size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl MappedRange
+{
+    //--------------------------------------------------------------------------
+    //  Returns whether the range contains the given virtual address.
+    //--------------------------------------------------------------------------
+    pub fn contains( &self, addr: VirtAddr ) -> bool
+    {
+        self.virt_start <= addr && addr - self.virt_start < self.size
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the given page directly follows the range.
+    //--------------------------------------------------------------------------
+    fn is_continued_by( &self, other: &MappedRange ) -> bool
+    {
+        self.page_size == other.page_size
+            && self.flags == other.flags
+            && self.virt_start.as_u64().wrapping_add(self.size)
+                == other.virt_start.as_u64()
+            && self.phys_start + self.size == other.phys_start
+    }
+}
+
+impl fmt::Display for MappedRange
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        let page_size = match self.page_size
+        {
+            0x1000 => "4K",
+            0x20_0000 => "2M",
+            _ => "1G",
+        };
+        write!
+        (
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {} x {:<6} {:?}",
+            self.virt_start.as_u64(),
+            self.virt_start.as_u64().wrapping_add(self.size - 1),
+            self.phys_start.as_u64(),
+            page_size,
+            self.size / self.page_size,
+            self.flags
+        )
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A page table entry used to translate an address.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslationStep
+{
+    pub level: u8,
+    pub index: u16,
+    pub addr: PhysAddr,
+    pub flags: PageTableFlags,
+}
+
+//------------------------------------------------------------------------------
+//  How a virtual address is translated.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation
+{
+    pub addr: VirtAddr,
+    pub steps: [Option<TranslationStep>; 4],
+    pub phys_addr: Option<PhysAddr>,
+}
+
+impl fmt::Display for Translation
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        writeln!(f, "{:?}", self.addr)?;
+        for step in self.steps.iter().flatten()
+        {
+            writeln!
+            (
+                f,
+                "  P{}[{:>3}] = {:#014x} {:?}",
+                step.level,
+                step.index,
+                step.addr.as_u64(),
+                step.flags
+            )?;
+        }
+        match self.phys_addr
+        {
+            Some(addr) => writeln!(f, "  -> {:?}", addr),
+            None => writeln!(f, "  -> not mapped"),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Reads the active page tables.
+//------------------------------------------------------------------------------
+pub struct PageTableInspector
+{
+    physical_memory_offset: VirtAddr,
+    level_4_table: PhysAddr,
+}
+
+impl PageTableInspector
+{
+    //--------------------------------------------------------------------------
+    //  Creates an inspector for the page tables that are active now.
+    //
+    //  This function is unsafe because the caller must guarantee that all
+    //  physical memory is mapped at `physical_memory_offset`.
+    //--------------------------------------------------------------------------
+    pub unsafe fn active( physical_memory_offset: VirtAddr ) -> Self
+    {
+        let (level_4_table_frame, _) = Cr3::read();
+        PageTableInspector
+        {
+            physical_memory_offset,
+            level_4_table: level_4_table_frame.start_address(),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Calls `f` with every mapped range, in address order.
+    //--------------------------------------------------------------------------
+    pub fn for_each_range<F>( &self, mut f: F )
+    where
+        F: FnMut(MappedRange),
+    {
+        let mut current: Option<MappedRange> = None;
+        self.walk(self.level_4_table, 4, 0, &mut |range|
+        {
+            match current.as_mut()
+            {
+                Some(c) if c.is_continued_by(&range) => c.size += range.size,
+                _ =>
+                {
+                    if let Some(c) = current.replace(range)
+                    {
+                        f(c);
+                    }
+                },
+            }
+        });
+        if let Some(c) = current
+        {
+            f(c);
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Writes every mapped range, one per line.
+    //--------------------------------------------------------------------------
+    pub fn write_ranges( &self, out: &mut impl fmt::Write ) -> fmt::Result
+    {
+        let mut result = Ok(());
+        self.for_each_range(|range|
+        {
+            if result.is_ok()
+            {
+                result = writeln!(out, "{}", range);
+            }
+        });
+        result
+    }
+
+    //--------------------------------------------------------------------------
+    //  Shows how the given address is translated.
+    //--------------------------------------------------------------------------
+    pub fn translate( &self, addr: VirtAddr ) -> Translation
+    {
+        let mut translation = Translation
+        {
+            addr,
+            steps: [None; 4],
+            phys_addr: None,
+        };
+
+        let indexes = [
+            u16::from(addr.p4_index()),
+            u16::from(addr.p3_index()),
+            u16::from(addr.p2_index()),
+            u16::from(addr.p1_index()),
+        ];
+        let mut table_addr = self.level_4_table;
+        for (i, &index) in indexes.iter().enumerate()
+        {
+            let level = 4 - i as u8;
+            let entry = &self.table(table_addr)[index as usize];
+            translation.steps[i] = Some(TranslationStep
+            {
+                level,
+                index,
+                addr: entry.addr(),
+                flags: entry.flags(),
+            });
+
+            if !entry.flags().contains(PageTableFlags::PRESENT)
+            {
+                break;
+            }
+            if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE)
+            {
+                let offset = addr.as_u64() % ENTRY_SIZES[level as usize - 1];
+                translation.phys_addr = Some(entry.addr() + offset);
+                break;
+            }
+            table_addr = entry.addr();
+        }
+
+        translation
+    }
+
+    //--------------------------------------------------------------------------
+    //  Visits the present entries of a table at the given level, and calls
+    //  `f` with a range for every page.
+    //--------------------------------------------------------------------------
+    fn walk
+    (
+        &self,
+        table_addr: PhysAddr,
+        level: usize,
+        base: u64,
+        f: &mut dyn FnMut(MappedRange),
+    )
+    {
+        let table = self.table(table_addr);
+        let entry_size = ENTRY_SIZES[level - 1];
+        for (index, entry) in table.iter().enumerate()
+        {
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT)
+            {
+                continue;
+            }
+
+            let start = base + index as u64 * entry_size;
+            if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                f(MappedRange
+                {
+                    virt_start: VirtAddr::new_truncate(start),
+                    phys_start: entry.addr(),
+                    size: entry_size,
+                    page_size: entry_size,
+                    flags: flags - STATUS_FLAGS,
+                });
+            }
+            else
+            {
+                self.walk(entry.addr(), level - 1, start, f);
+            }
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the page table at the given physical address.
+    //--------------------------------------------------------------------------
+    fn table( &self, addr: PhysAddr ) -> &PageTable
+    {
+        let virt = self.physical_memory_offset + addr.as_u64();
+        unsafe { &*virt.as_ptr() }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A `fmt::Write` that prints to the serial port.
+//------------------------------------------------------------------------------
+struct SerialWriter;
+
+impl fmt::Write for SerialWriter
+{
+    fn write_str( &mut self, s: &str ) -> fmt::Result
+    {
+        serial_print!("{}", s);
+        Ok(())
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns an inspector for the active page tables, using the physical memory
+//  offset of the kernel memory.
+//------------------------------------------------------------------------------
+fn kernel_inspector() -> PageTableInspector
+{
+    let physical_memory_offset =
+        super::with_kernel_memory(|memory| memory.mapper.phys_offset())
+        .expect("kernel memory is not installed");
+    unsafe { PageTableInspector::active(physical_memory_offset) }
+}
+
+//------------------------------------------------------------------------------
+//  Prints every mapped range of the active page tables over serial.
+//------------------------------------------------------------------------------
+pub fn serial_dump_page_tables()
+{
+    kernel_inspector()
+        .write_ranges(&mut SerialWriter)
+        .expect("Printing to serial failed");
+}
+
+//------------------------------------------------------------------------------
+//  Prints how the given address is translated over serial.
+//------------------------------------------------------------------------------
+pub fn serial_dump_translation( addr: VirtAddr )
+{
+    serial_print!("{}", kernel_inspector().translate(addr));
+}
@@ -0,0 +1,360 @@
+/*
+
+    Lazy regions
+
+    ----------------------------------------------------------------------------
+
+    A lazy region is a range of virtual memory that is reserved up front but
+    only backed by physical frames when it is first touched (demand paging).
+
+    Touching an unmapped page of a lazy region raises a page fault. The page
+    fault handler then calls `handle_page_fault`, which allocates a zeroed
+    frame, maps it with the flags of the region, and returns so that the
+    faulting instruction runs again.
+
+      access ---> page fault ---> lazy region? --yes--> map zeroed frame ---+
+        ^                              |                                    |
+        |                              no                                   |
+        |                              v                                    |
+        |                            fatal                                  |
+        +-------------------------------------------------------------------+
+
+    This suits memory that may or may not be used, such as stacks and large
+    buffers: frames are only spent on the pages that are actually accessed.
+
+    The regions are kept in a fixed-size table, because the page fault handler
+    must not allocate heap memory. Each region is also recorded in the kernel
+    VMA tree (see `vma`), so it cannot overlap the heap or other areas, and it
+    is listed by `kernel_layout`.
+
+*/
+
+use super::{ KERNEL_MEMORY, VmaError, VmaPurpose };
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    Mapper,
+    Page,
+    PageSize,
+    PageTableFlags,
+    Size4KiB,
+};
+
+//  The maximum number of lazy regions that can be reserved at once.
+pub const MAX_LAZY_REGIONS: usize = 32;
+
+static LAZY_REGIONS: Mutex<[Option<LazyRegion>; MAX_LAZY_REGIONS]> =
+    Mutex::new([None; MAX_LAZY_REGIONS]);
+
+//------------------------------------------------------------------------------
+//  A reserved range of virtual memory that is mapped on first access.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LazyRegion
+{
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+impl LazyRegion
+{
+    //--------------------------------------------------------------------------
+    //  Returns the first address of the region.
+    //--------------------------------------------------------------------------
+    pub fn start( &self ) -> VirtAddr
+    {
+        self.start
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the address right after the end of the region.
+    //--------------------------------------------------------------------------
+    pub fn end( &self ) -> VirtAddr
+    {
+        self.end
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the flags its pages are mapped with.
+    //--------------------------------------------------------------------------
+    pub fn flags( &self ) -> PageTableFlags
+    {
+        self.flags
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the region contains the given address.
+    //--------------------------------------------------------------------------
+    pub fn contains( &self, addr: VirtAddr ) -> bool
+    {
+        self.start <= addr && addr < self.end
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the pages of the region.
+    //--------------------------------------------------------------------------
+    fn pages( &self ) -> impl Iterator<Item = Page<Size4KiB>>
+    {
+        Page::range
+        (
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+}
+
+//------------------------------------------------------------------------------
+//  An error returned when a lazy region cannot be reserved.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyRegionError
+{
+    //  The start address or the size is not page aligned, or the size is 0.
+    InvalidRange,
+
+    //  The range overlaps a region or another kernel VMA that is already
+    //  reserved.
+    Overlap,
+
+    //  `MAX_LAZY_REGIONS` regions are already reserved.
+    TableFull,
+}
+
+//------------------------------------------------------------------------------
+//  The reason why a page fault could not be resolved.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultError
+{
+    //  The address is not inside a lazy region.
+    NotLazy,
+
+    //  The page is present, so the access violated its flags.
+    ProtectionViolation,
+
+    //  The access is not allowed by the flags of the lazy region.
+    AccessDenied,
+
+    //  The fault occurred while the page tables or the region table were
+    //  being modified.
+    Busy,
+
+    //  No frame is left to back the page.
+    OutOfMemory,
+
+    //  The page could not be mapped.
+    MapFailed,
+}
+
+impl fmt::Display for PageFaultError
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        let reason = match self
+        {
+            PageFaultError::NotLazy =>
+                "address is not in a lazy region",
+            PageFaultError::ProtectionViolation =>
+                "protection violation on a present page",
+            PageFaultError::AccessDenied =>
+                "access is not allowed by the lazy region",
+            PageFaultError::Busy =>
+                "page tables are in use by the faulting code",
+            PageFaultError::OutOfMemory =>
+                "out of physical frames",
+            PageFaultError::MapFailed =>
+                "mapping the page failed",
+        };
+        f.write_str(reason)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Reserves `start..start + size` as a lazy region whose pages are mapped
+//  with `flags` on first access. `PRESENT` is added to the flags.
+//
+//  The range must not be mapped already. It is recorded in the kernel VMA
+//  tree, so the heap must be initialized.
+//------------------------------------------------------------------------------
+pub fn reserve_lazy
+(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<LazyRegion, LazyRegionError>
+{
+    if size == 0
+        || !start.is_aligned(Size4KiB::SIZE)
+        || !size.is_multiple_of(Size4KiB::SIZE)
+    {
+        return Err(LazyRegionError::InvalidRange);
+    }
+    let end = start.as_u64()
+        .checked_add(size)
+        .and_then(|end| VirtAddr::try_new(end).ok())
+        .ok_or(LazyRegionError::InvalidRange)?;
+
+    let region = LazyRegion
+    {
+        start,
+        end,
+        flags: flags | PageTableFlags::PRESENT,
+    };
+
+    //  The VMA tree also holds the other lazy regions, so it catches every
+    //  overlap.
+    super::insert_vma(start, size, VmaPurpose::Lazy, region.flags)
+        .map_err(|err| match err
+        {
+            VmaError::Overlap(_) => LazyRegionError::Overlap,
+            _ => LazyRegionError::InvalidRange,
+        })?;
+
+    let inserted = interrupts::without_interrupts(||
+    {
+        let mut regions = LAZY_REGIONS.lock();
+        match regions.iter_mut().find(|r| r.is_none())
+        {
+            Some(slot) =>
+            {
+                *slot = Some(region);
+                true
+            },
+            None => false,
+        }
+    });
+    if !inserted
+    {
+        super::free_vma(start);
+        return Err(LazyRegionError::TableFull);
+    }
+    Ok(region)
+}
+
+//------------------------------------------------------------------------------
+//  Releases the lazy region starting at `start`, unmapping its pages and
+//  freeing their frames.
+//
+//  This function is unsafe because the caller must guarantee that the memory
+//  of the region is no longer in use.
+//------------------------------------------------------------------------------
+pub unsafe fn release_lazy( start: VirtAddr ) -> Option<LazyRegion>
+{
+    let region = interrupts::without_interrupts(||
+    {
+        LAZY_REGIONS.lock()
+            .iter_mut()
+            .find(|r| r.is_some_and(|r| r.start == start))
+            .and_then(|r| r.take())
+    })?;
+    super::free_vma(start);
+
+    super::with_kernel_memory(|memory|
+    {
+        for page in region.pages()
+        {
+            if let Ok((frame, flush)) = memory.mapper.unmap(page)
+            {
+                flush.flush();
+                memory.frame_allocator.deallocate_frame(frame);
+            }
+        }
+    });
+
+    Some(region)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the lazy region that contains the given address.
+//------------------------------------------------------------------------------
+pub fn find_lazy( addr: VirtAddr ) -> Option<LazyRegion>
+{
+    interrupts::without_interrupts(||
+    {
+        LAZY_REGIONS.lock().iter().flatten().find(|r| r.contains(addr)).copied()
+    })
+}
+
+//------------------------------------------------------------------------------
+//  Resolves a page fault at `addr` by mapping a zeroed frame, if the address
+//  lies in a lazy region.
+//
+//  Called from the page fault handler, so locks are only tried: a fault
+//  taken while the region table or the page tables are locked cannot be
+//  resolved.
+//------------------------------------------------------------------------------
+pub fn handle_page_fault
+(
+    addr: VirtAddr,
+    error_code: PageFaultErrorCode,
+) -> Result<(), PageFaultError>
+{
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        return Err(PageFaultError::ProtectionViolation);
+    }
+
+    let region = LAZY_REGIONS.try_lock()
+        .ok_or(PageFaultError::Busy)?
+        .iter()
+        .flatten()
+        .find(|r| r.contains(addr))
+        .copied()
+        .ok_or(PageFaultError::NotLazy)?;
+
+    let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
+    let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+    let fetch = error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH);
+    if (write && !region.flags.contains(PageTableFlags::WRITABLE))
+        || (user && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+        || (fetch && region.flags.contains(PageTableFlags::NO_EXECUTE))
+    {
+        return Err(PageFaultError::AccessDenied);
+    }
+
+    let mut guard = KERNEL_MEMORY.try_lock().ok_or(PageFaultError::Busy)?;
+    let memory = guard.as_mut().ok_or(PageFaultError::Busy)?;
+
+    let frame = memory.frame_allocator
+        .allocate_frame()
+        .ok_or(PageFaultError::OutOfMemory)?;
+    unsafe
+    {
+        let frame_ptr: *mut u8 =
+            (memory.mapper.phys_offset() + frame.start_address().as_u64())
+            .as_mut_ptr();
+        frame_ptr.write_bytes(0, Size4KiB::SIZE as usize);
+    }
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let map_to_result = unsafe
+    {
+        memory.mapper.map_to
+        (
+            page,
+            frame,
+            super::supported_flags(region.flags),
+            &mut memory.frame_allocator
+        )
+    };
+    match map_to_result
+    {
+        Ok(flush) =>
+        {
+            flush.flush();
+            Ok(())
+        },
+        Err(_) =>
+        {
+            unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            Err(PageFaultError::MapFailed)
+        },
+    }
+}
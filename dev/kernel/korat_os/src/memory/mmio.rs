@@ -0,0 +1,306 @@
+/*
+
+    MMIO mapping
+
+    ----------------------------------------------------------------------------
+
+    Devices expose their registers and buffers at fixed physical addresses
+    (memory-mapped I/O). To access them, the physical range is mapped into a
+    free range of kernel virtual memory taken from the VMA tree.
+
+      physical                                virtual
+      +------------------+                    +------------------+
+      | device registers | <-- map_mmio ----- | MmioRegion       |
+      +------------------+                    +------------------+
+
+    The flags are chosen by the caller. Device memory usually needs
+    `NO_CACHE` (or `WRITE_THROUGH`), and should never be executed
+    (`NO_EXECUTE`).
+
+    `map_mmio` returns an `MmioRegion` handle. The range stays mapped as long
+    as the handle lives and is unmapped when it is dropped. The frames belong
+    to the device, so they are never given to the frame allocator.
+
+*/
+
+use super::{ VmaError, VmaPurpose };
+
+use core::mem;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{
+    Mapper,
+    Page,
+    PageSize,
+    PageTableFlags,
+    PhysFrame,
+    Size4KiB,
+};
+use x86_64::structures::paging::mapper::MapToError;
+
+//------------------------------------------------------------------------------
+//  An error returned when a memory range cannot be mapped.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError
+{
+    //  The range is empty, not aligned or does not fit in the address space.
+    InvalidRange,
+
+    //  No virtual range could be reserved.
+    Vma(VmaError),
+
+    //  No frame was left for the page or for a page table.
+    FrameAllocationFailed,
+
+    //  A page in the range is already mapped to the given frame.
+    PageAlreadyMapped(PhysAddr),
+
+    //  A page in the range is part of an existing huge page.
+    ParentEntryHugePage,
+
+    //  The kernel memory has not been installed yet.
+    NotInstalled,
+}
+
+impl From<VmaError> for MapError
+{
+    fn from( err: VmaError ) -> Self
+    {
+        MapError::Vma(err)
+    }
+}
+
+impl<S: PageSize> From<MapToError<S>> for MapError
+{
+    fn from( err: MapToError<S> ) -> Self
+    {
+        match err
+        {
+            MapToError::FrameAllocationFailed =>
+                MapError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage =>
+                MapError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) =>
+                MapError::PageAlreadyMapped(frame.start_address()),
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A mapped physical range. The mapping is removed when it is dropped.
+//------------------------------------------------------------------------------
+#[derive(Debug)]
+pub struct MmioRegion
+{
+    phys_addr: PhysAddr,
+    virt_addr: VirtAddr,
+    len: usize,
+    vma_start: VirtAddr,
+    pages: u64,
+}
+
+impl MmioRegion
+{
+    //--------------------------------------------------------------------------
+    //  Returns the physical address of the start of the region.
+    //--------------------------------------------------------------------------
+    pub fn phys_addr( &self ) -> PhysAddr
+    {
+        self.phys_addr
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the virtual address the start of the region is mapped to.
+    //--------------------------------------------------------------------------
+    pub fn virt_addr( &self ) -> VirtAddr
+    {
+        self.virt_addr
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the length of the region in bytes.
+    //--------------------------------------------------------------------------
+    pub fn len( &self ) -> usize
+    {
+        self.len
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the region is empty. Always false, since empty regions
+    //  cannot be mapped.
+    //--------------------------------------------------------------------------
+    pub fn is_empty( &self ) -> bool
+    {
+        self.len == 0
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns a pointer to the start of the region.
+    //--------------------------------------------------------------------------
+    pub fn as_mut_ptr<T>( &self ) -> *mut T
+    {
+        self.virt_addr.as_mut_ptr()
+    }
+
+    //--------------------------------------------------------------------------
+    //  Reads a value at the given byte offset with a volatile read.
+    //
+    //  Panics if the value does not lie inside the region.
+    //--------------------------------------------------------------------------
+    pub fn read<T: Copy>( &self, offset: usize ) -> T
+    {
+        unsafe { self.ptr_at::<T>(offset).read_volatile() }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Writes a value at the given byte offset with a volatile write.
+    //
+    //  Panics if the value does not lie inside the region.
+    //--------------------------------------------------------------------------
+    pub fn write<T: Copy>( &mut self, offset: usize, value: T )
+    {
+        unsafe { self.ptr_at::<T>(offset).write_volatile(value) }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns a pointer to a `T` at the given byte offset, checking that it
+    //  is inside the region and aligned.
+    //--------------------------------------------------------------------------
+    fn ptr_at<T>( &self, offset: usize ) -> *mut T
+    {
+        assert!
+        (
+            offset.checked_add(mem::size_of::<T>())
+                .is_some_and(|end| end <= self.len),
+            "MMIO access out of bounds: offset {:#x}, length {:#x}",
+            offset,
+            self.len
+        );
+        let ptr: *mut T = (self.virt_addr + offset).as_mut_ptr();
+        assert!(ptr.is_aligned(), "unaligned MMIO access: {:p}", ptr);
+        ptr
+    }
+}
+
+impl Drop for MmioRegion
+{
+    fn drop( &mut self )
+    {
+        unmap_pages(self.vma_start, self.pages);
+        super::free_vma(self.vma_start);
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Maps the physical range `phys_addr..phys_addr + len` into a free range of
+//  kernel virtual memory with the given flags. `PRESENT` is always added.
+//
+//  `NO_EXECUTE` is dropped if the CPU does not have no-execute enabled, since
+//  the bit is reserved then.
+//
+//  This function is unsafe because the caller must guarantee that the range
+//  belongs to a device (or is otherwise not managed by the frame allocator)
+//  and that accessing it with the given flags has no undefined behavior.
+//------------------------------------------------------------------------------
+pub unsafe fn map_mmio
+(
+    phys_addr: PhysAddr,
+    len: usize,
+    flags: PageTableFlags,
+) -> Result<MmioRegion, MapError>
+{
+    let start = phys_addr.align_down(Size4KiB::SIZE);
+    let offset = phys_addr - start;
+    let end = phys_addr.as_u64()
+        .checked_add(len as u64)
+        .filter(|_| len > 0)
+        .and_then(|end| PhysAddr::try_new(end).ok())
+        .ok_or(MapError::InvalidRange)?;
+    let size = end.align_up(Size4KiB::SIZE) - start;
+    let pages = size / Size4KiB::SIZE;
+
+    let flags = super::supported_flags(flags | PageTableFlags::PRESENT);
+
+    let vma_start = super::allocate_vma
+    (
+        size,
+        Size4KiB::SIZE,
+        VmaPurpose::Mmio,
+        flags
+    )?
+    .start();
+
+    let mapped = super::with_kernel_memory(|memory|
+    {
+        for i in 0..pages
+        {
+            let page = Page::<Size4KiB>::containing_address
+            (
+                vma_start + i * Size4KiB::SIZE
+            );
+            let frame = PhysFrame::containing_address
+            (
+                start + i * Size4KiB::SIZE
+            );
+            let map_to_result = memory.mapper.map_to
+            (
+                page,
+                frame,
+                flags,
+                &mut memory.frame_allocator
+            );
+            match map_to_result
+            {
+                Ok(flush) => flush.flush(),
+                Err(err) => return (i, Err(MapError::from(err))),
+            }
+        }
+        (pages, Ok(()))
+    });
+
+    let result = match mapped
+    {
+        Some((_, Ok(()))) => Ok(()),
+        Some((count, Err(err))) =>
+        {
+            unmap_pages(vma_start, count);
+            Err(err)
+        },
+        None => Err(MapError::NotInstalled),
+    };
+    if let Err(err) = result
+    {
+        super::free_vma(vma_start);
+        return Err(err);
+    }
+
+    Ok(MmioRegion
+    {
+        phys_addr,
+        virt_addr: vma_start + offset,
+        len,
+        vma_start,
+        pages,
+    })
+}
+
+//------------------------------------------------------------------------------
+//  Unmaps `count` pages starting at `start` without freeing their frames.
+//------------------------------------------------------------------------------
+fn unmap_pages( start: VirtAddr, count: u64 )
+{
+    super::with_kernel_memory(|memory|
+    {
+        for i in 0..count
+        {
+            let page = Page::<Size4KiB>::containing_address
+            (
+                start + i * Size4KiB::SIZE
+            );
+            if let Ok((_, flush)) = memory.mapper.unmap(page)
+            {
+                flush.flush();
+            }
+        }
+    });
+}
@@ -28,12 +28,90 @@
 
 */
 
-use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+mod address_space;
+mod bitmap_frame_allocator;
+mod boot;
+mod buddy_frame_allocator;
+mod cow;
+mod dma;
+mod huge_page;
+mod inspect;
+mod lazy;
+mod mmio;
+mod protection;
+mod vma;
+
+pub use address_space::{
+    AddressSpace,
+    USER_SPACE_END,
+    USER_SPACE_START,
+    activate_kernel,
+};
+pub use bitmap_frame_allocator::BitmapFrameAllocator;
+pub use boot::{ BootMemorySummary, RegionTotal, reclaim_boot_memory };
+pub use buddy_frame_allocator::{
+    BuddyFrameAllocator,
+    MAX_ORDER,
+    order_for_size,
+};
+pub use cow::{ COPY_ON_WRITE, handle_cow_fault };
+pub use dma::{
+    DmaBuffer,
+    DmaCaching,
+    DmaRegion,
+    DMA_LIMIT_16MIB,
+    DMA_LIMIT_4GIB,
+};
+pub use huge_page::{
+    MappingSize,
+    RegionMapping,
+    huge_pages_1gib_supported,
+    map_physical_region,
+    map_region,
+    unmap_physical_region,
+    unmap_region,
+};
+pub use inspect::{
+    MappedRange,
+    PageTableInspector,
+    Translation,
+    TranslationStep,
+    serial_dump_page_tables,
+    serial_dump_translation,
+};
+pub use lazy::{
+    LazyRegion,
+    LazyRegionError,
+    PageFaultError,
+    MAX_LAZY_REGIONS,
+    find_lazy,
+    handle_page_fault,
+    release_lazy,
+    reserve_lazy,
+};
+pub use mmio::{ MapError, MmioRegion, map_mmio };
+pub use protection::{ enable_no_execute, protect_kernel };
+pub use vma::{
+    Vma,
+    VmaError,
+    VmaPurpose,
+    VmaTree,
+    VMA_WINDOW_END,
+    VMA_WINDOW_START,
+    allocate_vma,
+    find_vma,
+    free_vma,
+    init_vmas,
+    insert_vma,
+    kernel_layout,
+};
+
+use bootloader::bootinfo::{ MemoryMap, MemoryRegion, MemoryRegionType };
+use spin::Mutex;
 use x86_64::{ VirtAddr, PhysAddr };
 use x86_64::structures::paging::{
-    Page,
     PageTable,
-    Mapper,
+    PageTableFlags,
     Size4KiB,
     FrameAllocator,
     OffsetPageTable,
@@ -55,6 +133,53 @@ pub unsafe fn init( physical_memory_offset: VirtAddr )
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+//------------------------------------------------------------------------------
+//  The kernel's page table mapper and frame allocator.
+//
+//  Subsystems that map memory after boot (e.g. the heap when it grows) use
+//  them through `with_kernel_memory`. No heap memory may be allocated while
+//  they are in use, since growing the heap needs them too.
+//------------------------------------------------------------------------------
+pub struct KernelMemory
+{
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BuddyFrameAllocator,
+}
+
+static KERNEL_MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+
+//------------------------------------------------------------------------------
+//  Makes the given mapper and frame allocator available to the rest of the
+//  kernel.
+//------------------------------------------------------------------------------
+pub fn install
+(
+    mapper: OffsetPageTable<'static>,
+    frame_allocator: BuddyFrameAllocator,
+)
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(||
+    {
+        *KERNEL_MEMORY.lock() = Some(KernelMemory { mapper, frame_allocator });
+    });
+}
+
+//------------------------------------------------------------------------------
+//  Runs the given closure with the kernel's mapper and frame allocator.
+//
+//  Returns `None` if they have not been installed yet.
+//------------------------------------------------------------------------------
+pub fn with_kernel_memory<F, R>( f: F ) -> Option<R>
+where
+    F: FnOnce(&mut KernelMemory) -> R,
+{
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| KERNEL_MEMORY.lock().as_mut().map(f))
+}
+
 //------------------------------------------------------------------------------
 //  Returns a mutable reference to the active level 4 table.
 //
@@ -78,25 +203,27 @@ unsafe fn active_level_4_table( physical_memory_offset: VirtAddr )
 }
 
 //------------------------------------------------------------------------------
-//  Creates an example mapping for the given page to frame `0xb8000`.
+//  Removes the flags from `flags` that the CPU does not support at the
+//  moment: `NO_EXECUTE` is a reserved bit unless no-execute is enabled.
 //------------------------------------------------------------------------------
-pub fn create_example_mapping(
-    page: Page,
-    mapper: &mut OffsetPageTable,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-)
+pub fn supported_flags( mut flags: PageTableFlags ) -> PageTableFlags
 {
-    use x86_64::structures::paging::PageTableFlags as Flags;
-
-    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    use x86_64::registers::model_specific::{ Efer, EferFlags };
 
-    let map_to_result = unsafe
+    if !Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
     {
-        mapper.map_to(page, frame, flags, frame_allocator)
-    };
+        flags.remove(PageTableFlags::NO_EXECUTE);
+    }
+    flags
+}
 
-    map_to_result.expect("map_to failed").flush();
+//------------------------------------------------------------------------------
+//  Returns an iterator over the usable regions in the memory map.
+//------------------------------------------------------------------------------
+fn usable_regions( memory_map: &'static MemoryMap )
+    -> impl Iterator<Item = &'static MemoryRegion>
+{
+    memory_map.iter().filter(|r| r.region_type == MemoryRegionType::Usable)
 }
 
 //------------------------------------------------------------------------------
@@ -148,14 +275,8 @@ impl BootInfoFrameAllocator
     //--------------------------------------------------------------------------
     fn usable_frames( &self ) -> impl Iterator<Item = PhysFrame>
     {
-        //  Get usable regions from memory map.
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r|
-            r.region_type == MemoryRegionType::Usable
-        );
-
-        //  Map each region to its address range.
-        let addr_ranges = usable_regions.map(|r|
+        //  Map each usable region to its address range.
+        let addr_ranges = usable_regions(self.memory_map).map(|r|
             r.range.start_addr()..r.range.end_addr()
         );
 
@@ -0,0 +1,481 @@
+/*
+
+    Kernel memory protection (W^X)
+
+    ----------------------------------------------------------------------------
+
+    No page of the kernel should be both writable and executable (W^X). Code
+    that can be written to, or data that can be executed, turns a memory bug
+    into arbitrary code execution.
+
+    | Memory              | Flags                           |
+    | ------------------- | ------------------------------- |
+    | `.text`             | Read-only, executable           |
+    | `.rodata`           | Read-only, `NO_EXECUTE`         |
+    | `.data`, `.bss`     | Writable, `NO_EXECUTE`          |
+    | Heap                | Writable, `NO_EXECUTE`          |
+    | Boot stack          | Writable, `NO_EXECUTE`          |
+    | Physical memory map | Writable, `NO_EXECUTE`          |
+    | ... of the kernel   | Read-only, `NO_EXECUTE`         |
+
+    The bootloader loads the kernel ELF file into memory (the `Kernel` region
+    of the memory map) and maps its loadable segments. `protect_kernel` reads
+    the program headers of that file and updates the flags of every page of
+    each segment from the segment's permissions. The linker puts the sections
+    into segments by permissions, so this is done per section group. The
+    linker starts every segment on a new page, but should a page still be
+    shared by a writable and an executable segment, it is made writable and
+    not executable rather than both.
+
+    The boot stack is the run of pages around the stack pointer whose frames
+    lie in the `KernelStack` region of the memory map. Below it is the guard
+    page the bootloader leaves unmapped.
+
+    The bootloader also maps all physical memory, writable and executable,
+    at `physical_memory_offset`, mostly with 2 MiB pages. Every frame, the
+    kernel's own included, is reachable through this mapping, so it gets
+    `NO_EXECUTE` as well. The huge pages that overlap the `Kernel` region
+    are split into 4 KiB pages, so that the alias of the kernel can be made
+    read-only without touching the frames next to it.
+
+    `NO_EXECUTE` is a reserved bit unless `EFER.NXE` is set, so
+    `enable_no_execute` must run before anything is mapped with it. `CR0.WP`
+    is set as well, since otherwise the kernel could still write to read-only
+    pages.
+
+*/
+
+use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+use core::arch::x86_64::__cpuid;
+use super::KernelMemory;
+use x86_64::{ PhysAddr, VirtAddr };
+use x86_64::registers::control::{ Cr0, Cr0Flags };
+use x86_64::registers::model_specific::{ Efer, EferFlags };
+use x86_64::structures::paging::{
+    FrameAllocator,
+    Mapper,
+    OffsetPageTable,
+    Page,
+    PageSize,
+    PageTable,
+    PageTableFlags,
+    PhysFrame,
+    Size1GiB,
+    Size2MiB,
+    Size4KiB,
+    Translate,
+};
+use x86_64::structures::paging::mapper::{ MappedFrame, TranslateResult };
+
+//  ELF constants.
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELF_CLASS_64: u8 = 2;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+//  The maximum number of loadable segments that are protected.
+const MAX_SEGMENTS: usize = 16;
+
+//  The maximum size of the boot stack that is searched for.
+const MAX_STACK_PAGES: u64 = 512;
+
+//------------------------------------------------------------------------------
+//  A loadable segment of the kernel.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy)]
+struct Segment
+{
+    start: u64,
+    end: u64,
+    flags: u32,
+}
+
+//------------------------------------------------------------------------------
+//  Enables the no-execute bit in page table entries (`EFER.NXE`) if the CPU
+//  supports it, and makes read-only pages read-only for the kernel too
+//  (`CR0.WP`).
+//
+//  Returns whether no-execute is enabled.
+//------------------------------------------------------------------------------
+#[allow(unused_unsafe)]
+pub fn enable_no_execute() -> bool
+{
+    unsafe
+    {
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let max_extended_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    let supported = max_extended_leaf >= 0x8000_0001
+        && unsafe { __cpuid(0x8000_0001) }.edx & (1 << 20) != 0;
+    if supported
+    {
+        unsafe
+        {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+    }
+    supported
+}
+
+//------------------------------------------------------------------------------
+//  Remaps the kernel segments, the boot stack and the physical memory
+//  mapping with W^X flags.
+//
+//  This function is unsafe because the caller must guarantee that the
+//  memory map is the one passed by the bootloader, so that its `Kernel`
+//  region holds the kernel ELF file.
+//------------------------------------------------------------------------------
+pub unsafe fn protect_kernel( memory_map: &'static MemoryMap )
+{
+    super::with_kernel_memory(|memory|
+    {
+        let physical_memory_offset = memory.mapper.phys_offset();
+
+        let empty = Segment { start: 0, end: 0, flags: 0 };
+        let mut segments = [empty; MAX_SEGMENTS];
+        let count = kernel_segments
+        (
+            memory_map,
+            physical_memory_offset,
+            &mut segments
+        );
+        let segments = &segments[..count];
+
+        for segment in segments
+        {
+            let pages = Page::<Size4KiB>::range
+            (
+                Page::containing_address(VirtAddr::new(segment.start)),
+                Page::containing_address(VirtAddr::new(segment.end - 1)) + 1,
+            );
+            for page in pages
+            {
+                let flags = page_flags(segments, page);
+                if let Ok(flush) = memory.mapper.update_flags(page, flags)
+                {
+                    flush.flush();
+                }
+            }
+        }
+
+        //  Find the boot stack by walking from the current stack pointer
+        //  over the pages backed by the `KernelStack` region.
+        let marker = 0u8;
+        let stack_page = Page::<Size4KiB>::containing_address
+        (
+            VirtAddr::from_ptr(&marker)
+        );
+        let flags = super::supported_flags
+        (
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_EXECUTE
+        );
+        let mapper = &mut memory.mapper;
+        let mut page = stack_page;
+        for _ in 0..MAX_STACK_PAGES
+        {
+            if !protect_stack_page(mapper, memory_map, page, flags)
+            {
+                break;
+            }
+            page -= 1;
+        }
+        let mut page = stack_page + 1;
+        for _ in 0..MAX_STACK_PAGES
+        {
+            if !protect_stack_page(mapper, memory_map, page, flags)
+            {
+                break;
+            }
+            page += 1;
+        }
+
+        protect_physical_memory(memory, memory_map);
+    })
+    .expect("kernel memory is not installed");
+}
+
+//------------------------------------------------------------------------------
+//  Sets the flags of a page of the boot stack. Returns false, leaving the
+//  page alone, if it is unmapped (the guard page) or not backed by the
+//  `KernelStack` region, so the mappings next to the stack are not touched.
+//------------------------------------------------------------------------------
+fn protect_stack_page
+(
+    mapper: &mut OffsetPageTable<'static>,
+    memory_map: &'static MemoryMap,
+    page: Page<Size4KiB>,
+    flags: PageTableFlags,
+) -> bool
+{
+    let addr = match mapper.translate_page(page)
+    {
+        Ok(frame) => frame.start_address().as_u64(),
+        Err(_) => return false,
+    };
+    let is_stack = memory_map.iter().any(|r|
+        r.region_type == MemoryRegionType::KernelStack
+            && r.range.start_addr() <= addr
+            && addr < r.range.end_addr()
+    );
+    if !is_stack
+    {
+        return false;
+    }
+
+    //  The boot stack only holds data, so it does not need to be executable.
+    match unsafe { mapper.update_flags(page, flags) }
+    {
+        Ok(flush) =>
+        {
+            flush.flush();
+            true
+        },
+        Err(_) => false,
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Sets `NO_EXECUTE` on the mapping of all physical memory, and makes the
+//  alias of the `Kernel` region read-only.
+//------------------------------------------------------------------------------
+unsafe fn protect_physical_memory
+(
+    memory: &mut KernelMemory,
+    memory_map: &'static MemoryMap,
+)
+{
+    let physical_memory_offset = memory.mapper.phys_offset();
+    let end = memory_map.iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+
+    let mut addr = 0;
+    while addr < end
+    {
+        let virt = physical_memory_offset + addr;
+        let (frame, flags) = match memory.mapper.translate(virt)
+        {
+            TranslateResult::Mapped { frame, flags, .. } => (frame, flags),
+            _ =>
+            {
+                addr += Size4KiB::SIZE;
+                continue;
+            },
+        };
+
+        let size = frame.size();
+        let start = frame.start_address().as_u64();
+        let kernel = overlaps_kernel(memory_map, start, start + size);
+        if kernel && size != Size4KiB::SIZE
+        {
+            //  Split the page and look at the same address again.
+            split_huge_page(memory, virt);
+            continue;
+        }
+
+        let mut flags = flags | PageTableFlags::NO_EXECUTE;
+        if kernel
+        {
+            flags.remove(PageTableFlags::WRITABLE);
+        }
+        let flags = super::supported_flags(flags);
+        let mapper = &mut memory.mapper;
+        let result = match frame
+        {
+            MappedFrame::Size4KiB(_) => mapper
+                .update_flags(Page::<Size4KiB>::containing_address(virt), flags)
+                .map(|flush| flush.flush()),
+            MappedFrame::Size2MiB(_) => mapper
+                .update_flags(Page::<Size2MiB>::containing_address(virt), flags)
+                .map(|flush| flush.flush()),
+            MappedFrame::Size1GiB(_) => mapper
+                .update_flags(Page::<Size1GiB>::containing_address(virt), flags)
+                .map(|flush| flush.flush()),
+        };
+        result.expect("physical memory mapping changed while protecting it");
+        addr = start + size;
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether the physical range `start..end` overlaps a `Kernel`
+//  region of the memory map.
+//------------------------------------------------------------------------------
+fn overlaps_kernel( memory_map: &'static MemoryMap, start: u64, end: u64 )
+    -> bool
+{
+    memory_map.iter().any(|r|
+        r.region_type == MemoryRegionType::Kernel
+            && r.range.start_addr() < end
+            && start < r.range.end_addr()
+    )
+}
+
+//------------------------------------------------------------------------------
+//  Replaces the huge page that maps `addr` with a table of pages of the next
+//  smaller size, mapping the same frames with the same flags. Does nothing
+//  if `addr` is not mapped by a huge page.
+//
+//  This function is unsafe because the caller must guarantee that no
+//  reference into the split page tables is held.
+//------------------------------------------------------------------------------
+unsafe fn split_huge_page( memory: &mut KernelMemory, addr: VirtAddr )
+{
+    let physical_memory_offset = memory.mapper.phys_offset();
+
+    //  Find the entry of the huge page.
+    let mut entry = &mut memory.mapper.level_4_table()[addr.p4_index()];
+    let mut level = 4;
+    while level > 1
+    {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT)
+        {
+            return;
+        }
+        if flags.contains(PageTableFlags::HUGE_PAGE)
+        {
+            break;
+        }
+        let table: &mut PageTable = &mut *(physical_memory_offset
+            + entry.addr().as_u64()).as_mut_ptr();
+        level -= 1;
+        entry = match level
+        {
+            3 => &mut table[addr.p3_index()],
+            2 => &mut table[addr.p2_index()],
+            _ => return,
+        };
+    }
+    if level == 4
+    {
+        return;
+    }
+
+    //  Level 2 entries keep `HUGE_PAGE`; in level 1 entries the same bit
+    //  selects the memory type instead.
+    let flags = entry.flags();
+    let (child_size, child_flags) = if level == 3
+    {
+        (Size2MiB::SIZE, flags)
+    }
+    else
+    {
+        (Size4KiB::SIZE, flags - PageTableFlags::HUGE_PAGE)
+    };
+
+    let frame: PhysFrame = memory.frame_allocator.allocate_frame()
+        .expect("no frame left to split a huge page");
+    let children: &mut PageTable = &mut *(physical_memory_offset
+        + frame.start_address().as_u64()).as_mut_ptr();
+    let start = entry.addr().as_u64();
+    children.zero();
+    for (i, child) in children.iter_mut().enumerate()
+    {
+        child.set_addr
+        (
+            PhysAddr::new(start + i as u64 * child_size),
+            child_flags
+        );
+    }
+    entry.set_addr
+    (
+        frame.start_address(),
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    );
+    x86_64::instructions::tlb::flush_all();
+}
+
+//------------------------------------------------------------------------------
+//  Returns the flags for a kernel page from the permissions of the segments
+//  that contain it. A page shared by two segments gets the permissions of
+//  both, except that it is never both writable and executable: a page
+//  shared by a writable and an executable segment stays writable and is
+//  not executable.
+//------------------------------------------------------------------------------
+fn page_flags( segments: &[Segment], page: Page<Size4KiB> ) -> PageTableFlags
+{
+    let page_start = page.start_address().as_u64();
+    let page_end = page_start + Size4KiB::SIZE;
+    let permissions = segments.iter()
+        .filter(|s| s.start < page_end && page_start < s.end)
+        .fold(0, |acc, s| acc | s.flags);
+
+    let mut flags = PageTableFlags::PRESENT;
+    if permissions & PF_W != 0
+    {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if permissions & PF_X == 0 || permissions & PF_W != 0
+    {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    super::supported_flags(flags)
+}
+
+//------------------------------------------------------------------------------
+//  Reads the loadable segments from the kernel ELF file into `segments`, and
+//  returns their number.
+//------------------------------------------------------------------------------
+unsafe fn kernel_segments
+(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+    segments: &mut [Segment],
+) -> usize
+{
+    let kernel = match memory_map.iter()
+        .find(|r| r.region_type == MemoryRegionType::Kernel)
+    {
+        Some(region) => region,
+        None => return 0,
+    };
+    let elf: *const u8 =
+        (physical_memory_offset + kernel.range.start_addr()).as_ptr();
+    let read_u16 = |offset: u64| (elf.add(offset as usize) as *const u16)
+        .read_unaligned();
+    let read_u32 = |offset: u64| (elf.add(offset as usize) as *const u32)
+        .read_unaligned();
+    let read_u64 = |offset: u64| (elf.add(offset as usize) as *const u64)
+        .read_unaligned();
+
+    if core::slice::from_raw_parts(elf, 4) != ELF_MAGIC
+        || elf.add(4).read() != ELF_CLASS_64
+    {
+        return 0;
+    }
+
+    let ph_offset = read_u64(0x20);
+    let ph_entry_size = read_u16(0x36) as u64;
+    let ph_count = read_u16(0x38) as u64;
+
+    let mut count = 0;
+    for i in 0..ph_count
+    {
+        let header = ph_offset + i * ph_entry_size;
+        let mem_size = read_u64(header + 0x28);
+        if read_u32(header) != PT_LOAD || mem_size == 0
+        {
+            continue;
+        }
+        if count == segments.len()
+        {
+            break;
+        }
+
+        let start = read_u64(header + 0x10);
+        segments[count] = Segment
+        {
+            start,
+            end: start + mem_size,
+            flags: read_u32(header + 0x04),
+        };
+        count += 1;
+    }
+    count
+}
@@ -0,0 +1,581 @@
+/*
+
+    Kernel virtual memory areas
+
+    ----------------------------------------------------------------------------
+
+    The kernel's virtual address space is tracked as a set of virtual memory
+    areas (VMAs). A VMA is a page aligned range of virtual addresses together
+    with its purpose and the flags it is mapped with. The areas are kept in a
+    tree ordered by start address, so overlapping ranges are caught when they
+    are inserted.
+
+    At boot, the ranges that are already in use are recorded:
+
+    | Purpose           | Range                                           |
+    | ----------------- | ----------------------------------------------- |
+    | Physical memory   | The bootloader's mapping of all physical memory |
+    | Heap              | `HEAP_START..HEAP_START + HEAP_MAX_SIZE`        |
+    | User              | `USER_SPACE_START..USER_SPACE_END`              |
+    | Boot              | Every other level 4 entry that is present       |
+
+    New ranges (stacks, MMIO, ...) are handed out from the dynamic window
+    `VMA_WINDOW_START..VMA_WINDOW_END` with `allocate_vma`, so subsystems do
+    not need to pick addresses by hand. Each allocated area is followed by an
+    unused guard page.
+
+    The tree is stored on the heap, so it is only available after the heap is
+    initialized, and it must not be used while the kernel memory is locked
+    (see `with_kernel_memory`).
+
+*/
+
+use super::{ USER_SPACE_END, USER_SPACE_START };
+use crate::allocator::{ HEAP_START, HEAP_MAX_SIZE };
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use bootloader::bootinfo::MemoryMap;
+use core::fmt;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{
+    FrameAllocator,
+    PageSize,
+    PageTable,
+    PageTableFlags,
+    PhysFrame,
+    Size4KiB,
+};
+
+//  The range that `allocate_vma` hands out addresses from.
+pub const VMA_WINDOW_START: u64 = 0x_6000_0000_0000;
+pub const VMA_WINDOW_END: u64 = 0x_7000_0000_0000;
+
+//  The size of the address range covered by one level 4 entry.
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+//  The end of the lower half of the address space.
+const LOWER_HALF_END: u64 = 0x_8000_0000_0000;
+
+static KERNEL_VMAS: Mutex<VmaTree> = Mutex::new(VmaTree::new());
+
+//------------------------------------------------------------------------------
+//  What a virtual memory area is used for.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaPurpose
+{
+    PhysicalMemory,
+    Boot,
+    Heap,
+    Stack,
+    Mmio,
+    Dma,
+    Lazy,
+    User,
+    Other,
+}
+
+impl fmt::Display for VmaPurpose
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        let name = match self
+        {
+            VmaPurpose::PhysicalMemory => "physical memory",
+            VmaPurpose::Boot => "boot",
+            VmaPurpose::Heap => "heap",
+            VmaPurpose::Stack => "stack",
+            VmaPurpose::Mmio => "mmio",
+            VmaPurpose::Dma => "dma",
+            VmaPurpose::Lazy => "lazy",
+            VmaPurpose::User => "user",
+            VmaPurpose::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A range of kernel virtual memory.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma
+{
+    start: u64,
+    end: u64,
+    purpose: VmaPurpose,
+    flags: PageTableFlags,
+}
+
+impl Vma
+{
+    //--------------------------------------------------------------------------
+    //  Creates an area for `start..start + size`. Both must be page aligned.
+    //--------------------------------------------------------------------------
+    pub fn new
+    (
+        start: VirtAddr,
+        size: u64,
+        purpose: VmaPurpose,
+        flags: PageTableFlags,
+    ) -> Result<Vma, VmaError>
+    {
+        let start = start.as_u64();
+        if size == 0
+            || !start.is_multiple_of(Size4KiB::SIZE)
+            || !size.is_multiple_of(Size4KiB::SIZE)
+        {
+            return Err(VmaError::InvalidRange);
+        }
+        let end = start.checked_add(size).ok_or(VmaError::InvalidRange)?;
+        if start < LOWER_HALF_END && end > LOWER_HALF_END
+        {
+            return Err(VmaError::InvalidRange);
+        }
+
+        Ok(Vma { start, end, purpose, flags })
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the first address of the area.
+    //--------------------------------------------------------------------------
+    pub fn start( &self ) -> VirtAddr
+    {
+        VirtAddr::new(self.start)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the size of the area in bytes.
+    //--------------------------------------------------------------------------
+    pub fn size( &self ) -> u64
+    {
+        self.end - self.start
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns what the area is used for.
+    //--------------------------------------------------------------------------
+    pub fn purpose( &self ) -> VmaPurpose
+    {
+        self.purpose
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the flags the area is mapped with.
+    //--------------------------------------------------------------------------
+    pub fn flags( &self ) -> PageTableFlags
+    {
+        self.flags
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the area contains the given address.
+    //--------------------------------------------------------------------------
+    pub fn contains( &self, addr: VirtAddr ) -> bool
+    {
+        self.start <= addr.as_u64() && addr.as_u64() < self.end
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns whether the area shares an address with `other`.
+    //--------------------------------------------------------------------------
+    pub fn overlaps( &self, other: &Vma ) -> bool
+    {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+impl fmt::Display for Vma
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        write!
+        (
+            f,
+            "{:#014x}-{:#014x} {:>10} KiB  {:<16} {:?}",
+            self.start,
+            self.end,
+            self.size() / 1024,
+            self.purpose,
+            self.flags
+        )
+    }
+}
+
+//------------------------------------------------------------------------------
+//  An error returned by the VMA tree.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError
+{
+    //  The range is empty, not page aligned or not canonical.
+    InvalidRange,
+
+    //  The range overlaps the given area.
+    Overlap(Vma),
+
+    //  No free range of the requested size is left in the window.
+    OutOfSpace,
+}
+
+//------------------------------------------------------------------------------
+//  A set of non-overlapping virtual memory areas ordered by address.
+//------------------------------------------------------------------------------
+pub struct VmaTree
+{
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaTree
+{
+    //--------------------------------------------------------------------------
+    //  Creates an empty tree.
+    //--------------------------------------------------------------------------
+    pub const fn new() -> Self
+    {
+        VmaTree
+        {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Inserts an area, unless it overlaps an existing one.
+    //--------------------------------------------------------------------------
+    pub fn insert( &mut self, vma: Vma ) -> Result<(), VmaError>
+    {
+        //  Only the last area starting before the end of the new one can
+        //  overlap it, since the areas do not overlap each other.
+        if let Some((_, prev)) = self.areas.range(..vma.end).next_back()
+        {
+            if prev.overlaps(&vma)
+            {
+                return Err(VmaError::Overlap(*prev));
+            }
+        }
+
+        self.areas.insert(vma.start, vma);
+        Ok(())
+    }
+
+    //--------------------------------------------------------------------------
+    //  Finds a free range of `size` bytes aligned to `align` in
+    //  `window_start..window_end`, followed by a guard page, and inserts an
+    //  area for it.
+    //--------------------------------------------------------------------------
+    pub fn allocate
+    (
+        &mut self,
+        window_start: u64,
+        window_end: u64,
+        size: u64,
+        align: u64,
+        purpose: VmaPurpose,
+        flags: PageTableFlags,
+    ) -> Result<Vma, VmaError>
+    {
+        let align = align.max(Size4KiB::SIZE);
+        if size == 0
+            || !size.is_multiple_of(Size4KiB::SIZE)
+            || !align.is_power_of_two()
+        {
+            return Err(VmaError::InvalidRange);
+        }
+
+        //  The end of the range at `candidate`, including its guard page. A
+        //  range that does not fit in the address space is out of space.
+        let end_at = |candidate: u64|
+            candidate.checked_add(size)?.checked_add(Size4KiB::SIZE);
+
+        //  Try the gap before each area in the window, then the gap after the
+        //  last one.
+        let mut candidate = checked_align_up(window_start, align)
+            .ok_or(VmaError::OutOfSpace)?;
+        for vma in self.areas.range(..window_end).map(|(_, v)| v)
+        {
+            if vma.end <= candidate
+            {
+                continue;
+            }
+            let end = end_at(candidate).ok_or(VmaError::OutOfSpace)?;
+            if end <= vma.start
+            {
+                break;
+            }
+            candidate = vma.end.checked_add(Size4KiB::SIZE)
+                .and_then(|addr| checked_align_up(addr, align))
+                .ok_or(VmaError::OutOfSpace)?;
+        }
+
+        if !matches!(end_at(candidate), Some(end) if end <= window_end)
+        {
+            return Err(VmaError::OutOfSpace);
+        }
+
+        let vma = Vma::new(VirtAddr::new(candidate), size, purpose, flags)?;
+        self.insert(vma)?;
+        Ok(vma)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Removes the area starting at the given address.
+    //--------------------------------------------------------------------------
+    pub fn remove( &mut self, start: VirtAddr ) -> Option<Vma>
+    {
+        self.areas.remove(&start.as_u64())
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the area that contains the given address.
+    //--------------------------------------------------------------------------
+    pub fn find( &self, addr: VirtAddr ) -> Option<Vma>
+    {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| *vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns an iterator over the areas in address order.
+    //--------------------------------------------------------------------------
+    pub fn iter( &self ) -> impl Iterator<Item = &Vma>
+    {
+        self.areas.values()
+    }
+}
+
+impl Default for VmaTree
+{
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Records the virtual memory that is in use at boot in the kernel VMA tree.
+//
+//  Must be called once, after the heap and the kernel memory are initialized.
+//------------------------------------------------------------------------------
+pub fn init_vmas
+(
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+)
+{
+    //  Collect the present level 4 entries first: the tree cannot be touched
+    //  while the kernel memory is locked.
+    let mut present = [false; 256];
+    super::with_kernel_memory(|memory|
+    {
+        let physical_memory_offset = memory.mapper.phys_offset();
+        let level_4_table = memory.mapper.level_4_table();
+        for (i, entry) in level_4_table.iter().take(256).enumerate()
+        {
+            present[i] = !entry.is_unused();
+        }
+
+        //  Allocate the level 3 tables of the dynamic window up front, so
+        //  that address spaces created later share every kernel mapping made
+        //  in it.
+        let first = (VMA_WINDOW_START / LEVEL_4_ENTRY_SIZE) as usize;
+        let last = (VMA_WINDOW_END / LEVEL_4_ENTRY_SIZE) as usize;
+        for entry in level_4_table.iter_mut().skip(first).take(last - first)
+        {
+            if !entry.is_unused()
+            {
+                continue;
+            }
+            let frame: PhysFrame = memory.frame_allocator
+                .allocate_frame()
+                .expect("no frame left for the kernel page tables");
+            let table: *mut PageTable =
+                (physical_memory_offset + frame.start_address().as_u64())
+                .as_mut_ptr();
+            unsafe { table.write(PageTable::new()) };
+            entry.set_frame
+            (
+                frame,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+            );
+        }
+    })
+    .expect("kernel memory is not installed");
+
+    let physical_memory_size = memory_map.iter()
+        .map(|r| r.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let physical_memory = Vma::new
+    (
+        physical_memory_offset,
+        align_up(physical_memory_size, Size4KiB::SIZE),
+        VmaPurpose::PhysicalMemory,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    )
+    .expect("invalid physical memory mapping");
+    let heap = Vma::new
+    (
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_MAX_SIZE as u64,
+        VmaPurpose::Heap,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    )
+    .expect("invalid heap range");
+
+    let user_space = Vma::new
+    (
+        VirtAddr::new(USER_SPACE_START),
+        USER_SPACE_END - USER_SPACE_START,
+        VmaPurpose::User,
+        PageTableFlags::empty(),
+    )
+    .expect("invalid user space range");
+
+    let mut tree = KERNEL_VMAS.lock();
+    tree.insert(physical_memory).expect("physical memory overlaps");
+    tree.insert(heap).expect("heap overlaps");
+
+    //  Address spaces cannot map pages in the user range if the bootloader
+    //  put something there, so it is only reserved when it is free.
+    let user_first = (USER_SPACE_START / LEVEL_4_ENTRY_SIZE) as usize;
+    let user_last = (USER_SPACE_END / LEVEL_4_ENTRY_SIZE) as usize;
+    if !present[user_first..user_last].contains(&true)
+    {
+        tree.insert(user_space).expect("user space overlaps");
+    }
+
+    //  Reserve the rest of every present level 4 entry for the bootloader's
+    //  mappings (kernel image, stack, boot info).
+    for (i, _) in present.iter().enumerate().filter(|(_, &p)| p)
+    {
+        let entry_start = i as u64 * LEVEL_4_ENTRY_SIZE;
+        let entry_end = entry_start + LEVEL_4_ENTRY_SIZE;
+
+        let mut start = entry_start;
+        let known: Vec<Vma> = tree.iter()
+            .filter(|v| v.start < entry_end && entry_start < v.end)
+            .copied()
+            .collect();
+        for vma in known.iter()
+        {
+            if start < vma.start
+            {
+                insert_boot_area(&mut tree, start, vma.start);
+            }
+            start = start.max(vma.end);
+        }
+        if start < entry_end
+        {
+            insert_boot_area(&mut tree, start, entry_end);
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Inserts a `Boot` area for `start..end`.
+//------------------------------------------------------------------------------
+fn insert_boot_area( tree: &mut VmaTree, start: u64, end: u64 )
+{
+    let vma = Vma::new
+    (
+        VirtAddr::new(start),
+        end - start,
+        VmaPurpose::Boot,
+        PageTableFlags::PRESENT,
+    )
+    .expect("invalid boot range");
+    tree.insert(vma).expect("boot range overlaps");
+}
+
+//------------------------------------------------------------------------------
+//  Records an area at a fixed address in the kernel VMA tree.
+//------------------------------------------------------------------------------
+pub fn insert_vma
+(
+    start: VirtAddr,
+    size: u64,
+    purpose: VmaPurpose,
+    flags: PageTableFlags,
+) -> Result<Vma, VmaError>
+{
+    let vma = Vma::new(start, size, purpose, flags)?;
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().insert(vma))?;
+    Ok(vma)
+}
+
+//------------------------------------------------------------------------------
+//  Reserves a free range of `size` bytes in the kernel's dynamic window and
+//  records it in the kernel VMA tree.
+//------------------------------------------------------------------------------
+pub fn allocate_vma
+(
+    size: u64,
+    align: u64,
+    purpose: VmaPurpose,
+    flags: PageTableFlags,
+) -> Result<Vma, VmaError>
+{
+    interrupts::without_interrupts(||
+    {
+        KERNEL_VMAS.lock().allocate
+        (
+            VMA_WINDOW_START,
+            VMA_WINDOW_END,
+            size,
+            align,
+            purpose,
+            flags
+        )
+    })
+}
+
+//------------------------------------------------------------------------------
+//  Removes the area starting at `start` from the kernel VMA tree. The caller
+//  is responsible for unmapping it.
+//------------------------------------------------------------------------------
+pub fn free_vma( start: VirtAddr ) -> Option<Vma>
+{
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().remove(start))
+}
+
+//------------------------------------------------------------------------------
+//  Returns the kernel area that contains the given address.
+//------------------------------------------------------------------------------
+pub fn find_vma( addr: VirtAddr ) -> Option<Vma>
+{
+    interrupts::without_interrupts(|| KERNEL_VMAS.lock().find(addr))
+}
+
+//------------------------------------------------------------------------------
+//  Returns the kernel areas in address order.
+//------------------------------------------------------------------------------
+pub fn kernel_layout() -> Vec<Vma>
+{
+    interrupts::without_interrupts(||
+    {
+        KERNEL_VMAS.lock().iter().copied().collect()
+    })
+}
+
+//------------------------------------------------------------------------------
+//  Align the given address `addr` upwards to alignment `align`.
+//------------------------------------------------------------------------------
+fn align_up( addr: u64, align: u64 ) -> u64
+{
+    (addr + align - 1) & !(align - 1)
+}
+
+//------------------------------------------------------------------------------
+//  Align the given address `addr` upwards to alignment `align`, or returns
+//  `None` if the result does not fit in 64 bits.
+//------------------------------------------------------------------------------
+fn checked_align_up( addr: u64, align: u64 ) -> Option<u64>
+{
+    Some(addr.checked_add(align - 1)? & !(align - 1))
+}
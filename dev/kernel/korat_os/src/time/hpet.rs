@@ -0,0 +1,135 @@
+/*
+
+    HPET
+
+    ----------------------------------------------------------------------------
+
+    The High Precision Event Timer has a main counter that runs at a fixed
+    rate of at least 10 MHz, which makes it a better reference than the PIT
+    for calibrating the TSC. Only the main counter is used; its comparators
+    stay disabled, so it raises no interrupts and the PIT keeps its IRQ.
+
+    Its address is described by the ACPI HPET table, which is not parsed yet,
+    so the standard address `0xfed0_0000` is probed. The capabilities
+    register tells whether a timer is there: it holds a non-zero revision and
+    a counter period of at most 100 ns.
+
+    | Register               | Offset  | Use                                  |
+    | ---------------------- | ------- | ------------------------------------ |
+    | General capabilities   | `0x000` | Period in fs (bits 32..64), 64-bit   |
+    |                        |         | counter (bit 13), revision (0..8)    |
+    | General configuration  | `0x010` | Enable bit (bit 0)                   |
+    | Main counter           | `0x0f0` | The counter                          |
+
+    - [HPET(OSDev Wiki)](https://wiki.osdev.org/HPET)
+
+*/
+
+use crate::memory::{ self, MmioRegion };
+
+use x86_64::PhysAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+//  The standard physical address of the HPET.
+const HPET_BASE: u64 = 0xfed0_0000;
+
+const HPET_SIZE: usize = 0x400;
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0f0;
+
+const CAPABILITIES_REVISION: u64 = 0xff;
+const CAPABILITIES_COUNTER_64: u64 = 1 << 13;
+const CAPABILITIES_PERIOD_SHIFT: u64 = 32;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+//  The longest counter period the specification allows, in femtoseconds.
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+//------------------------------------------------------------------------------
+//  The registers of an HPET whose main counter is running.
+//------------------------------------------------------------------------------
+pub(super) struct Hpet
+{
+    region: MmioRegion,
+    period_fs: u64,
+    counter_64: bool,
+}
+
+impl Hpet
+{
+    //--------------------------------------------------------------------------
+    //  Maps the HPET at its standard address and starts its main counter.
+    //  Returns `None` if no HPET is there. Needs the kernel memory to be
+    //  installed.
+    //--------------------------------------------------------------------------
+    pub(super) fn probe() -> Option<Hpet>
+    {
+        let flags = PageTableFlags::WRITABLE
+            | PageTableFlags::NO_CACHE
+            | PageTableFlags::NO_EXECUTE;
+        let mut region = unsafe
+        {
+            memory::map_mmio(PhysAddr::new(HPET_BASE), HPET_SIZE, flags)
+        }
+        .ok()?;
+
+        let capabilities: u64 = region.read(CAPABILITIES);
+        let period_fs = capabilities >> CAPABILITIES_PERIOD_SHIFT;
+        if capabilities & CAPABILITIES_REVISION == 0
+            || period_fs == 0
+            || period_fs > MAX_PERIOD_FS
+        {
+            return None;
+        }
+
+        let configuration: u64 = region.read(CONFIGURATION);
+        region.write(CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+
+        Some(Hpet
+        {
+            region,
+            period_fs,
+            counter_64: capabilities & CAPABILITIES_COUNTER_64 != 0,
+        })
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the time between two counts, in femtoseconds.
+    //--------------------------------------------------------------------------
+    pub(super) fn period_fs( &self ) -> u64
+    {
+        self.period_fs
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the counts from `earlier` to `later`, allowing for a 32-bit
+    //  counter that wrapped around once.
+    //--------------------------------------------------------------------------
+    pub(super) fn counts_between( &self, earlier: u64, later: u64 ) -> u64
+    {
+        if self.counter_64
+        {
+            later.wrapping_sub(earlier)
+        }
+        else
+        {
+            u64::from((later as u32).wrapping_sub(earlier as u32))
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Reads the main counter.
+    //--------------------------------------------------------------------------
+    pub(super) fn counter( &self ) -> u64
+    {
+        if self.counter_64
+        {
+            self.region.read(MAIN_COUNTER)
+        }
+        else
+        {
+            u64::from(self.region.read::<u32>(MAIN_COUNTER))
+        }
+    }
+}
@@ -0,0 +1,204 @@
+/*
+
+    Time
+
+    ----------------------------------------------------------------------------
+
+    The kernel keeps time by counting the interrupts of the PIT (see `pit`),
+    which ticks at `DEFAULT_TIMER_FREQUENCY` unless told otherwise. The time
+    since `init` is the uptime, and everything else is measured against it:
+
+    | Item       | Meaning                                               |
+    | ---------- | ----------------------------------------------------- |
+    | `ticks`    | Timer interrupts since boot                           |
+    | `uptime`   | Time since boot, as a `Duration`                      |
+    | `Instant`  | A point in time, to measure the `Duration` since then |
+    | `udelay`   | Spins until a number of microseconds has passed       |
+    | `sleep`    | Halts the CPU until a `Duration` has passed           |
+
+    The uptime only advances with a tick, so it has the resolution of the
+    timer, and a `sleep` may last up to one tick longer than asked for.
+
+    Once `calibrate_tsc` has measured the TSC (see `tsc`), `monotonic_nanos`
+    and `Instant` count nanoseconds with it, and `udelay` and `ndelay`
+    busy-wait for short delays that a tick cannot measure.
+
+    The wall-clock date and time come from the CMOS real-time clock, with
+    `read_rtc` and `unix_time` (see `rtc`).
+
+*/
+
+mod hpet;
+mod pit;
+mod rtc;
+mod tsc;
+
+pub use core::time::Duration;
+pub use pit::{
+    DEFAULT_TIMER_FREQUENCY,
+    PIT_FREQUENCY,
+    TimerError,
+    set_timer_frequency,
+    ticks,
+    timer_frequency,
+};
+pub use rtc::{
+    DateTime,
+    MAX_RTC_RATE,
+    MIN_RTC_RATE,
+    RtcError,
+    disable_rtc_interrupt,
+    enable_rtc_interrupt,
+    read_rtc,
+    rtc_interrupts,
+    unix_time,
+};
+pub use tsc::{
+    TscCalibration,
+    TscError,
+    TscReference,
+    calibrate_tsc,
+    monotonic_nanos,
+    ndelay,
+    read_tsc,
+    tsc_calibration,
+    tsc_invariant,
+    tsc_supported,
+    udelay,
+};
+
+use core::ops::{ Add, AddAssign, Sub };
+use x86_64::instructions::interrupts;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+
+//------------------------------------------------------------------------------
+//  Programs the timer.
+//------------------------------------------------------------------------------
+pub fn init()
+{
+    pit::init();
+}
+
+//------------------------------------------------------------------------------
+//  Returns the time since `init`.
+//------------------------------------------------------------------------------
+pub fn uptime() -> Duration
+{
+    let nanos =
+        u128::from(pit::clocks()) * NANOS_PER_SEC / u128::from(PIT_FREQUENCY);
+    Duration::from_nanos(nanos as u64)
+}
+
+//------------------------------------------------------------------------------
+//  Halts the CPU until `duration` has passed, running deferred work
+//  meanwhile. A duration beyond what an `Instant` can hold sleeps forever.
+//
+//  Must be called with interrupts enabled, and not from an interrupt handler.
+//------------------------------------------------------------------------------
+pub fn sleep( duration: Duration )
+{
+    //  A deadline too far away for an `Instant` never comes.
+    let deadline = Instant::now().checked_add(duration);
+    loop
+    {
+        //  A tick arriving between the check and `hlt` would not wake the
+        //  CPU, so interrupts are only enabled again by `hlt` itself.
+        interrupts::disable();
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            interrupts::enable();
+            return;
+        }
+        crate::interrupts::wait_for_interrupt();
+    }
+}
+
+//------------------------------------------------------------------------------
+//  A point in time, measured from `init` with `monotonic_nanos`.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant
+{
+    since_boot: Duration,
+}
+
+impl Instant
+{
+    //--------------------------------------------------------------------------
+    //  Returns the current time.
+    //--------------------------------------------------------------------------
+    pub fn now() -> Self
+    {
+        Instant { since_boot: Duration::from_nanos(monotonic_nanos()) }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the time from `earlier` to `self`, or zero if `earlier` is
+    //  later.
+    //--------------------------------------------------------------------------
+    pub fn duration_since( &self, earlier: Instant ) -> Duration
+    {
+        self.since_boot.saturating_sub(earlier.since_boot)
+    }
+
+    //--------------------------------------------------------------------------
+    //  Returns the time that has passed since `self`.
+    //--------------------------------------------------------------------------
+    pub fn elapsed( &self ) -> Duration
+    {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add( &self, duration: Duration ) -> Option<Instant>
+    {
+        self.since_boot.checked_add(duration)
+            .map(|since_boot| Instant { since_boot })
+    }
+
+    pub fn checked_sub( &self, duration: Duration ) -> Option<Instant>
+    {
+        self.since_boot.checked_sub(duration)
+            .map(|since_boot| Instant { since_boot })
+    }
+}
+
+impl Add<Duration> for Instant
+{
+    type Output = Instant;
+
+    fn add( self, duration: Duration ) -> Instant
+    {
+        self.checked_add(duration)
+            .expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl AddAssign<Duration> for Instant
+{
+    fn add_assign( &mut self, duration: Duration )
+    {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Duration> for Instant
+{
+    type Output = Instant;
+
+    fn sub( self, duration: Duration ) -> Instant
+    {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting a duration from an instant")
+    }
+}
+
+impl Sub<Instant> for Instant
+{
+    type Output = Duration;
+
+    fn sub( self, earlier: Instant ) -> Duration
+    {
+        self.duration_since(earlier)
+    }
+}
@@ -0,0 +1,156 @@
+/*
+
+    Programmable Interval Timer
+
+    ----------------------------------------------------------------------------
+
+    The 8253/8254 PIT counts down from a divisor at 1.193182 MHz, and channel
+    0 raises IRQ 0 each time the count reaches zero. The rate is therefore
+    `PIT_FREQUENCY / divisor`, from about 18.2 Hz (a divisor of 65536, the
+    default of the BIOS) up to the input frequency.
+
+    | Port | Register                    |
+    | ---- | --------------------------- |
+    | 0x40 | Channel 0 data (the count)  |
+    | 0x43 | Mode/command                |
+
+    The handler counts ticks and the input clocks that have passed, so the
+    time stays exact even though the rate is not a whole number of Hz, and
+    across changes of the rate.
+
+*/
+
+use crate::interrupts::{ self, InterruptIndex, IrqStatus };
+
+use core::fmt;
+use core::sync::atomic::{ AtomicU32, AtomicU64, Ordering };
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+//  The input frequency of the PIT in Hz.
+pub const PIT_FREQUENCY: u32 = 1_193_182;
+
+//  The tick rate set up by `init`.
+pub const DEFAULT_TIMER_FREQUENCY: u32 = 1000;
+
+const CHANNEL_0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+
+//  Channel 0, low byte then high byte, mode 2 (rate generator), binary.
+const RATE_GENERATOR: u8 = 0x34;
+
+//  The divisor the BIOS leaves, which is written to the PIT as 0.
+const MAX_DIVISOR: u32 = 0x1_0000;
+
+static DIVISOR: AtomicU32 = AtomicU32::new(MAX_DIVISOR);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static CLOCKS: AtomicU64 = AtomicU64::new(0);
+
+//------------------------------------------------------------------------------
+//  An error returned when the timer cannot run at a frequency.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError
+{
+    //  The frequency is below about 18.2 Hz or above `PIT_FREQUENCY`.
+    InvalidFrequency,
+}
+
+impl fmt::Display for TimerError
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        match self
+        {
+            TimerError::InvalidFrequency =>
+            {
+                f.write_str("the PIT cannot run at this frequency")
+            },
+        }
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Programs the PIT to `DEFAULT_TIMER_FREQUENCY` and registers the tick
+//  handler.
+//------------------------------------------------------------------------------
+pub(super) fn init()
+{
+    set_timer_frequency(DEFAULT_TIMER_FREQUENCY)
+        .expect("the default timer frequency is invalid");
+    interrupts::register_irq_handler
+    (
+        InterruptIndex::Timer.irq(),
+        timer_interrupt_handler
+    )
+    .expect("registering the timer handler failed");
+}
+
+//------------------------------------------------------------------------------
+//  Programs the PIT to tick at about `frequency` Hz. The actual rate is
+//  returned by `timer_frequency`.
+//------------------------------------------------------------------------------
+pub fn set_timer_frequency( frequency: u32 ) -> Result<(), TimerError>
+{
+    if frequency == 0 || frequency > PIT_FREQUENCY
+    {
+        return Err(TimerError::InvalidFrequency);
+    }
+    let divisor = (PIT_FREQUENCY + frequency / 2) / frequency;
+    if divisor > MAX_DIVISOR
+    {
+        return Err(TimerError::InvalidFrequency);
+    }
+
+    cpu_interrupts::without_interrupts(||
+    {
+        let mut command = Port::<u8>::new(COMMAND_PORT);
+        let mut channel_0 = Port::<u8>::new(CHANNEL_0_PORT);
+
+        //  A divisor of 65536 is written as 0.
+        let count = divisor as u16;
+        unsafe
+        {
+            command.write(RATE_GENERATOR);
+            channel_0.write(count as u8);
+            channel_0.write((count >> 8) as u8);
+        }
+        DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+//  Returns the tick rate of the PIT in Hz, rounded down.
+//------------------------------------------------------------------------------
+pub fn timer_frequency() -> u32
+{
+    PIT_FREQUENCY / DIVISOR.load(Ordering::Relaxed)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of timer ticks since `init`.
+//------------------------------------------------------------------------------
+pub fn ticks() -> u64
+{
+    TICKS.load(Ordering::Relaxed)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of PIT input clocks counted by the ticks since `init`.
+//------------------------------------------------------------------------------
+pub(super) fn clocks() -> u64
+{
+    CLOCKS.load(Ordering::Acquire)
+}
+
+//------------------------------------------------------------------------------
+//  A timer interrupt hander.
+//------------------------------------------------------------------------------
+fn timer_interrupt_handler() -> IrqStatus
+{
+    let divisor = u64::from(DIVISOR.load(Ordering::Relaxed));
+    CLOCKS.fetch_add(divisor, Ordering::Release);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    IrqStatus::Handled
+}
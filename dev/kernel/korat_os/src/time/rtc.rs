@@ -0,0 +1,354 @@
+/*
+
+    Real-Time Clock
+
+    ----------------------------------------------------------------------------
+
+    The RTC in the CMOS chip keeps the date and time while the machine is off.
+    Its registers are read by writing the register number to port 0x70 and
+    reading port 0x71.
+
+    | Register | Content                                                 |
+    | -------- | ------------------------------------------------------- |
+    | `0x00`   | Seconds                                                 |
+    | `0x02`   | Minutes                                                 |
+    | `0x04`   | Hours (bit 7 is PM in 12-hour format)                   |
+    | `0x07`   | Day of the month                                        |
+    | `0x08`   | Month                                                   |
+    | `0x09`   | Year in the century                                     |
+    | `0x0a`   | Status A: update in progress (bit 7), periodic rate     |
+    | `0x0b`   | Status B: periodic interrupt (bit 6), binary (bit 2),   |
+    |          | 24-hour format (bit 1)                                  |
+    | `0x0c`   | Status C: interrupt flags, cleared by reading           |
+    | `0x32`   | Century                                                 |
+
+    The values are in BCD unless status B says binary. The RTC updates them
+    once a second, and a read during the update can mix two times, so
+    `read_rtc` waits until no update is in progress and reads until two reads
+    agree.
+
+    The century register is described by the ACPI FADT table, which is not
+    parsed yet, so the usual register `0x32` is used if it holds a plausible
+    century. Otherwise the 21st century is assumed. The RTC is assumed to keep
+    UTC.
+
+    The RTC can also raise IRQ 8 at `32768 >> (rate - 1)` Hz, for a rate from
+    3 (8192 Hz) to 15 (2 Hz), through the secondary PIC.
+
+    - [CMOS(OSDev Wiki)](https://wiki.osdev.org/CMOS)
+    - [RTC(OSDev Wiki)](https://wiki.osdev.org/RTC)
+    - [Date algorithms](https://howardhinnant.github.io/date_algorithms.html)
+
+*/
+
+use crate::interrupts::{ self, InterruptIndex, IrqHandlerId, IrqStatus };
+
+use core::fmt;
+use core::sync::atomic::{ AtomicU64, Ordering };
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+const CENTURY: u8 = 0x32;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE: u8 = 0x0f;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+//  The periodic rates the RTC supports.
+pub const MIN_RTC_RATE: u8 = 3;
+pub const MAX_RTC_RATE: u8 = 15;
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+static PERIODIC_HANDLER: Mutex<Option<IrqHandlerId>> = Mutex::new(None);
+static PERIODIC_INTERRUPTS: AtomicU64 = AtomicU64::new(0);
+
+//------------------------------------------------------------------------------
+//  An error returned when the periodic interrupt cannot be enabled.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError
+{
+    //  The rate is not from `MIN_RTC_RATE` to `MAX_RTC_RATE`.
+    InvalidRate,
+
+    //  The IRQ handler could not be registered.
+    Irq(interrupts::IrqError),
+}
+
+//------------------------------------------------------------------------------
+//  A date and time in UTC.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime
+{
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime
+{
+    //--------------------------------------------------------------------------
+    //  Returns the seconds since 1970-01-01 00:00:00 UTC.
+    //--------------------------------------------------------------------------
+    pub fn unix_timestamp( &self ) -> i64
+    {
+        let days = days_from_civil
+        (
+            i64::from(self.year),
+            i64::from(self.month),
+            i64::from(self.day)
+        );
+        days * 86400
+            + i64::from(self.hour) * 3600
+            + i64::from(self.minute) * 60
+            + i64::from(self.second)
+    }
+}
+
+impl fmt::Display for DateTime
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        write!
+        (
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+}
+
+//------------------------------------------------------------------------------
+//  The CMOS ports.
+//------------------------------------------------------------------------------
+struct Cmos
+{
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos
+{
+    const fn new() -> Self
+    {
+        Cmos { index: Port::new(INDEX_PORT), data: Port::new(DATA_PORT) }
+    }
+
+    fn read( &mut self, register: u8 ) -> u8
+    {
+        unsafe
+        {
+            self.index.write(register);
+            self.data.read()
+        }
+    }
+
+    fn write( &mut self, register: u8, value: u8 )
+    {
+        unsafe
+        {
+            self.index.write(register);
+            self.data.write(value);
+        }
+    }
+
+    //--------------------------------------------------------------------------
+    //  Waits for the end of an update, and reads the raw time registers.
+    //--------------------------------------------------------------------------
+    fn read_time( &mut self ) -> [u8; 7]
+    {
+        while self.read(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+        {
+            core::hint::spin_loop();
+        }
+        [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR, CENTURY]
+            .map(|register| self.read(register))
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Reads the date and time from the RTC.
+//------------------------------------------------------------------------------
+pub fn read_rtc() -> DateTime
+{
+    let (raw, status_b) = cpu_interrupts::without_interrupts(||
+    {
+        let mut cmos = CMOS.lock();
+        let mut raw = cmos.read_time();
+        loop
+        {
+            let again = cmos.read_time();
+            if again == raw
+            {
+                break;
+            }
+            raw = again;
+        }
+        (raw, cmos.read(STATUS_B))
+    });
+
+    let [second, minute, hour, day, month, year, century] = raw;
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let decode = |value: u8|
+        if binary { value } else { (value >> 4) * 10 + (value & 0x0f) };
+
+    //  The PM bit is not part of the BCD or binary value.
+    let mut hour_value = decode(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0
+    {
+        let pm = hour & HOUR_PM != 0;
+        hour_value = match (pm, hour_value)
+        {
+            (false, 12) => 0,
+            (true, 12) => 12,
+            (true, hour) => hour + 12,
+            (false, hour) => hour,
+        };
+    }
+
+    let century = match decode(century)
+    {
+        century @ 19..=29 => u16::from(century),
+        _ => 20,
+    };
+
+    DateTime
+    {
+        year: century * 100 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour: hour_value,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns the current Unix timestamp, read from the RTC.
+//------------------------------------------------------------------------------
+pub fn unix_time() -> i64
+{
+    read_rtc().unix_timestamp()
+}
+
+//------------------------------------------------------------------------------
+//  Makes the RTC raise IRQ 8 at `32768 >> (rate - 1)` Hz. The interrupts are
+//  counted by `rtc_interrupts`.
+//------------------------------------------------------------------------------
+pub fn enable_rtc_interrupt( rate: u8 ) -> Result<(), RtcError>
+{
+    if !(MIN_RTC_RATE..=MAX_RTC_RATE).contains(&rate)
+    {
+        return Err(RtcError::InvalidRate);
+    }
+
+    let mut handler = PERIODIC_HANDLER.lock();
+    if handler.is_none()
+    {
+        let id = interrupts::register_irq_handler
+        (
+            InterruptIndex::RealTimeClock.irq(),
+            rtc_interrupt_handler
+        )
+        .map_err(RtcError::Irq)?;
+        *handler = Some(id);
+    }
+
+    cpu_interrupts::without_interrupts(||
+    {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, (status_a & !STATUS_A_RATE) | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | STATUS_B_PERIODIC);
+
+        //  An interrupt flag left set would keep IRQ 8 from being raised.
+        cmos.read(STATUS_C);
+    });
+    Ok(())
+}
+
+//------------------------------------------------------------------------------
+//  Stops the periodic interrupt of the RTC.
+//------------------------------------------------------------------------------
+pub fn disable_rtc_interrupt()
+{
+    let mut handler = PERIODIC_HANDLER.lock();
+    cpu_interrupts::without_interrupts(||
+    {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !STATUS_B_PERIODIC);
+        cmos.read(STATUS_C);
+    });
+    if let Some(id) = handler.take()
+    {
+        interrupts::unregister_irq_handler(id);
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of periodic RTC interrupts.
+//------------------------------------------------------------------------------
+pub fn rtc_interrupts() -> u64
+{
+    PERIODIC_INTERRUPTS.load(Ordering::Relaxed)
+}
+
+//------------------------------------------------------------------------------
+//  An RTC interrupt handler. Reading status C acknowledges the interrupt.
+//------------------------------------------------------------------------------
+fn rtc_interrupt_handler() -> IrqStatus
+{
+    let status_c = CMOS.lock().read(STATUS_C);
+    if status_c & STATUS_C_PERIODIC == 0
+    {
+        return IrqStatus::NotHandled;
+    }
+
+    PERIODIC_INTERRUPTS.fetch_add(1, Ordering::Relaxed);
+    IrqStatus::Handled
+}
+
+//------------------------------------------------------------------------------
+//  Returns the days from 1970-01-01 to a date of the proleptic Gregorian
+//  calendar, with the `days_from_civil` algorithm by Howard Hinnant.
+//------------------------------------------------------------------------------
+fn days_from_civil( year: i64, month: i64, day: i64 ) -> i64
+{
+    //  Years start in March, so the leap day is the last day of a year.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
@@ -0,0 +1,306 @@
+/*
+
+    Time Stamp Counter
+
+    ----------------------------------------------------------------------------
+
+    The TSC counts CPU clock cycles and is read with one instruction, which
+    makes it the finest clock available. Its frequency is not reported
+    reliably, so `calibrate_tsc` measures it: it counts the cycles during
+    `CALIBRATION_MILLIS` as measured by a reference timer.
+
+    | Reference | Used when                   | Resolution                   |
+    | --------- | --------------------------- | ---------------------------- |
+    | HPET      | One is found (see `hpet`)   | 100 ns or better             |
+    | PIT       | Otherwise                   | One tick of the timer        |
+
+    The TSC runs at a constant rate through frequency and power state
+    changes only if CPUID reports it as invariant. Otherwise the clock is
+    still used, but may drift; `TscCalibration` tells which.
+
+    The clock counts from the uptime at the end of the calibration, and
+    before the calibration it falls back to the uptime.
+
+*/
+
+use super::hpet::Hpet;
+use super::{ pit, uptime, PIT_FREQUENCY };
+
+use core::arch::x86_64::{ __cpuid, _rdtsc };
+use core::fmt;
+use spin::Once;
+use x86_64::instructions::interrupts;
+
+//  How long the TSC is measured for.
+const CALIBRATION_MILLIS: u64 = 50;
+
+const NANOS_PER_SEC: u128 = 1_000_000_000;
+const FEMTOS_PER_SEC: u128 = 1_000_000_000_000_000;
+
+//  CPUID leaves and bits.
+const CPUID_FEATURES: u32 = 0x0000_0001;
+const CPUID_FEATURES_TSC: u32 = 1 << 4;
+const CPUID_MAX_EXTENDED: u32 = 0x8000_0000;
+const CPUID_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+static CALIBRATION: Once<Calibration> = Once::new();
+
+//------------------------------------------------------------------------------
+//  An error returned when the TSC cannot be calibrated.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TscError
+{
+    //  The CPU has no TSC.
+    NotSupported,
+
+    //  Interrupts are disabled, so the PIT does not tick.
+    InterruptsDisabled,
+}
+
+//------------------------------------------------------------------------------
+//  The timer the TSC was calibrated against.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TscReference
+{
+    Hpet,
+    Pit,
+}
+
+//------------------------------------------------------------------------------
+//  The result of the calibration.
+//------------------------------------------------------------------------------
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TscCalibration
+{
+    //  The TSC frequency in Hz.
+    pub frequency: u64,
+
+    pub reference: TscReference,
+
+    //  Whether the TSC runs at a constant rate in all power states.
+    pub invariant: bool,
+}
+
+impl fmt::Display for TscCalibration
+{
+    fn fmt( &self, f: &mut fmt::Formatter ) -> fmt::Result
+    {
+        let khz = self.frequency / 1000;
+        write!
+        (
+            f,
+            "TSC: {}.{:03} MHz, calibrated against the {:?}, {}",
+            khz / 1000,
+            khz % 1000,
+            self.reference,
+            if self.invariant { "invariant" } else { "not invariant" }
+        )
+    }
+}
+
+//------------------------------------------------------------------------------
+//  The calibration and the point where the clock starts.
+//------------------------------------------------------------------------------
+struct Calibration
+{
+    calibration: TscCalibration,
+    base_tsc: u64,
+    base_nanos: u64,
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether the CPU has a TSC.
+//------------------------------------------------------------------------------
+#[allow(unused_unsafe)]
+pub fn tsc_supported() -> bool
+{
+    let edx = unsafe { __cpuid(CPUID_FEATURES) }.edx;
+    edx & CPUID_FEATURES_TSC != 0
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether the TSC runs at a constant rate in all power states.
+//------------------------------------------------------------------------------
+#[allow(unused_unsafe)]
+pub fn tsc_invariant() -> bool
+{
+    let max_extended = unsafe { __cpuid(CPUID_MAX_EXTENDED) }.eax;
+    if max_extended < CPUID_POWER_MANAGEMENT
+    {
+        return false;
+    }
+    let edx = unsafe { __cpuid(CPUID_POWER_MANAGEMENT) }.edx;
+    edx & CPUID_INVARIANT_TSC != 0
+}
+
+//------------------------------------------------------------------------------
+//  Reads the TSC.
+//------------------------------------------------------------------------------
+#[allow(unused_unsafe)]
+pub fn read_tsc() -> u64
+{
+    unsafe { _rdtsc() }
+}
+
+//------------------------------------------------------------------------------
+//  Measures the TSC frequency against the HPET, or the PIT if there is no
+//  HPET, and starts the high-resolution clock.
+//
+//  Does nothing if the TSC is already calibrated. Needs the kernel memory to
+//  be installed, and interrupts to be enabled if there is no HPET.
+//------------------------------------------------------------------------------
+pub fn calibrate_tsc() -> Result<TscCalibration, TscError>
+{
+    if !tsc_supported()
+    {
+        return Err(TscError::NotSupported);
+    }
+    if let Some(calibration) = CALIBRATION.wait()
+    {
+        return Ok(calibration.calibration);
+    }
+
+    let (frequency, reference) = match Hpet::probe()
+    {
+        Some(hpet) => (measure_with_hpet(&hpet), TscReference::Hpet),
+        None =>
+        {
+            if !interrupts::are_enabled()
+            {
+                return Err(TscError::InterruptsDisabled);
+            }
+            (measure_with_pit(), TscReference::Pit)
+        },
+    };
+    let calibration = TscCalibration
+    {
+        frequency,
+        reference,
+        invariant: tsc_invariant(),
+    };
+
+    let (base_tsc, base_nanos) = interrupts::without_interrupts(||
+        (read_tsc(), uptime().as_nanos() as u64)
+    );
+    CALIBRATION.call_once(|| Calibration
+    {
+        calibration,
+        base_tsc,
+        base_nanos,
+    });
+    Ok(calibration)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the result of the calibration, if the TSC is calibrated.
+//------------------------------------------------------------------------------
+pub fn tsc_calibration() -> Option<TscCalibration>
+{
+    CALIBRATION.wait().map(|calibration| calibration.calibration)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the nanoseconds since boot, measured with the TSC once it is
+//  calibrated and with the PIT before.
+//------------------------------------------------------------------------------
+pub fn monotonic_nanos() -> u64
+{
+    match CALIBRATION.wait()
+    {
+        Some(calibration) =>
+        {
+            let cycles = read_tsc().saturating_sub(calibration.base_tsc);
+            let nanos = u128::from(cycles) * NANOS_PER_SEC
+                / u128::from(calibration.calibration.frequency);
+            calibration.base_nanos + nanos as u64
+        },
+        None => uptime().as_nanos() as u64,
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Busy-waits for at least `nanos` nanoseconds, or forever if the deadline
+//  does not fit in a `u64`.
+//------------------------------------------------------------------------------
+pub fn ndelay( nanos: u64 )
+{
+    let deadline = monotonic_nanos().checked_add(nanos);
+    while deadline.is_none_or(|deadline| monotonic_nanos() < deadline)
+    {
+        core::hint::spin_loop();
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Busy-waits for at least `micros` microseconds.
+//------------------------------------------------------------------------------
+pub fn udelay( micros: u64 )
+{
+    ndelay(micros.saturating_mul(1000));
+}
+
+//------------------------------------------------------------------------------
+//  Returns the TSC frequency measured over `CALIBRATION_MILLIS` of the HPET.
+//------------------------------------------------------------------------------
+fn measure_with_hpet( hpet: &Hpet ) -> u64
+{
+    let window_fs = u128::from(CALIBRATION_MILLIS) * FEMTOS_PER_SEC / 1000;
+    let window = (window_fs / u128::from(hpet.period_fs())) as u64;
+
+    let start = hpet.counter();
+    let start_tsc = read_tsc();
+    let (counts, end_tsc) = loop
+    {
+        let counts = hpet.counts_between(start, hpet.counter());
+        let end_tsc = read_tsc();
+        if counts >= window
+        {
+            break (counts, end_tsc);
+        }
+        core::hint::spin_loop();
+    };
+
+    let femtos = u128::from(counts) * u128::from(hpet.period_fs());
+    (u128::from(end_tsc - start_tsc) * FEMTOS_PER_SEC / femtos) as u64
+}
+
+//------------------------------------------------------------------------------
+//  Returns the TSC frequency measured over about `CALIBRATION_MILLIS` of PIT
+//  ticks. The measurement starts and ends right after a tick.
+//------------------------------------------------------------------------------
+fn measure_with_pit() -> u64
+{
+    let window = u64::from(PIT_FREQUENCY) * CALIBRATION_MILLIS / 1000;
+
+    let previous = pit::clocks();
+    let start = wait_for_tick(previous);
+    let start_tsc = read_tsc();
+    let mut end = start;
+    while end - start < window
+    {
+        end = wait_for_tick(end);
+    }
+    let end_tsc = read_tsc();
+
+    let cycles = u128::from(end_tsc - start_tsc);
+    (cycles * u128::from(PIT_FREQUENCY) / u128::from(end - start)) as u64
+}
+
+//------------------------------------------------------------------------------
+//  Spins until the PIT ticks after `clocks`, and returns the new count.
+//------------------------------------------------------------------------------
+fn wait_for_tick( clocks: u64 ) -> u64
+{
+    loop
+    {
+        let now = pit::clocks();
+        if now != clocks
+        {
+            return now;
+        }
+        core::hint::spin_loop();
+    }
+}
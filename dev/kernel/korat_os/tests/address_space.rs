@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use korat_os::memory::{ self, AddressSpace, MapError, USER_SPACE_START };
+
+use bootloader::{ entry_point, BootInfo };
+use alloc::boxed::Box;
+use core::panic::PanicInfo;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{
+    Page,
+    PageTableFlags,
+    PhysFrame,
+    Size4KiB,
+    Translate,
+};
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the page at the given offset in the user range.
+//------------------------------------------------------------------------------
+fn user_page( offset: u64 ) -> Page<Size4KiB>
+{
+    Page::containing_address(VirtAddr::new(USER_SPACE_START + offset))
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of free frames.
+//------------------------------------------------------------------------------
+fn free_frames() -> usize
+{
+    memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames())
+        .unwrap()
+}
+
+#[test_case]
+fn mapping_is_private()
+{
+    let flags = PageTableFlags::WRITABLE;
+    let page = user_page(0);
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+
+    a.map(page, flags).unwrap();
+    assert!(a.translate(page.start_address()).is_some());
+    assert_eq!(b.translate(page.start_address()), None);
+
+    b.map(page, flags).unwrap();
+    assert_ne!
+    (
+        a.translate(page.start_address()),
+        b.translate(page.start_address())
+    );
+
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe
+    {
+        a.activate();
+        assert!(a.is_active());
+        ptr.write_volatile(0xaaaa);
+
+        b.activate();
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xbbbb);
+
+        a.activate();
+        assert_eq!(ptr.read_volatile(), 0xaaaa);
+
+        memory::activate_kernel();
+    }
+    assert!(!a.is_active());
+}
+
+#[test_case]
+fn kernel_is_mapped()
+{
+    let space = AddressSpace::new().unwrap();
+
+    //  Code, stack and heap translate the same way in the new space.
+    let on_heap = Box::new(42u64);
+    let on_stack = 7u64;
+    let code = korat_os::hlt_loop as fn() -> ! as usize as u64;
+    let addrs = [
+        VirtAddr::new(code),
+        VirtAddr::from_ptr(&on_stack),
+        VirtAddr::from_ptr(&*on_heap),
+    ];
+    for addr in addrs
+    {
+        let kernel = memory::with_kernel_memory(|memory|
+            memory.mapper.translate_addr(addr)
+        )
+        .unwrap();
+        assert!(kernel.is_some());
+        assert_eq!(space.translate(addr), kernel);
+    }
+
+    unsafe { space.activate() };
+    assert_eq!(*on_heap, 42);
+    assert_eq!(unsafe { (&on_stack as *const u64).read_volatile() }, 7);
+    unsafe { memory::activate_kernel() };
+}
+
+#[test_case]
+fn map_outside_user_range()
+{
+    let mut space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(0x_6000_0000_0000));
+    let result = space.map(page, PageTableFlags::WRITABLE);
+    assert_eq!(result, Err(MapError::InvalidRange));
+}
+
+#[test_case]
+fn unmap_frees_owned_frames()
+{
+    let mut space = AddressSpace::new().unwrap();
+    let page = user_page(0x1000);
+    space.map(page, PageTableFlags::WRITABLE).unwrap();
+
+    let before = free_frames();
+    assert!(space.unmap(page));
+    assert_eq!(free_frames(), before + 1);
+    assert_eq!(space.translate(page.start_address()), None);
+    assert!(!space.unmap(page));
+}
+
+#[test_case]
+fn drop_frees_every_frame()
+{
+    let before = free_frames();
+    {
+        let mut space = AddressSpace::new().unwrap();
+        for i in 0..16
+        {
+            //  Spread the pages over several level 1, 2 and 3 tables.
+            let offset = i * 0x1000 + (i % 4) * (1 << 30) + (i % 2) * (1 << 21);
+            let page = user_page(offset);
+            space.map(page, PageTableFlags::WRITABLE).unwrap();
+        }
+
+        //  A borrowed frame is not freed with the space.
+        let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+        let page = user_page(1 << 39);
+        unsafe { space.map_frame(page, frame, PageTableFlags::WRITABLE) }
+            .unwrap();
+        assert!(free_frames() < before);
+    }
+    assert_eq!(free_frames(), before);
+}
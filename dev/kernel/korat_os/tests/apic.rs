@@ -0,0 +1,83 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::interrupts::{
+    self,
+    InterruptController,
+    InterruptIndex,
+    PICS,
+};
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn apic_is_enabled()
+{
+    assert!(interrupts::apic_supported());
+    assert_eq!(interrupts::interrupt_controller(), InterruptController::Apic);
+    assert!(interrupts::local_apic_id().is_some());
+}
+
+#[test_case]
+fn init_is_idempotent()
+{
+    assert_eq!(interrupts::init_apic(), Ok(()));
+    assert_eq!(interrupts::interrupt_controller(), InterruptController::Apic);
+}
+
+#[test_case]
+fn legacy_irqs_are_routed()
+{
+    assert_eq!
+    (
+        interrupts::irq_vector(0),
+        Some(InterruptIndex::Timer as u8)
+    );
+    assert_eq!
+    (
+        interrupts::irq_vector(1),
+        Some(InterruptIndex::Keyboard as u8)
+    );
+    assert_eq!(interrupts::irq_vector(3), None);
+}
+
+#[test_case]
+fn pic_is_masked()
+{
+    let masks = x86_64::instructions::interrupts::without_interrupts(||
+        unsafe { PICS.lock().read_masks() }
+    );
+    assert_eq!(masks, [0xff, 0xff]);
+}
+
+#[test_case]
+fn timer_interrupts_arrive()
+{
+    //  `hlt` only returns once an interrupt arrives, which needs the timer
+    //  routing and the end of interrupt to work.
+    for _ in 0..10
+    {
+        x86_64::instructions::hlt();
+    }
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+use korat_os::{ exit_qemu, QemuExitCode, serial_print, serial_println };
+use korat_os::memory::BitmapFrameAllocator;
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::{ PhysAddr, VirtAddr };
+use x86_64::structures::paging::{ FrameDeallocator, PhysFrame };
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+
+    free_vga_buffer(boot_info);
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn free_vga_buffer( boot_info: &'static BootInfo )
+{
+    serial_print!("bitmap_invalid_free::free_vga_buffer...\t");
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut allocator = unsafe
+    {
+        BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+
+    //  The VGA text buffer is not usable RAM, so it was never handed out.
+    let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
+    unsafe { allocator.deallocate_frame(frame) };
+}
+
+#[panic_handler]
+fn panic( _info: &PanicInfo ) -> !
+{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::memory::{ self, BootMemorySummary };
+
+use bootloader::{ entry_point, BootInfo };
+use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+use core::panic::PanicInfo;
+use spin::Once;
+
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+    BOOT_INFO.call_once(|| boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the memory map passed by the bootloader.
+//------------------------------------------------------------------------------
+fn memory_map() -> &'static MemoryMap
+{
+    &BOOT_INFO.wait().unwrap().memory_map
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of bytes of the regions of the given type.
+//------------------------------------------------------------------------------
+fn region_bytes( region_type: MemoryRegionType ) -> u64
+{
+    memory_map().iter()
+        .filter(|r| r.region_type == region_type)
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum()
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of free and total frames.
+//------------------------------------------------------------------------------
+fn frames() -> (usize, usize)
+{
+    memory::with_kernel_memory(|memory|
+    {
+        (
+            memory.frame_allocator.free_frames(),
+            memory.frame_allocator.total_frames()
+        )
+    })
+    .unwrap()
+}
+
+#[test_case]
+fn summary_adds_up()
+{
+    let summary = BootMemorySummary::new(memory_map());
+    let total: u64 = memory_map().iter()
+        .map(|r| r.range.end_addr() - r.range.start_addr())
+        .sum();
+    assert_eq!(summary.total_bytes(), total);
+
+    for region_type in [
+        MemoryRegionType::Usable,
+        MemoryRegionType::Kernel,
+        MemoryRegionType::PageTable,
+        MemoryRegionType::Bootloader,
+    ]
+    {
+        assert_eq!(summary.total(region_type).bytes, region_bytes(region_type));
+    }
+    assert!(summary.total(MemoryRegionType::Usable).bytes > 0);
+    assert!(summary.total(MemoryRegionType::Kernel).regions > 0);
+    assert_eq!
+    (
+        summary.reclaimable().bytes,
+        region_bytes(MemoryRegionType::Bootloader)
+    );
+}
+
+#[test_case]
+fn reclaim_adds_frames()
+{
+    let (free_before, total_before) = frames();
+    korat_os::finish_early_boot(BOOT_INFO.wait().unwrap());
+    let (free_after, total_after) = frames();
+
+    let reclaimed = region_bytes(MemoryRegionType::Bootloader) / 4096;
+    assert!(reclaimed > 0);
+    assert_eq!(free_after - free_before, reclaimed as usize);
+    assert_eq!(total_after - total_before, reclaimed as usize);
+
+    //  Reclaiming twice does nothing.
+    assert_eq!(unsafe { memory::reclaim_boot_memory(memory_map()) }, 0);
+    assert_eq!(frames(), (free_after, total_after));
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+
+use korat_os::{ exit_qemu, QemuExitCode, serial_print, serial_println };
+use korat_os::memory::BuddyFrameAllocator;
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+
+    double_free_after_merge(boot_info);
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+//------------------------------------------------------------------------------
+//  Fails the test without panicking, so only the checked deallocation can
+//  reach the panic handler.
+//------------------------------------------------------------------------------
+fn fail( message: &str ) -> !
+{
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    exit_qemu(QemuExitCode::Failed);
+    korat_os::hlt_loop();
+}
+
+fn double_free_after_merge( boot_info: &'static BootInfo )
+{
+    serial_print!("buddy_double_free::double_free_after_merge...\t");
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut allocator = unsafe
+    {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+
+    //  Use up the free single frames, so the next two are buddies split from
+    //  a larger block.
+    while allocator.free_blocks(0) > 0
+    {
+        allocator.allocate(0);
+    }
+    let (lower, upper) = match (allocator.allocate(0), allocator.allocate(0))
+    {
+        (Some(lower), Some(upper)) => (lower, upper),
+        _ => fail("allocation failed"),
+    };
+    if upper != lower + 4096u64
+    {
+        fail("the frames are not buddies");
+    }
+
+    unsafe
+    {
+        allocator.deallocate(lower, 0);
+        allocator.deallocate(upper, 0);
+    }
+    if allocator.allocate(1) != Some(lower)
+    {
+        fail("the buddies were not merged");
+    }
+    unsafe { allocator.deallocate(lower, 1) };
+
+    //  The pair is merged, so the upper half is not a block of its own.
+    unsafe { allocator.deallocate(upper, 0) };
+}
+
+#[panic_handler]
+fn panic( _info: &PanicInfo ) -> !
+{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
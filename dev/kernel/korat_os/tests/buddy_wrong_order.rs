@@ -0,0 +1,74 @@
+#![no_std]
+#![no_main]
+
+use korat_os::{ exit_qemu, QemuExitCode, serial_print, serial_println };
+use korat_os::memory::BuddyFrameAllocator;
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+
+    deallocate_with_wrong_order(boot_info);
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+//------------------------------------------------------------------------------
+//  Fails the test without panicking, so only the checked deallocation can
+//  reach the panic handler.
+//------------------------------------------------------------------------------
+fn fail( message: &str ) -> !
+{
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    exit_qemu(QemuExitCode::Failed);
+    korat_os::hlt_loop();
+}
+
+fn deallocate_with_wrong_order( boot_info: &'static BootInfo )
+{
+    serial_print!("buddy_wrong_order::deallocate_with_wrong_order...\t");
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut allocator = unsafe
+    {
+        BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset)
+    };
+
+    //  Use up the free blocks below order 3, so the next frame is split from
+    //  a larger block and aligned for order 3. Only the order check can then
+    //  reject freeing it as 8 frames.
+    for order in 0..3
+    {
+        while allocator.free_blocks(order) > 0
+        {
+            allocator.allocate(order);
+        }
+    }
+    let frame = match allocator.allocate(0)
+    {
+        Some(frame) => frame,
+        None => fail("allocation failed"),
+    };
+    if !frame.is_aligned(8 * 4096u64)
+    {
+        fail("the frame is not aligned for order 3");
+    }
+
+    unsafe { allocator.deallocate(frame, 3) };
+}
+
+#[panic_handler]
+fn panic( _info: &PanicInfo ) -> !
+{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
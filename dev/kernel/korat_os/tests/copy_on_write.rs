@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::memory::{ self, AddressSpace, USER_SPACE_START };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{ Page, PageTableFlags, PhysFrame, Size4KiB };
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the page at the given offset in the user range.
+//------------------------------------------------------------------------------
+fn user_page( offset: u64 ) -> Page<Size4KiB>
+{
+    Page::containing_address(VirtAddr::new(USER_SPACE_START + offset))
+}
+
+//------------------------------------------------------------------------------
+//  Returns the frame the given page is mapped to in the given space.
+//------------------------------------------------------------------------------
+fn frame( space: &AddressSpace, page: Page<Size4KiB> ) -> PhysFrame
+{
+    let addr = space.translate(page.start_address()).unwrap();
+    PhysFrame::containing_address(addr)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of references to the given frame.
+//------------------------------------------------------------------------------
+fn reference_count( frame: PhysFrame ) -> usize
+{
+    memory::with_kernel_memory(|memory|
+        memory.frame_allocator.reference_count(frame)
+    )
+    .unwrap()
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of free frames.
+//------------------------------------------------------------------------------
+fn free_frames() -> usize
+{
+    memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames())
+        .unwrap()
+}
+
+//------------------------------------------------------------------------------
+//  Writes `value` at the given page in the given space.
+//------------------------------------------------------------------------------
+fn write( space: &AddressSpace, page: Page<Size4KiB>, value: u64 )
+{
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe
+    {
+        space.activate();
+        ptr.write_volatile(value);
+        memory::activate_kernel();
+    }
+}
+
+//------------------------------------------------------------------------------
+//  Reads the value at the given page in the given space.
+//------------------------------------------------------------------------------
+fn read( space: &AddressSpace, page: Page<Size4KiB> ) -> u64
+{
+    let ptr: *const u64 = page.start_address().as_ptr();
+    unsafe
+    {
+        space.activate();
+        let value = ptr.read_volatile();
+        memory::activate_kernel();
+        value
+    }
+}
+
+#[test_case]
+fn fork_shares_frames()
+{
+    let page = user_page(0);
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(page, PageTableFlags::WRITABLE).unwrap();
+    write(&parent, page, 1);
+
+    let child = parent.fork().unwrap();
+    assert_eq!(frame(&parent, page), frame(&child, page));
+    assert_eq!(reference_count(frame(&parent, page)), 2);
+    assert_eq!(read(&child, page), 1);
+}
+
+#[test_case]
+fn writes_diverge()
+{
+    let page = user_page(0x1000);
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(page, PageTableFlags::WRITABLE).unwrap();
+    write(&parent, page, 1);
+    let shared = frame(&parent, page);
+
+    let child = parent.fork().unwrap();
+    write(&parent, page, 2);
+    write(&child, page, 3);
+
+    assert_eq!(read(&parent, page), 2);
+    assert_eq!(read(&child, page), 3);
+    assert_ne!(frame(&parent, page), frame(&child, page));
+
+    //  The parent wrote first and got a copy, the child took the frame over.
+    assert_eq!(frame(&child, page), shared);
+    assert_eq!(reference_count(frame(&parent, page)), 1);
+    assert_eq!(reference_count(frame(&child, page)), 1);
+}
+
+#[test_case]
+fn last_reference_takes_over()
+{
+    let page = user_page(0x2000);
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(page, PageTableFlags::WRITABLE).unwrap();
+    let shared = frame(&parent, page);
+
+    drop(parent.fork().unwrap());
+    assert_eq!(reference_count(shared), 1);
+
+    let before = free_frames();
+    write(&parent, page, 4);
+    assert_eq!(free_frames(), before);
+    assert_eq!(frame(&parent, page), shared);
+    assert_eq!(read(&parent, page), 4);
+}
+
+#[test_case]
+fn drop_releases_shared_frames()
+{
+    let before = free_frames();
+    {
+        let mut parent = AddressSpace::new().unwrap();
+        for i in 0..8
+        {
+            parent.map(user_page(i * 0x1000), PageTableFlags::WRITABLE)
+                .unwrap();
+        }
+        let mut child = parent.fork().unwrap();
+        let grandchild = child.fork().unwrap();
+        write(&child, user_page(0), 5);
+        write(&grandchild, user_page(0x1000), 6);
+        write(&parent, user_page(0x2000), 7);
+    }
+    assert_eq!(free_frames(), before);
+}
@@ -0,0 +1,121 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use korat_os::allocator::{
+    self,
+    ALLOC_POISON,
+    FREE_POISON,
+    GUARD_BYTE,
+    RED_ZONE_SIZE,
+};
+
+use alloc::alloc::{ alloc, dealloc, Layout };
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn new_memory_is_poisoned()
+{
+    let layout = Layout::from_size_align(64, 8).unwrap();
+    unsafe
+    {
+        let ptr = alloc(layout);
+        for i in 0..layout.size()
+        {
+            assert_eq!(ptr.add(i).read_volatile(), ALLOC_POISON);
+        }
+        dealloc(ptr, layout);
+    }
+}
+
+#[test_case]
+fn blocks_have_red_zones()
+{
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    unsafe
+    {
+        let ptr = alloc(layout);
+        for i in 1..=RED_ZONE_SIZE
+        {
+            assert_eq!(ptr.sub(i).read_volatile(), GUARD_BYTE);
+        }
+        for i in 0..RED_ZONE_SIZE
+        {
+            assert_eq!(ptr.add(layout.size() + i).read_volatile(), GUARD_BYTE);
+        }
+        dealloc(ptr, layout);
+    }
+}
+
+#[test_case]
+fn freed_memory_is_poisoned()
+{
+    //  The allocator may reuse the first bytes of the block for its free
+    //  list, so only the data is checked.
+    let layout = Layout::from_size_align(128, 8).unwrap();
+    unsafe
+    {
+        let ptr = alloc(layout);
+        ptr.write_bytes(0, layout.size());
+        dealloc(ptr, layout);
+        for i in 0..layout.size()
+        {
+            assert_eq!(ptr.add(i).read_volatile(), FREE_POISON);
+        }
+    }
+}
+
+#[test_case]
+fn alignment_is_kept()
+{
+    for align in [8, 64, 512, 4096]
+    {
+        let layout = Layout::from_size_align(100, align).unwrap();
+        unsafe
+        {
+            let ptr = alloc(layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % align, 0);
+            dealloc(ptr, layout);
+        }
+    }
+}
+
+#[test_case]
+fn normal_use_is_balanced()
+{
+    let before = allocator::heap_stats();
+    {
+        let mut boxes = Vec::new();
+        for i in 0..100
+        {
+            boxes.push(Box::new(i));
+        }
+        assert_eq!(boxes.iter().map(|b| **b).sum::<u64>(), 4950);
+    }
+    assert!(allocator::heap_stats().diff(&before).is_balanced());
+}
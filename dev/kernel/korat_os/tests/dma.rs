@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::memory::{
+    self,
+    DmaBuffer,
+    DmaCaching,
+    DmaRegion,
+    MapError,
+    DMA_LIMIT_16MIB,
+    DMA_LIMIT_4GIB,
+};
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::{ PhysAddr, VirtAddr };
+use x86_64::structures::paging::{ PageTableFlags, Translate };
+use x86_64::structures::paging::mapper::TranslateResult;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the physical address and the flags of the page at `addr`.
+//------------------------------------------------------------------------------
+fn translate( addr: VirtAddr ) -> (PhysAddr, PageTableFlags)
+{
+    memory::with_kernel_memory(|memory|
+    {
+        match memory.mapper.translate(addr)
+        {
+            TranslateResult::Mapped { frame, offset, flags } =>
+            {
+                (frame.start_address() + offset, flags)
+            },
+            _ => panic!("{:?} is not mapped", addr),
+        }
+    })
+    .unwrap()
+}
+
+//------------------------------------------------------------------------------
+//  Returns the number of free frames.
+//------------------------------------------------------------------------------
+fn free_frames() -> usize
+{
+    memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames())
+        .unwrap()
+}
+
+#[test_case]
+fn region_is_below_limit()
+{
+    let region = DmaRegion::new(0x3000, DMA_LIMIT_16MIB, DmaCaching::WriteBack)
+        .unwrap();
+    let end = region.phys_addr() + region.len() as u64;
+    assert!(end <= DMA_LIMIT_16MIB);
+    assert!(region.phys_addr().is_aligned(0x4000u64));
+    assert!(region.virt_addr().is_aligned(0x1000u64));
+}
+
+#[test_case]
+fn region_is_contiguous()
+{
+    let region = DmaRegion::new(0x4000, DMA_LIMIT_4GIB, DmaCaching::WriteBack)
+        .unwrap();
+    for offset in (0..0x4000u64).step_by(0x1000)
+    {
+        let (phys, _) = translate(region.virt_addr() + offset);
+        assert_eq!(phys, region.phys_addr() + offset);
+    }
+}
+
+#[test_case]
+fn region_is_zeroed()
+{
+    let mut region = DmaRegion::new(100, DMA_LIMIT_4GIB, DmaCaching::WriteBack)
+        .unwrap();
+    assert!(region.as_slice().iter().all(|&b| b == 0));
+    region.as_mut_slice().fill(0xab);
+    drop(region);
+
+    let region = DmaRegion::new(100, DMA_LIMIT_4GIB, DmaCaching::WriteBack)
+        .unwrap();
+    assert!(region.as_slice().iter().all(|&b| b == 0));
+}
+
+#[test_case]
+fn caching_sets_flags()
+{
+    let uncached = DmaRegion::new(1, DMA_LIMIT_4GIB, DmaCaching::Uncached)
+        .unwrap();
+    let (_, flags) = translate(uncached.virt_addr());
+    assert!(flags.contains(PageTableFlags::NO_CACHE));
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+
+    let write_back = DmaRegion::new(1, DMA_LIMIT_4GIB, DmaCaching::WriteBack)
+        .unwrap();
+    let (_, flags) = translate(write_back.virt_addr());
+    assert!(!flags.contains(PageTableFlags::NO_CACHE));
+}
+
+#[test_case]
+fn drop_frees_frames()
+{
+    let before = free_frames();
+    let region = DmaRegion::new(0x8000, DMA_LIMIT_4GIB, DmaCaching::Uncached)
+        .unwrap();
+    assert_eq!(free_frames(), before - 8);
+    let vma = memory::find_vma(region.virt_addr()).unwrap();
+    assert_eq!(vma.purpose(), memory::VmaPurpose::Dma);
+
+    let virt_addr = region.virt_addr();
+    drop(region);
+    assert_eq!(free_frames(), before);
+    assert!(memory::find_vma(virt_addr).is_none());
+}
+
+#[test_case]
+fn limit_too_low()
+{
+    let result = DmaRegion::new
+    (
+        0x1000,
+        PhysAddr::new(0x1000),
+        DmaCaching::WriteBack
+    );
+    assert_eq!(result.err(), Some(MapError::FrameAllocationFailed));
+    assert_eq!
+    (
+        DmaRegion::new(0, DMA_LIMIT_4GIB, DmaCaching::WriteBack).err(),
+        Some(MapError::InvalidRange)
+    );
+}
+
+#[test_case]
+fn buffer_holds_value()
+{
+    let mut buffer = DmaBuffer::new
+    (
+        [7u32; 16],
+        DMA_LIMIT_4GIB,
+        DmaCaching::Uncached
+    )
+    .unwrap();
+    assert_eq!(buffer[3], 7);
+    buffer[3] = 9;
+
+    let ptr: *const u32 = buffer.virt_addr().as_ptr();
+    assert_eq!(unsafe { ptr.add(3).read_volatile() }, 9);
+    let (phys, _) = translate(buffer.virt_addr());
+    assert_eq!(phys, buffer.phys_addr());
+}
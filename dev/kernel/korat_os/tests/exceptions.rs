@@ -0,0 +1,265 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+//  Every exception that can be raised on purpose in ring 0 is raised once and
+//  recovered from with `expect_exception`. Not covered here:
+//
+//  - #DF is tested by `stack_overflow`, and #MC by `machine_check`, since
+//    their handlers never return.
+//  - #TS needs a task switch, #AC needs ring 3 and #CP needs CET, none of
+//    which exist in the kernel.
+
+use korat_os::interrupts::{ self, CaughtException, Exception };
+
+use bootloader::{ entry_point, BootInfo };
+use core::arch::asm;
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::registers::control::{ Cr0, Cr0Flags, Cr2, Cr4, Cr4Flags };
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Runs `raise`, whose first instruction raising `exception` is `skip` bytes
+//  long, and returns the caught exception.
+//------------------------------------------------------------------------------
+fn catch<F>( exception: Exception, skip: u64, raise: F ) -> CaughtException
+where
+    F: FnOnce(),
+{
+    unsafe { interrupts::expect_exception(exception, skip) };
+    raise();
+    let caught = interrupts::take_caught_exception()
+        .expect("exception was not raised");
+    assert_eq!(caught.exception, exception);
+    caught
+}
+
+#[test_case]
+fn divide_error()
+{
+    let caught = catch(Exception::DivideError, 2, ||
+        unsafe
+        {
+            asm!
+            (
+                "div ecx",
+                inout("eax") 1u32 => _,
+                inout("edx") 0u32 => _,
+                in("ecx") 0u32
+            );
+        }
+    );
+    assert_eq!(caught.error_code, None);
+}
+
+#[test_case]
+fn debug()
+{
+    //  `int1`, which the assembler does not know.
+    catch(Exception::Debug, 0, || unsafe { asm!(".byte 0xf1") });
+}
+
+#[test_case]
+fn non_maskable_interrupt()
+{
+    catch(Exception::NonMaskableInterrupt, 0, || unsafe { asm!("int 2") });
+}
+
+#[test_case]
+fn breakpoint()
+{
+    catch(Exception::Breakpoint, 0, x86_64::instructions::interrupts::int3);
+}
+
+#[test_case]
+fn overflow()
+{
+    //  `into` is not valid in 64-bit mode.
+    catch(Exception::Overflow, 0, || unsafe { asm!("int 4") });
+}
+
+#[test_case]
+fn bound_range_exceeded()
+{
+    //  `bound` is not valid in 64-bit mode.
+    catch(Exception::BoundRangeExceeded, 0, || unsafe { asm!("int 5") });
+}
+
+#[test_case]
+fn invalid_opcode()
+{
+    catch(Exception::InvalidOpcode, 2, || unsafe { asm!("ud2") });
+}
+
+#[test_case]
+fn device_not_available()
+{
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+    catch(Exception::DeviceNotAvailable, 2, || unsafe { asm!("fld1") });
+    unsafe { Cr0::update(|flags| flags.remove(Cr0Flags::TASK_SWITCHED)) };
+}
+
+#[test_case]
+fn coprocessor_segment_overrun()
+{
+    //  Only raised by CPUs with an external x87 coprocessor.
+    catch(Exception::CoprocessorSegmentOverrun, 0, || unsafe
+    {
+        asm!("int 9")
+    });
+}
+
+#[test_case]
+fn segment_not_present()
+{
+    //  The IDT entry of vector 0x81 is not present.
+    let caught = catch(Exception::SegmentNotPresent, 2, || unsafe
+    {
+        asm!("int 0x81")
+    });
+    assert_eq!(caught.error_code, Some(0x81 << 3 | 0b010));
+}
+
+#[test_case]
+fn stack_segment_fault()
+{
+    //  A non-canonical address relative to `rbp` is in the stack segment.
+    let caught = catch(Exception::StackSegmentFault, 4, ||
+        unsafe
+        {
+            asm!
+            (
+                "push rbp",
+                "mov rbp, {addr}",
+                "mov {tmp}, [rbp]",
+                "pop rbp",
+                addr = in(reg) 0x8000_0000_0000_0000u64,
+                tmp = out(reg) _
+            );
+        }
+    );
+    assert_eq!(caught.error_code, Some(0));
+}
+
+#[test_case]
+fn general_protection_fault()
+{
+    //  The selector is past the end of the GDT.
+    let caught = catch(Exception::GeneralProtectionFault, 3, ||
+        unsafe { asm!("mov ds, ax", in("ax") 0x1000u16) }
+    );
+    assert_eq!(caught.error_code, Some(0x1000));
+}
+
+#[test_case]
+fn page_fault()
+{
+    let caught = catch(Exception::PageFault, 3, ||
+        unsafe { asm!("mov {tmp}, [rcx]", in("rcx") 0u64, tmp = out(reg) _) }
+    );
+    assert_eq!(caught.error_code, Some(0));
+    assert_eq!(Cr2::read(), VirtAddr::new(0));
+}
+
+#[test_case]
+fn x87_floating_point()
+{
+    //  Unmask the x87 divide by zero exception. It is raised by the next
+    //  waiting instruction after the division.
+    static CONTROL_WORD: u16 = 0x037b;
+
+    unsafe
+    {
+        Cr0::update(|flags|
+        {
+            flags.insert(Cr0Flags::NUMERIC_ERROR);
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+        });
+    }
+    catch(Exception::X87FloatingPoint, 1, ||
+        unsafe
+        {
+            asm!
+            (
+                "fninit",
+                "fldcw [{control_word}]",
+                "fldz",
+                "fld1",
+                "fdiv st, st(1)",
+                "fwait",
+                "fninit",
+                control_word = in(reg) &CONTROL_WORD
+            );
+        }
+    );
+}
+
+#[test_case]
+fn simd_floating_point()
+{
+    //  Unmask the SSE divide by zero exception. The kernel is built without
+    //  SSE, so the XMM registers are free to use.
+    static MXCSR: u32 = 0x1d80;
+    static DEFAULT_MXCSR: u32 = 0x1f80;
+
+    unsafe
+    {
+        Cr0::update(|flags| flags.remove(Cr0Flags::EMULATE_COPROCESSOR));
+        Cr4::update(|flags|
+        {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE)
+        });
+    }
+    catch(Exception::SimdFloatingPoint, 4, ||
+        unsafe
+        {
+            asm!
+            (
+                "ldmxcsr [{mxcsr}]",
+                "mov eax, 0x3f800000",
+                "movd xmm0, eax",
+                "xorps xmm1, xmm1",
+                "divss xmm0, xmm1",
+                "ldmxcsr [{default_mxcsr}]",
+                mxcsr = in(reg) &MXCSR,
+                default_mxcsr = in(reg) &DEFAULT_MXCSR,
+                out("eax") _
+            );
+        }
+    );
+}
+
+#[test_case]
+fn virtualization()
+{
+    //  Only raised by the CPU inside a virtual machine with EPT violations
+    //  converted to exceptions.
+    catch(Exception::Virtualization, 0, || unsafe { asm!("int 0x14") });
+}
+
+#[test_case]
+fn unexpected_exception_is_not_caught()
+{
+    unsafe { interrupts::expect_exception(Exception::InvalidOpcode, 2) };
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(interrupts::take_caught_exception(), None);
+}
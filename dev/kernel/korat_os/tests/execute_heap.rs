@@ -0,0 +1,134 @@
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+use korat_os::{ exit_qemu, QemuExitCode, serial_print, serial_println };
+use korat_os::memory;
+
+use alloc::boxed::Box;
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use core::sync::atomic::{ AtomicU64, Ordering };
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{ PageTableFlags, Translate };
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::idt::{
+    InterruptDescriptorTable,
+    InterruptStackFrame,
+    PageFaultErrorCode,
+};
+
+lazy_static!
+{
+    static ref TEST_IDT: InterruptDescriptorTable =
+    {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+//  The address of the code on the heap.
+static HEAP_CODE: AtomicU64 = AtomicU64::new(0);
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    serial_print!("execute_heap::execute_heap...\t");
+
+    korat_os::gdt::init_gdt();
+    init_test_idt();
+    korat_os::init_memory(boot_info);
+
+    //  A `ret` instruction.
+    let code = Box::new([0xc3u8; 16]);
+    let code_ptr = code.as_ptr();
+    HEAP_CODE.store(code_ptr as u64, Ordering::SeqCst);
+
+    //  The heap is also reachable through the physical memory mapping.
+    let flags = alias_flags(boot_info, VirtAddr::from_ptr(code_ptr));
+    if !flags.contains(PageTableFlags::NO_EXECUTE)
+    {
+        fail("the alias of the heap is executable");
+    }
+
+    let function: extern "C" fn() = unsafe { core::mem::transmute(code_ptr) };
+    function();
+
+    panic!("Executing heap memory did not fault");
+}
+
+//------------------------------------------------------------------------------
+//  Returns the flags of the page that maps `addr` in the physical memory
+//  mapping.
+//------------------------------------------------------------------------------
+fn alias_flags( boot_info: &'static BootInfo, addr: VirtAddr )
+    -> PageTableFlags
+{
+    memory::with_kernel_memory(|memory|
+    {
+        let phys = match memory.mapper.translate_addr(addr)
+        {
+            Some(phys) => phys,
+            None => fail("the address is not mapped"),
+        };
+        let alias = VirtAddr::new
+        (
+            boot_info.physical_memory_offset + phys.as_u64()
+        );
+        match memory.mapper.translate(alias)
+        {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => fail("the physical memory mapping is missing"),
+        }
+    })
+    .unwrap()
+}
+
+fn fail( message: &str ) -> !
+{
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", message);
+    exit_qemu(QemuExitCode::Failed);
+    korat_os::hlt_loop();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler
+(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+)
+{
+    use x86_64::registers::control::Cr2;
+
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION
+        | PageFaultErrorCode::INSTRUCTION_FETCH;
+    let addr = Cr2::read().as_u64();
+    if addr == HEAP_CODE.load(Ordering::SeqCst) && error_code.contains(expected)
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+    else
+    {
+        serial_println!("[failed]\n");
+        serial_println!("Error: unexpected page fault: {:?}", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    korat_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+pub fn init_test_idt()
+{
+    TEST_IDT.load();
+}
@@ -0,0 +1,190 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::memory::{
+    BitmapFrameAllocator,
+    BuddyFrameAllocator,
+    MAX_ORDER,
+};
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{
+    FrameAllocator,
+    FrameDeallocator,
+    PageSize,
+    PhysFrame,
+    Size2MiB,
+};
+
+entry_point!(main);
+
+static BOOT_INFO: Mutex<Option<&'static BootInfo>> = Mutex::new(None);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    *BOOT_INFO.lock() = Some(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Creates a fresh allocator over the bootloader's memory map.
+//------------------------------------------------------------------------------
+fn bitmap_frame_allocator() -> BitmapFrameAllocator
+{
+    let boot_info = BOOT_INFO.lock().expect("boot info is not set");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) }
+}
+
+//------------------------------------------------------------------------------
+//  Creates a fresh buddy allocator over the bootloader's memory map.
+//------------------------------------------------------------------------------
+fn buddy_frame_allocator() -> BuddyFrameAllocator
+{
+    let boot_info = BOOT_INFO.lock().expect("boot info is not set");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) }
+}
+
+#[test_case]
+fn frame_counts()
+{
+    let allocator = bitmap_frame_allocator();
+    assert!(allocator.total_frames() > 0);
+    assert!(allocator.used_frames() > 0);
+    assert_eq!
+    (
+        allocator.total_frames(),
+        allocator.free_frames() + allocator.used_frames()
+    );
+}
+
+#[test_case]
+fn allocate_and_deallocate()
+{
+    let mut allocator = bitmap_frame_allocator();
+    let free_frames = allocator.free_frames();
+
+    let frame = allocator.allocate_frame().expect("allocation failed");
+    assert_eq!(allocator.free_frames(), free_frames - 1);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_frames);
+
+    let reused = allocator.allocate_frame().expect("allocation failed");
+    assert_eq!(reused, frame);
+}
+
+#[test_case]
+fn many_frames()
+{
+    const COUNT: usize = 512;
+
+    let mut allocator = bitmap_frame_allocator();
+    let free_frames = allocator.free_frames();
+
+    let mut frames: [Option<PhysFrame>; COUNT] = [None; COUNT];
+    for i in 0..COUNT
+    {
+        let frame = allocator.allocate_frame().expect("allocation failed");
+        assert!(frames[..i].iter().all(|f| *f != Some(frame)));
+        frames[i] = Some(frame);
+    }
+    assert_eq!(allocator.free_frames(), free_frames - COUNT);
+
+    for frame in frames.iter().flatten()
+    {
+        unsafe { allocator.deallocate_frame(*frame) };
+    }
+    assert_eq!(allocator.free_frames(), free_frames);
+}
+
+#[test_case]
+fn buddy_frame_counts()
+{
+    let allocator = buddy_frame_allocator();
+    assert!(allocator.total_frames() > 0);
+    assert_eq!
+    (
+        allocator.total_frames(),
+        allocator.free_frames() + allocator.used_frames()
+    );
+
+    let free_frames: usize = (0..=MAX_ORDER)
+        .map(|order| allocator.free_blocks(order) << order)
+        .sum();
+    assert_eq!(free_frames, allocator.free_frames());
+}
+
+#[test_case]
+fn buddy_contiguous_allocation()
+{
+    let mut allocator = buddy_frame_allocator();
+    let free_frames = allocator.free_frames();
+
+    let addr = allocator.allocate(3).expect("allocation failed");
+    assert!(addr.is_aligned(8 * 4096u64));
+    assert_eq!(allocator.free_frames(), free_frames - 8);
+
+    unsafe { allocator.deallocate(addr, 3) };
+    assert_eq!(allocator.free_frames(), free_frames);
+}
+
+#[test_case]
+fn buddy_coalescing()
+{
+    let mut allocator = buddy_frame_allocator();
+    let mut free_blocks = [0; MAX_ORDER + 1];
+    for (order, count) in free_blocks.iter_mut().enumerate()
+    {
+        *count = allocator.free_blocks(order);
+    }
+
+    //  Split a large block into single frames and free them again.
+    let mut frames: [Option<PhysFrame>; 64] = [None; 64];
+    for frame in frames.iter_mut()
+    {
+        *frame = allocator.allocate_frame();
+        assert!(frame.is_some());
+    }
+    for frame in frames.iter().flatten()
+    {
+        unsafe { allocator.deallocate_frame(*frame) };
+    }
+
+    for (order, count) in free_blocks.iter().enumerate()
+    {
+        assert_eq!(allocator.free_blocks(order), *count);
+    }
+}
+
+#[test_case]
+fn buddy_huge_frame()
+{
+    let mut allocator = buddy_frame_allocator();
+    let free_frames = allocator.free_frames();
+
+    let frame: PhysFrame<Size2MiB> = allocator
+        .allocate_frame()
+        .expect("allocation failed");
+    assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+    assert_eq!(allocator.free_frames(), free_frames - 512);
+
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free_frames);
+}
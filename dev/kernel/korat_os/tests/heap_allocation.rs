@@ -6,9 +6,10 @@
 
 extern crate alloc;
 
-use korat_os::allocator::HEAP_SIZE;
+use korat_os::allocator::{ self, HEAP_SIZE };
 
 use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
 use bootloader::{ entry_point, BootInfo };
 use core::panic::PanicInfo;
@@ -17,18 +18,8 @@ entry_point!(main);
 
 fn main( boot_info: &'static BootInfo ) -> !
 {
-    use korat_os::allocator;
-    use korat_os::memory::{ self, BootInfoFrameAllocator };
-    use x86_64::VirtAddr;
     korat_os::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe
-    {
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    korat_os::init_memory(boot_info);
 
     test_main();
     loop {}
@@ -70,3 +61,108 @@ fn many_boxes()
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn aligned_allocation()
+{
+    use alloc::alloc::{ alloc, dealloc, Layout };
+
+    for align in [8, 64, 512, 4096]
+    {
+        let layout = Layout::from_size_align(100, align).unwrap();
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null());
+        assert_eq!(ptr as usize % align, 0);
+        unsafe { dealloc(ptr, layout) };
+    }
+}
+
+#[test_case]
+fn heap_growth()
+{
+    //  Keep far more than `HEAP_SIZE` bytes alive at the same time.
+    let mut blocks = Vec::new();
+    for i in 0..64
+    {
+        blocks.push(vec![i as u8; 16 * 1024]);
+    }
+
+    for (i, block) in blocks.iter().enumerate()
+    {
+        assert!(block.iter().all(|&b| b == i as u8));
+    }
+    assert!(allocator::heap_size() > HEAP_SIZE);
+}
+
+#[test_case]
+fn large_allocation()
+{
+    let n = 4 * 1024 * 1024 / 8;
+    let vec = vec![1u64; n];
+    assert_eq!(vec.iter().sum::<u64>(), n as u64);
+}
+
+#[test_case]
+fn stats_count_allocations()
+{
+    let before = allocator::heap_stats();
+    let value = Box::new([0u8; 100]);
+    let during = allocator::heap_stats();
+
+    let diff = during.diff(&before);
+    assert_eq!(diff.allocations, 1);
+    assert_eq!(diff.frees, 0);
+    assert_eq!(diff.bytes, 100);
+    assert!(during.peak_bytes >= during.current_bytes);
+    assert_eq!
+    (
+        during.size_classes[3] - before.size_classes[3],
+        1,
+        "100 bytes belong to the 65..=128 class"
+    );
+
+    drop(value);
+    let diff = allocator::heap_stats().diff(&before);
+    assert_eq!(diff.frees, 1);
+    assert!(diff.is_balanced());
+}
+
+#[test_case]
+fn stats_detect_leak()
+{
+    let before = allocator::heap_stats();
+    let leaked = Box::leak(Box::new(7u64));
+    assert_eq!(*leaked, 7);
+
+    let diff = allocator::heap_stats().diff(&before);
+    assert!(!diff.is_balanced());
+    assert_eq!(diff.bytes, 8);
+}
+
+#[test_case]
+fn stats_track_peak()
+{
+    let before = allocator::heap_stats();
+    {
+        let _block = vec![0u8; 256 * 1024];
+    }
+    let after = allocator::heap_stats();
+    assert!(after.peak_bytes >= before.current_bytes + 256 * 1024);
+    assert!(after.diff(&before).is_balanced());
+}
+
+#[test_case]
+fn stats_record_failure()
+{
+    use alloc::alloc::{ alloc, Layout };
+
+    let layout = Layout::from_size_align(64 * 1024 * 1024, 8).unwrap();
+    let before = allocator::heap_stats();
+    let ptr = unsafe { alloc(layout) };
+    assert!(ptr.is_null());
+
+    let after = allocator::heap_stats();
+    assert_eq!(after.diff(&before).failures, 1);
+    assert_eq!(after.last_failure, Some(layout));
+    assert!(after.diff(&before).is_balanced());
+}
@@ -0,0 +1,44 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use korat_os::{ exit_qemu, QemuExitCode, serial_print, serial_println };
+
+use alloc::alloc::{ alloc, dealloc, Layout };
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    double_free();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn double_free()
+{
+    serial_print!("heap_double_free::double_free...\t");
+
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    unsafe
+    {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+}
+
+#[panic_handler]
+fn panic( _info: &PanicInfo ) -> !
+{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
@@ -0,0 +1,236 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::memory::{ self, MappingSize, RegionMapping, VmaPurpose };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{ PageTableFlags, Translate };
+use x86_64::structures::paging::mapper::{ MappedFrame, TranslateResult };
+
+const KIB: u64 = 1024;
+const MIB: u64 = 1024 * KIB;
+const GIB: u64 = 1024 * MIB;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the physical address the given address is mapped to.
+//------------------------------------------------------------------------------
+fn translate_addr( addr: VirtAddr ) -> Option<PhysAddr>
+{
+    memory::with_kernel_memory(|memory| memory.mapper.translate_addr(addr))
+        .unwrap()
+}
+
+//------------------------------------------------------------------------------
+//  Returns the size of the page the given address is mapped with.
+//------------------------------------------------------------------------------
+fn page_size( addr: VirtAddr ) -> u64
+{
+    memory::with_kernel_memory(|memory|
+    {
+        match memory.mapper.translate(addr)
+        {
+            TranslateResult::Mapped { frame, .. } => frame.size(),
+            _ => 0,
+        }
+    })
+    .unwrap()
+}
+
+//------------------------------------------------------------------------------
+//  Returns the expected number of pages of each size.
+//------------------------------------------------------------------------------
+fn pages( pages_4kib: u64, pages_2mib: u64, pages_1gib: u64 ) -> RegionMapping
+{
+    RegionMapping { pages_4kib, pages_2mib, pages_1gib }
+}
+
+//------------------------------------------------------------------------------
+//  Reserves a virtual range aligned to `align`.
+//------------------------------------------------------------------------------
+fn reserve( size: u64, align: u64 ) -> VirtAddr
+{
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::allocate_vma(size, align, VmaPurpose::Other, flags)
+        .unwrap()
+        .start()
+}
+
+//------------------------------------------------------------------------------
+//  Checks that every page of `start..start + size` is mapped and holds what is
+//  written to it.
+//------------------------------------------------------------------------------
+fn check_region( start: VirtAddr, size: u64 )
+{
+    for offset in (0..size).step_by(4096)
+    {
+        let addr = start + offset;
+        assert!(translate_addr(addr).is_some());
+
+        let ptr: *mut u64 = addr.as_mut_ptr();
+        unsafe
+        {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(offset);
+            assert_eq!(ptr.read_volatile(), offset);
+        }
+    }
+}
+
+#[test_case]
+fn map_with_2mib_pages()
+{
+    let size = 6 * MIB;
+    let start = reserve(size, 2 * MIB);
+    let mapping = memory::map_region
+    (
+        start,
+        size,
+        PageTableFlags::WRITABLE,
+        MappingSize::Size2MiB
+    )
+    .unwrap();
+    assert_eq!(mapping, pages(0, 3, 0));
+
+    //  Each 2 MiB page maps a contiguous physical range.
+    for page in 0..3
+    {
+        let page_start = start + page * 2 * MIB;
+        let frame = translate_addr(page_start).unwrap();
+        assert!(frame.is_aligned(2 * MIB));
+        assert_eq!(page_size(page_start), 2 * MIB);
+        for offset in [4 * KIB, MIB + 123, 2 * MIB - 1]
+        {
+            let addr = page_start + offset;
+            assert_eq!(translate_addr(addr), Some(frame + offset));
+        }
+    }
+    check_region(start, size);
+
+    unsafe { memory::unmap_region(start, size) };
+    assert_eq!(translate_addr(start), None);
+    memory::free_vma(start);
+}
+
+#[test_case]
+fn map_with_4kib_pages()
+{
+    let size = 6 * MIB;
+    let start = reserve(size, 2 * MIB);
+    let mapping = memory::map_region
+    (
+        start,
+        size,
+        PageTableFlags::WRITABLE,
+        MappingSize::Size4KiB
+    )
+    .unwrap();
+    assert_eq!(mapping, pages(1536, 0, 0));
+
+    for offset in (0..size).step_by(4096)
+    {
+        let addr = start + offset;
+        assert_eq!(page_size(addr), 4 * KIB);
+        let frame = translate_addr(addr).unwrap();
+        assert_eq!(translate_addr(addr + 100u64), Some(frame + 100u64));
+    }
+    check_region(start, size);
+
+    unsafe { memory::unmap_region(start, size) };
+    assert_eq!(translate_addr(start + 4 * MIB), None);
+    memory::free_vma(start);
+}
+
+#[test_case]
+fn unaligned_head_and_tail()
+{
+    //  8 KiB before and after two 2 MiB pages.
+    let vma_start = reserve(8 * MIB, 2 * MIB);
+    let start = vma_start + (2 * MIB - 8 * KIB);
+    let size = 4 * MIB + 16 * KIB;
+    let mapping = memory::map_region
+    (
+        start,
+        size,
+        PageTableFlags::WRITABLE,
+        MappingSize::Size2MiB
+    )
+    .unwrap();
+    assert_eq!(mapping, pages(4, 2, 0));
+    assert_eq!(page_size(start), 4 * KIB);
+    assert_eq!(page_size(start + 8 * KIB), 2 * MIB);
+    assert_eq!(page_size(start + size - 4 * KIB), 4 * KIB);
+    check_region(start, size);
+
+    unsafe { memory::unmap_region(start, size) };
+    memory::free_vma(vma_start);
+}
+
+#[test_case]
+fn map_physical_memory()
+{
+    let start = reserve(GIB, GIB);
+    let max_size = MappingSize::largest_supported();
+    let mapping = unsafe
+    {
+        memory::map_physical_region
+        (
+            start,
+            PhysAddr::new(0),
+            GIB,
+            PageTableFlags::WRITABLE,
+            max_size
+        )
+    }
+    .unwrap();
+
+    if memory::huge_pages_1gib_supported()
+    {
+        assert_eq!(mapping, pages(0, 0, 1));
+        assert_eq!(page_size(start), GIB);
+    }
+    else
+    {
+        assert_eq!(mapping, pages(0, 512, 0));
+        assert_eq!(page_size(start), 2 * MIB);
+    }
+
+    //  The VGA buffer is at the same offset in the new mapping.
+    let vga_buffer = start + 0xb8000u64;
+    assert_eq!(translate_addr(vga_buffer), Some(PhysAddr::new(0xb8000)));
+    let is_huge = memory::with_kernel_memory(|memory|
+    {
+        !matches!
+        (
+            memory.mapper.translate(vga_buffer),
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(_), .. }
+        )
+    })
+    .unwrap();
+    assert!(is_huge);
+
+    unsafe { memory::unmap_physical_region(start, GIB) };
+    assert_eq!(translate_addr(vga_buffer), None);
+    memory::free_vma(start);
+}
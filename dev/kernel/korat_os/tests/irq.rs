@@ -0,0 +1,219 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use korat_os::interrupts::{
+    self,
+    InterruptIndex,
+    IrqError,
+    IrqStatus,
+    MAX_IRQ_HANDLERS,
+};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use core::sync::atomic::{ AtomicUsize, Ordering };
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//  An IRQ line without a device.
+const IRQ: u8 = 5;
+
+//------------------------------------------------------------------------------
+//  Raises the interrupt of `IRQ` in software.
+//------------------------------------------------------------------------------
+fn raise()
+{
+    unsafe
+    {
+        core::arch::asm!("int {}", const interrupts::IRQ_VECTOR_BASE + IRQ)
+    };
+}
+
+//------------------------------------------------------------------------------
+//  Returns a handler that counts its calls in `count` and returns `status`.
+//------------------------------------------------------------------------------
+fn counter
+(
+    count: &Arc<AtomicUsize>,
+    status: IrqStatus,
+) -> impl Fn() -> IrqStatus + Send + Sync + 'static
+{
+    let count = count.clone();
+    move ||
+    {
+        count.fetch_add(1, Ordering::Relaxed);
+        status
+    }
+}
+
+#[test_case]
+fn handler_runs()
+{
+    let count = Arc::new(AtomicUsize::new(0));
+    let id = interrupts::register_irq_handler
+    (
+        IRQ,
+        counter(&count, IrqStatus::Handled)
+    )
+    .unwrap();
+    assert_eq!(id.irq(), IRQ);
+
+    raise();
+    raise();
+    assert_eq!(count.load(Ordering::Relaxed), 2);
+
+    assert!(interrupts::unregister_irq_handler(id));
+    assert!(!interrupts::unregister_irq_handler(id));
+    raise();
+    assert_eq!(count.load(Ordering::Relaxed), 2);
+}
+
+#[test_case]
+fn shared_line_runs_every_handler()
+{
+    let first = Arc::new(AtomicUsize::new(0));
+    let second = Arc::new(AtomicUsize::new(0));
+    let ids =
+    [
+        interrupts::register_irq_handler
+        (
+            IRQ,
+            counter(&first, IrqStatus::NotHandled)
+        )
+        .unwrap(),
+        interrupts::register_irq_handler
+        (
+            IRQ,
+            counter(&second, IrqStatus::Handled)
+        )
+        .unwrap(),
+    ];
+
+    let before = interrupts::irq_stats(IRQ).unwrap();
+    raise();
+    let after = interrupts::irq_stats(IRQ).unwrap();
+    assert_eq!(first.load(Ordering::Relaxed), 1);
+    assert_eq!(second.load(Ordering::Relaxed), 1);
+    assert_eq!(after.interrupts, before.interrupts + 1);
+    assert_eq!(after.unhandled, before.unhandled);
+
+    for id in ids
+    {
+        assert!(interrupts::unregister_irq_handler(id));
+    }
+}
+
+#[test_case]
+fn unhandled_interrupts_are_counted()
+{
+    let count = Arc::new(AtomicUsize::new(0));
+    let id = interrupts::register_irq_handler
+    (
+        IRQ,
+        counter(&count, IrqStatus::NotHandled)
+    )
+    .unwrap();
+
+    let before = interrupts::irq_stats(IRQ).unwrap();
+    raise();
+    let after = interrupts::irq_stats(IRQ).unwrap();
+    assert_eq!(after.unhandled, before.unhandled + 1);
+
+    interrupts::unregister_irq_handler(id);
+    raise();
+    let last = interrupts::irq_stats(IRQ).unwrap();
+    assert_eq!(last.unhandled, after.unhandled + 1);
+}
+
+#[test_case]
+fn line_is_unmasked_while_registered()
+{
+    assert!(!interrupts::irq_has_handlers(IRQ));
+    assert_eq!(interrupts::irq_vector(IRQ), None);
+
+    let id = interrupts::register_irq_handler(IRQ, || IrqStatus::Handled)
+        .unwrap();
+    assert!(interrupts::irq_has_handlers(IRQ));
+    assert_eq!
+    (
+        interrupts::irq_vector(IRQ),
+        Some(interrupts::IRQ_VECTOR_BASE + IRQ)
+    );
+
+    interrupts::unregister_irq_handler(id);
+    assert!(!interrupts::irq_has_handlers(IRQ));
+    assert_eq!(interrupts::irq_vector(IRQ), None);
+}
+
+#[test_case]
+fn too_many_handlers()
+{
+    let ids: Vec<_> = (0..MAX_IRQ_HANDLERS)
+        .map(|_|
+            interrupts::register_irq_handler(IRQ, || IrqStatus::Handled)
+                .unwrap()
+        )
+        .collect();
+    assert_eq!
+    (
+        interrupts::register_irq_handler(IRQ, || IrqStatus::Handled),
+        Err(IrqError::TooManyHandlers)
+    );
+
+    for id in ids
+    {
+        interrupts::unregister_irq_handler(id);
+    }
+}
+
+#[test_case]
+fn invalid_irq()
+{
+    assert_eq!
+    (
+        interrupts::register_irq_handler
+        (
+            interrupts::IRQ_LINES as u8,
+            || IrqStatus::Handled
+        ),
+        Err(IrqError::InvalidIrq)
+    );
+    assert_eq!(interrupts::irq_stats(interrupts::IRQ_LINES as u8), None);
+}
+
+#[test_case]
+fn timer_is_dispatched()
+{
+    let timer = InterruptIndex::Timer.irq();
+    assert!(interrupts::irq_has_handlers(timer));
+    assert!(interrupts::irq_has_handlers(InterruptIndex::Keyboard.irq()));
+
+    let before = interrupts::irq_stats(timer).unwrap();
+    x86_64::instructions::hlt();
+    x86_64::instructions::hlt();
+    let after = interrupts::irq_stats(timer).unwrap();
+    assert!(after.interrupts > before.interrupts);
+    assert_eq!(after.unhandled, before.unhandled);
+}
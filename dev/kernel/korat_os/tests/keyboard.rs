@@ -0,0 +1,203 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::keyboard::{ self, KeyStream, Layout, SCANCODE_QUEUE_SIZE };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::sync::atomic::{ AtomicBool, Ordering };
+use core::task::{ Context, Poll, RawWaker, RawWakerVTable, Waker };
+use futures_util::stream::Stream;
+use pc_keyboard::DecodedKey;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//  Scancode set 1 codes of the `A` key.
+const A_PRESSED: u8 = 0x1e;
+const A_RELEASED: u8 = 0x9e;
+
+//  Scancode set 1 codes of other keys. A key is released with the code plus
+//  `RELEASED`.
+const CAPS_LOCK: u8 = 0x3a;
+const CTRL: u8 = 0x1d;
+const SHIFT: u8 = 0x2a;
+const KEY_2: u8 = 0x03;
+const KEY_3: u8 = 0x04;
+const KEY_C: u8 = 0x2e;
+const KEY_S: u8 = 0x1f;
+const RELEASED: u8 = 0x80;
+
+//  Set by the waker of `flag_waker`.
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+//------------------------------------------------------------------------------
+//  Returns a waker that sets `WOKEN`.
+//------------------------------------------------------------------------------
+fn flag_waker() -> Waker
+{
+    fn clone( _: *const () ) -> RawWaker
+    {
+        RawWaker::new(core::ptr::null(), &VTABLE)
+    }
+    fn wake( _: *const () )
+    {
+        WOKEN.store(true, Ordering::SeqCst);
+    }
+    fn drop( _: *const () ) {}
+
+    static VTABLE: RawWakerVTable =
+        RawWakerVTable::new(clone, wake, wake, drop);
+
+    unsafe { Waker::from_raw(clone(core::ptr::null())) }
+}
+
+//------------------------------------------------------------------------------
+//  Presses and releases a key, with `modifier` held down if given, and returns
+//  the decoded key.
+//------------------------------------------------------------------------------
+fn type_key( modifier: Option<u8>, scancode: u8 ) -> Option<DecodedKey>
+{
+    if let Some(modifier) = modifier
+    {
+        keyboard::add_scancode(modifier);
+    }
+    keyboard::add_scancode(scancode);
+    keyboard::add_scancode(scancode | RELEASED);
+    if let Some(modifier) = modifier
+    {
+        keyboard::add_scancode(modifier | RELEASED);
+    }
+
+    let key = keyboard::try_read_key();
+    assert_eq!(keyboard::try_read_key(), None);
+    key
+}
+
+#[test_case]
+fn keys_are_decoded()
+{
+    keyboard::add_scancode(A_PRESSED);
+    keyboard::add_scancode(A_RELEASED);
+
+    assert_eq!(keyboard::read_key(), DecodedKey::Unicode('a'));
+    assert_eq!(keyboard::try_read_key(), None);
+}
+
+#[test_case]
+fn releases_are_not_keys()
+{
+    keyboard::add_scancode(A_RELEASED);
+    assert_eq!(keyboard::try_read_key(), None);
+}
+
+#[test_case]
+fn overflow_drops_scancodes()
+{
+    let dropped = keyboard::dropped_scancodes();
+    for _ in 0..=SCANCODE_QUEUE_SIZE
+    {
+        keyboard::add_scancode(A_PRESSED);
+    }
+    assert_eq!(keyboard::dropped_scancodes(), dropped + 1);
+
+    let mut keys = 0;
+    while let Some(key) = keyboard::try_read_key()
+    {
+        assert_eq!(key, DecodedKey::Unicode('a'));
+        keys += 1;
+    }
+    assert_eq!(keys, SCANCODE_QUEUE_SIZE);
+
+    //  There is room again.
+    keyboard::add_scancode(A_RELEASED);
+    assert_eq!(keyboard::dropped_scancodes(), dropped + 1);
+    assert_eq!(keyboard::try_read_key(), None);
+}
+
+#[test_case]
+fn stream_is_woken_by_input()
+{
+    let waker = flag_waker();
+    let mut cx = Context::from_waker(&waker);
+    let mut stream = KeyStream::new();
+
+    WOKEN.store(false, Ordering::SeqCst);
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+
+    keyboard::add_scancode(A_PRESSED);
+    assert!(WOKEN.load(Ordering::SeqCst));
+    assert_eq!
+    (
+        Pin::new(&mut stream).poll_next(&mut cx),
+        Poll::Ready(Some(DecodedKey::Unicode('a')))
+    );
+
+    keyboard::add_scancode(A_RELEASED);
+    assert_eq!(Pin::new(&mut stream).poll_next(&mut cx), Poll::Pending);
+}
+
+#[test_case]
+fn ctrl_keys_are_control_codes()
+{
+    assert_eq!(type_key(Some(CTRL), KEY_C), Some(DecodedKey::Unicode('\u{3}')));
+    assert_eq!(type_key(None, KEY_C), Some(DecodedKey::Unicode('c')));
+}
+
+#[test_case]
+fn layouts_are_switched()
+{
+    assert_eq!(keyboard::layout(), Layout::Us104);
+    assert_eq!(type_key(Some(SHIFT), KEY_2), Some(DecodedKey::Unicode('@')));
+
+    keyboard::set_layout(Layout::Uk105);
+    assert_eq!(keyboard::layout(), Layout::Uk105);
+    assert_eq!(type_key(Some(SHIFT), KEY_3), Some(DecodedKey::Unicode('£')));
+
+    keyboard::set_layout(Layout::Jis106);
+    assert_eq!(type_key(Some(SHIFT), KEY_2), Some(DecodedKey::Unicode('"')));
+
+    keyboard::set_layout(Layout::Dvorak);
+    assert_eq!(type_key(None, KEY_S), Some(DecodedKey::Unicode('o')));
+
+    keyboard::set_layout(Layout::Us104);
+    assert_eq!(type_key(None, KEY_S), Some(DecodedKey::Unicode('s')));
+}
+
+#[test_case]
+fn caps_lock_survives_layout_switch()
+{
+    assert!(!keyboard::lock_keys().caps_lock);
+    assert!(keyboard::lock_keys().num_lock);
+
+    type_key(None, CAPS_LOCK);
+    assert!(keyboard::lock_keys().caps_lock);
+    assert_eq!(type_key(None, A_PRESSED), Some(DecodedKey::Unicode('A')));
+
+    keyboard::set_layout(Layout::Uk105);
+    assert!(keyboard::lock_keys().caps_lock);
+    assert_eq!(type_key(None, A_PRESSED), Some(DecodedKey::Unicode('A')));
+
+    type_key(None, CAPS_LOCK);
+    assert!(!keyboard::lock_keys().caps_lock);
+    assert_eq!(type_key(None, A_PRESSED), Some(DecodedKey::Unicode('a')));
+    keyboard::set_layout(Layout::Us104);
+}
@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::allocator::HEAP_START;
+use korat_os::memory::{ self, LazyRegionError, VmaPurpose };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{ PageTableFlags, Translate };
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns whether the given address is mapped.
+//------------------------------------------------------------------------------
+fn is_mapped( addr: VirtAddr ) -> bool
+{
+    memory::with_kernel_memory(|memory|
+    {
+        memory.mapper.translate_addr(addr).is_some()
+    })
+    .unwrap()
+}
+
+#[test_case]
+fn pages_are_mapped_on_first_access()
+{
+    let start = VirtAddr::new(0x_5555_0000_0000);
+    let flags = PageTableFlags::WRITABLE;
+    memory::reserve_lazy(start, 16 * 4096, flags).unwrap();
+    assert!(!is_mapped(start));
+
+    let ptr: *mut u64 = (start + 5 * 4096u64).as_mut_ptr();
+    unsafe
+    {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+
+    assert!(is_mapped(start + 5 * 4096u64));
+    assert!(!is_mapped(start + 4 * 4096u64));
+    assert!(!is_mapped(start + 6 * 4096u64));
+
+    unsafe { memory::release_lazy(start).unwrap() };
+    assert!(!is_mapped(start + 5 * 4096u64));
+}
+
+#[test_case]
+fn whole_region_is_usable()
+{
+    let start = VirtAddr::new(0x_5555_1000_0000);
+    let size = 64 * 4096;
+    memory::reserve_lazy(start, size, PageTableFlags::WRITABLE).unwrap();
+
+    let slice = unsafe
+    {
+        core::slice::from_raw_parts_mut
+        (
+            start.as_mut_ptr::<u8>(),
+            size as usize
+        )
+    };
+    assert!(slice.iter().all(|&b| b == 0));
+    slice.fill(0xab);
+    assert!(slice.iter().all(|&b| b == 0xab));
+
+    unsafe { memory::release_lazy(start).unwrap() };
+}
+
+#[test_case]
+fn invalid_reservations()
+{
+    let start = VirtAddr::new(0x_5555_2000_0000);
+    let flags = PageTableFlags::WRITABLE;
+
+    assert_eq!
+    (
+        memory::reserve_lazy(start + 1u64, 4096, flags),
+        Err(LazyRegionError::InvalidRange)
+    );
+    assert_eq!
+    (
+        memory::reserve_lazy(start, 0, flags),
+        Err(LazyRegionError::InvalidRange)
+    );
+
+    memory::reserve_lazy(start, 4 * 4096, flags).unwrap();
+    assert_eq!
+    (
+        memory::reserve_lazy(start + 3 * 4096u64, 4 * 4096, flags),
+        Err(LazyRegionError::Overlap)
+    );
+    assert!(memory::find_lazy(start + 3 * 4096u64).is_some());
+    assert!(memory::find_lazy(start + 4 * 4096u64).is_none());
+
+    unsafe { memory::release_lazy(start).unwrap() };
+    assert!(memory::find_lazy(start).is_none());
+}
+
+#[test_case]
+fn regions_are_kernel_vmas()
+{
+    let start = VirtAddr::new(0x_5555_3000_0000);
+    let flags = PageTableFlags::WRITABLE;
+
+    //  The heap is a kernel VMA, so a lazy region cannot overlap it.
+    assert_eq!
+    (
+        memory::reserve_lazy(VirtAddr::new(HEAP_START as u64), 4096, flags),
+        Err(LazyRegionError::Overlap)
+    );
+
+    memory::reserve_lazy(start, 4 * 4096, flags).unwrap();
+    let vma = memory::find_vma(start).unwrap();
+    assert_eq!(vma.purpose(), VmaPurpose::Lazy);
+    assert!(memory::kernel_layout().contains(&vma));
+
+    unsafe { memory::release_lazy(start).unwrap() };
+    assert_eq!(memory::find_vma(start), None);
+}
@@ -0,0 +1,34 @@
+#![no_std]
+#![no_main]
+
+use korat_os::{ exit_qemu, QemuExitCode, serial_print, serial_println };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    machine_check();
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn machine_check()
+{
+    serial_print!("machine_check::machine_check...\t");
+    unsafe { core::arch::asm!("int 18") };
+}
+
+#[panic_handler]
+fn panic( _info: &PanicInfo ) -> !
+{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
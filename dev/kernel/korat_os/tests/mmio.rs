@@ -0,0 +1,110 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::memory::{ self, MapError, VmaPurpose };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use spin::Once;
+use x86_64::{ VirtAddr, PhysAddr };
+use x86_64::structures::paging::{ PageTableFlags, Translate };
+
+//  The VGA text buffer: 80 x 25 cells of a character and a color byte.
+const VGA_BUFFER: u64 = 0xb8000;
+const VGA_BUFFER_SIZE: usize = 80 * 25 * 2;
+
+static PHYSICAL_MEMORY_OFFSET: Once<u64> = Once::new();
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+    PHYSICAL_MEMORY_OFFSET.call_once(|| boot_info.physical_memory_offset);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the physical address the given address is mapped to.
+//------------------------------------------------------------------------------
+fn translate( addr: VirtAddr ) -> Option<PhysAddr>
+{
+    memory::with_kernel_memory(|memory| memory.mapper.translate_addr(addr))
+        .unwrap()
+}
+
+#[test_case]
+fn map_vga_buffer()
+{
+    let flags = PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::NO_EXECUTE;
+    let mut vga_buffer = unsafe
+    {
+        memory::map_mmio(PhysAddr::new(VGA_BUFFER), VGA_BUFFER_SIZE, flags)
+    }
+    .unwrap();
+    assert_eq!
+    (
+        translate(vga_buffer.virt_addr()),
+        Some(PhysAddr::new(VGA_BUFFER))
+    );
+
+    let vma = memory::find_vma(vga_buffer.virt_addr()).unwrap();
+    assert_eq!(vma.purpose(), VmaPurpose::Mmio);
+
+    //  Write `OK` to the last row and read it back through the physical
+    //  memory mapping.
+    let offset = 24 * 80 * 2;
+    vga_buffer.write::<u16>(offset, 0x0f4f);
+    vga_buffer.write::<u16>(offset + 2, 0x0f4b);
+    assert_eq!(vga_buffer.read::<u16>(offset), 0x0f4f);
+
+    let physical_memory_offset = *PHYSICAL_MEMORY_OFFSET.wait().unwrap();
+    let phys_ptr = (physical_memory_offset + VGA_BUFFER) as *const u16;
+    let cell = unsafe { phys_ptr.add(offset / 2 + 1).read_volatile() };
+    assert_eq!(cell, 0x0f4b);
+
+    //  The mapping and the virtual range are released on drop.
+    let virt_addr = vga_buffer.virt_addr();
+    drop(vga_buffer);
+    assert_eq!(translate(virt_addr), None);
+    assert!(memory::find_vma(virt_addr).is_none());
+}
+
+#[test_case]
+fn unaligned_region()
+{
+    let phys_addr = PhysAddr::new(VGA_BUFFER + 0xff8);
+    let region = unsafe
+    {
+        memory::map_mmio(phys_addr, 16, PageTableFlags::WRITABLE)
+    }
+    .unwrap();
+
+    assert_eq!(region.virt_addr().as_u64() % 4096, 0xff8);
+    assert_eq!(translate(region.virt_addr()), Some(phys_addr));
+    assert_eq!(translate(region.virt_addr() + 8u64), Some(phys_addr + 8u64));
+}
+
+#[test_case]
+fn empty_region()
+{
+    let result = unsafe
+    {
+        memory::map_mmio(PhysAddr::new(VGA_BUFFER), 0, PageTableFlags::WRITABLE)
+    };
+    assert!(matches!(result, Err(MapError::InvalidRange)));
+}
@@ -0,0 +1,125 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use korat_os::allocator::HEAP_START;
+use korat_os::memory::{ self, MappedRange, PageTableInspector };
+
+use alloc::vec::Vec;
+use bootloader::{ entry_point, BootInfo };
+use core::fmt;
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{ PageTableFlags, Translate };
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns an inspector for the active page tables.
+//------------------------------------------------------------------------------
+fn inspector() -> PageTableInspector
+{
+    let offset = memory::with_kernel_memory(|m| m.mapper.phys_offset())
+        .unwrap();
+    unsafe { PageTableInspector::active(offset) }
+}
+
+//------------------------------------------------------------------------------
+//  A `fmt::Write` that counts lines.
+//------------------------------------------------------------------------------
+struct LineCounter(usize);
+
+impl fmt::Write for LineCounter
+{
+    fn write_str( &mut self, s: &str ) -> fmt::Result
+    {
+        self.0 += s.matches('\n').count();
+        Ok(())
+    }
+}
+
+#[test_case]
+fn ranges_are_merged_and_ordered()
+{
+    let mut ranges: Vec<MappedRange> = Vec::new();
+    inspector().for_each_range(|range| ranges.push(range));
+    assert!(!ranges.is_empty());
+
+    for pair in ranges.windows(2)
+    {
+        let end = pair[0].virt_start.as_u64() + pair[0].size;
+        assert!(end <= pair[1].virt_start.as_u64());
+    }
+
+    //  The initial heap is mapped to new frames page by page, so it is at
+    //  least one range of writable 4 KiB pages.
+    let heap = VirtAddr::new(HEAP_START as u64);
+    let range = ranges.iter().find(|r| r.contains(heap)).unwrap();
+    assert_eq!(range.page_size, 4096);
+    assert!(range.flags.contains(PageTableFlags::WRITABLE));
+}
+
+#[test_case]
+fn translation_matches_mapper()
+{
+    let inspector = inspector();
+    let local = 0u64;
+    let addrs = [
+        VirtAddr::new(HEAP_START as u64 + 0x123),
+        VirtAddr::from_ptr(&local),
+        VirtAddr::new(korat_os::hlt_loop as fn() -> ! as usize as u64),
+    ];
+
+    for addr in addrs
+    {
+        let translation = inspector.translate(addr);
+        let expected = memory::with_kernel_memory(|m|
+        {
+            m.mapper.translate_addr(addr)
+        })
+        .unwrap();
+        assert_eq!(translation.phys_addr, expected);
+
+        let step = translation.steps[0].unwrap();
+        assert_eq!(step.level, 4);
+        assert_eq!(step.index, u16::from(addr.p4_index()));
+        assert!(step.flags.contains(PageTableFlags::PRESENT));
+    }
+}
+
+#[test_case]
+fn unmapped_translation()
+{
+    let translation = inspector().translate(VirtAddr::new(0x_7fff_0000_0000));
+    assert_eq!(translation.phys_addr, None);
+}
+
+#[test_case]
+fn dump_page_tables()
+{
+    let mut counter = LineCounter(0);
+    inspector().write_ranges(&mut counter).unwrap();
+
+    let mut ranges = 0;
+    inspector().for_each_range(|_| ranges += 1);
+    assert_eq!(counter.0, ranges);
+}
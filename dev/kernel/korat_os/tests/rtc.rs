@@ -0,0 +1,141 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use korat_os::interrupts::{ self, InterruptIndex };
+use korat_os::time::{
+    self,
+    DateTime,
+    Duration,
+    RtcError,
+    MAX_RTC_RATE,
+    MIN_RTC_RATE,
+};
+
+use alloc::format;
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Returns the date and time with the given fields.
+//------------------------------------------------------------------------------
+fn date_time
+(
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+) -> DateTime
+{
+    DateTime { year, month, day, hour, minute, second }
+}
+
+#[test_case]
+fn date_is_plausible()
+{
+    let now = time::read_rtc();
+
+    assert!((2020..2200).contains(&now.year), "{}", now);
+    assert!((1..=12).contains(&now.month), "{}", now);
+    assert!((1..=31).contains(&now.day), "{}", now);
+    assert!(now.hour < 24, "{}", now);
+    assert!(now.minute < 60, "{}", now);
+    assert!(now.second < 60, "{}", now);
+}
+
+#[test_case]
+fn unix_timestamps()
+{
+    assert_eq!(date_time(1970, 1, 1, 0, 0, 0).unix_timestamp(), 0);
+    assert_eq!
+    (
+        date_time(1999, 12, 31, 23, 59, 59).unix_timestamp(),
+        946_684_799
+    );
+    assert_eq!(date_time(2000, 3, 1, 0, 0, 0).unix_timestamp(), 951_868_800);
+    assert_eq!
+    (
+        date_time(2024, 2, 29, 12, 30, 15).unix_timestamp(),
+        1_709_209_815
+    );
+}
+
+#[test_case]
+fn date_is_formatted()
+{
+    assert_eq!
+    (
+        format!("{}", date_time(2024, 2, 9, 8, 5, 3)),
+        "2024-02-09 08:05:03"
+    );
+}
+
+#[test_case]
+fn unix_time_advances()
+{
+    let start = time::unix_time();
+    time::sleep(Duration::from_millis(1100));
+    let end = time::unix_time();
+
+    assert!(end > start);
+    assert!(end - start <= 2);
+}
+
+#[test_case]
+fn periodic_interrupt()
+{
+    let rtc = InterruptIndex::RealTimeClock.irq();
+    assert_eq!(rtc, 8);
+
+    //  1024 Hz
+    assert_eq!(time::enable_rtc_interrupt(6), Ok(()));
+    assert!(interrupts::irq_has_handlers(rtc));
+
+    let start = time::rtc_interrupts();
+    time::sleep(Duration::from_millis(50));
+    assert!(time::rtc_interrupts() > start + 10);
+
+    time::disable_rtc_interrupt();
+    assert!(!interrupts::irq_has_handlers(rtc));
+    let stopped = time::rtc_interrupts();
+    time::sleep(Duration::from_millis(10));
+    assert_eq!(time::rtc_interrupts(), stopped);
+}
+
+#[test_case]
+fn invalid_rates()
+{
+    assert_eq!
+    (
+        time::enable_rtc_interrupt(MIN_RTC_RATE - 1),
+        Err(RtcError::InvalidRate)
+    );
+    assert_eq!
+    (
+        time::enable_rtc_interrupt(MAX_RTC_RATE + 1),
+        Err(RtcError::InvalidRate)
+    );
+}
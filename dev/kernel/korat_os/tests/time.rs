@@ -0,0 +1,116 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::time::{
+    self,
+    Duration,
+    Instant,
+    TimerError,
+    DEFAULT_TIMER_FREQUENCY,
+    PIT_FREQUENCY,
+};
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn timer_runs_at_default_frequency()
+{
+    assert_eq!(time::timer_frequency(), DEFAULT_TIMER_FREQUENCY);
+}
+
+#[test_case]
+fn ticks_and_uptime_advance()
+{
+    let ticks = time::ticks();
+    let uptime = time::uptime();
+    time::sleep(Duration::from_millis(10));
+
+    assert!(time::ticks() >= ticks + 10);
+    assert!(time::uptime() >= uptime + Duration::from_millis(10));
+}
+
+#[test_case]
+fn sleep_lasts_at_least_the_duration()
+{
+    let start = Instant::now();
+    time::sleep(Duration::from_millis(25));
+    let elapsed = start.elapsed();
+
+    assert!(elapsed >= Duration::from_millis(25));
+    assert!(elapsed < Duration::from_secs(1));
+}
+
+#[test_case]
+fn zero_sleep_returns()
+{
+    time::sleep(Duration::ZERO);
+}
+
+#[test_case]
+fn instant_arithmetic()
+{
+    let now = Instant::now();
+    let later = now + Duration::from_millis(5);
+
+    assert!(later > now);
+    assert_eq!(later - now, Duration::from_millis(5));
+    assert_eq!(now - later, Duration::ZERO);
+    assert_eq!(later - Duration::from_millis(5), now);
+    assert_eq!(now.checked_sub(Duration::MAX), None);
+}
+
+#[test_case]
+fn frequency_can_be_changed()
+{
+    assert_eq!(time::set_timer_frequency(100), Ok(()));
+    assert_eq!(time::timer_frequency(), 100);
+
+    let ticks = time::ticks();
+    time::sleep(Duration::from_millis(50));
+    let elapsed = time::ticks() - ticks;
+    assert!((5..50).contains(&elapsed));
+
+    assert_eq!(time::set_timer_frequency(DEFAULT_TIMER_FREQUENCY), Ok(()));
+}
+
+#[test_case]
+fn invalid_frequencies()
+{
+    assert_eq!
+    (
+        time::set_timer_frequency(0),
+        Err(TimerError::InvalidFrequency)
+    );
+    assert_eq!
+    (
+        time::set_timer_frequency(18),
+        Err(TimerError::InvalidFrequency)
+    );
+    assert_eq!
+    (
+        time::set_timer_frequency(PIT_FREQUENCY + 1),
+        Err(TimerError::InvalidFrequency)
+    );
+    assert_eq!(time::timer_frequency(), DEFAULT_TIMER_FREQUENCY);
+}
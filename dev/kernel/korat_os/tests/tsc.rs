@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::time::{ self, Duration, Instant };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn tsc_is_calibrated()
+{
+    assert!(time::tsc_supported());
+
+    let calibration = time::tsc_calibration().expect("TSC is not calibrated");
+    assert!(calibration.frequency > 1_000_000);
+    assert_eq!(calibration.invariant, time::tsc_invariant());
+
+    //  Calibrating again returns the first result.
+    assert_eq!(time::calibrate_tsc(), Ok(calibration));
+}
+
+#[test_case]
+fn clock_matches_pit_ticks()
+{
+    //  Start right after a tick, so both clocks measure from the same point.
+    let ticks = time::ticks();
+    while time::ticks() == ticks {}
+    let start_ticks = time::ticks();
+    let start_nanos = time::monotonic_nanos();
+
+    time::sleep(Duration::from_millis(200));
+
+    let ticks = time::ticks() - start_ticks;
+    let nanos = time::monotonic_nanos() - start_nanos;
+    let pit_nanos = ticks * 1_000_000_000 / u64::from(time::timer_frequency());
+
+    //  Allow 5% and one tick of difference.
+    let tolerance = pit_nanos / 20 + 1_000_000;
+    assert!
+    (
+        nanos.abs_diff(pit_nanos) <= tolerance,
+        "TSC: {} ns, PIT: {} ns",
+        nanos,
+        pit_nanos
+    );
+}
+
+#[test_case]
+fn clock_is_monotonic()
+{
+    let mut last = time::monotonic_nanos();
+    for _ in 0..10_000
+    {
+        let now = time::monotonic_nanos();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn clock_has_sub_tick_resolution()
+{
+    //  Two readings within one tick still differ.
+    let start = time::monotonic_nanos();
+    let mut now = start;
+    for _ in 0..1_000_000
+    {
+        now = time::monotonic_nanos();
+        if now != start
+        {
+            break;
+        }
+    }
+    assert!(now - start < 1_000_000);
+    assert_ne!(now, start);
+}
+
+#[test_case]
+fn udelay_waits()
+{
+    let start = Instant::now();
+    time::udelay(500);
+    let elapsed = start.elapsed();
+
+    assert!(elapsed >= Duration::from_micros(500));
+    assert!(elapsed < Duration::from_millis(100));
+}
+
+#[test_case]
+fn ndelay_waits()
+{
+    let start = time::monotonic_nanos();
+    time::ndelay(2_000);
+    assert!(time::monotonic_nanos() - start >= 2_000);
+}
@@ -0,0 +1,152 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use korat_os::allocator::HEAP_START;
+use korat_os::memory::{ self, Vma, VmaError, VmaPurpose, VmaTree };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//------------------------------------------------------------------------------
+//  Creates an area of `pages` pages at `start`.
+//------------------------------------------------------------------------------
+fn vma( start: u64, pages: u64 ) -> Vma
+{
+    Vma::new
+    (
+        VirtAddr::new(start),
+        pages * 4096,
+        VmaPurpose::Other,
+        PageTableFlags::PRESENT
+    )
+    .unwrap()
+}
+
+#[test_case]
+fn insert_rejects_overlap()
+{
+    let mut tree = VmaTree::new();
+    tree.insert(vma(0x10000, 4)).unwrap();
+    tree.insert(vma(0x20000, 4)).unwrap();
+
+    assert_eq!(tree.insert(vma(0x13000, 1)), Err(VmaError::Overlap(vma(0x10000, 4))));
+    assert_eq!(tree.insert(vma(0x0f000, 2)), Err(VmaError::Overlap(vma(0x10000, 4))));
+    assert_eq!(tree.insert(vma(0x18000, 16)), Err(VmaError::Overlap(vma(0x20000, 4))));
+    tree.insert(vma(0x14000, 12)).unwrap();
+
+    assert_eq!(tree.iter().count(), 3);
+    assert_eq!(tree.find(VirtAddr::new(0x14000)), Some(vma(0x14000, 12)));
+    assert_eq!(tree.find(VirtAddr::new(0x24000)), None);
+}
+
+#[test_case]
+fn allocate_fills_gaps()
+{
+    let flags = PageTableFlags::PRESENT;
+    let mut tree = VmaTree::new();
+    tree.insert(vma(0x12000, 2)).unwrap();
+
+    //  Fits before the existing area, leaving a guard page.
+    let a = tree.allocate(0x10000, 0x20000, 0x1000, 0, VmaPurpose::Stack, flags)
+        .unwrap();
+    assert_eq!(a.start().as_u64(), 0x10000);
+
+    //  Does not fit before it, so it goes after it and its guard page.
+    let b = tree.allocate(0x10000, 0x20000, 0x2000, 0, VmaPurpose::Stack, flags)
+        .unwrap();
+    assert_eq!(b.start().as_u64(), 0x15000);
+
+    let c = tree.allocate(0x10000, 0x20000, 0x1000, 0x8000, VmaPurpose::Mmio, flags)
+        .unwrap();
+    assert_eq!(c.start().as_u64(), 0x18000);
+
+    assert_eq!
+    (
+        tree.allocate(0x10000, 0x20000, 0x10000, 0, VmaPurpose::Other, flags),
+        Err(VmaError::OutOfSpace)
+    );
+
+    assert_eq!(tree.remove(b.start()), Some(b));
+    assert_eq!(tree.iter().count(), 3);
+}
+
+#[test_case]
+fn kernel_layout_is_recorded()
+{
+    let heap = memory::find_vma(VirtAddr::new(HEAP_START as u64)).unwrap();
+    assert_eq!(heap.purpose(), VmaPurpose::Heap);
+
+    let layout = memory::kernel_layout();
+    assert!(layout.iter().any(|v| v.purpose() == VmaPurpose::PhysicalMemory));
+    assert!(layout.iter().any(|v| v.purpose() == VmaPurpose::Boot));
+    for pair in layout.windows(2)
+    {
+        assert!(!pair[0].overlaps(&pair[1]));
+    }
+
+    //  The kernel code is mapped by the bootloader.
+    let code = VirtAddr::new(korat_os::hlt_loop as fn() -> ! as usize as u64);
+    assert_eq!(memory::find_vma(code).unwrap().purpose(), VmaPurpose::Boot);
+}
+
+#[test_case]
+fn allocate_kernel_vma()
+{
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let a = memory::allocate_vma(0x4000, 0, VmaPurpose::Stack, flags).unwrap();
+    let b = memory::allocate_vma(0x4000, 0, VmaPurpose::Stack, flags).unwrap();
+    assert!(!a.overlaps(&b));
+    assert!(a.start().as_u64() >= memory::VMA_WINDOW_START);
+
+    assert_eq!
+    (
+        memory::insert_vma(a.start(), 0x1000, VmaPurpose::Other, flags),
+        Err(VmaError::Overlap(a))
+    );
+
+    assert_eq!(memory::free_vma(a.start()), Some(a));
+    assert_eq!(memory::free_vma(b.start()), Some(b));
+    assert_eq!(memory::find_vma(a.start()), None);
+}
+
+#[test_case]
+fn huge_allocations_fail()
+{
+    let flags = PageTableFlags::PRESENT;
+
+    //  The largest page-aligned size, and the largest alignment.
+    assert_eq!
+    (
+        memory::allocate_vma(!0xfff, 0, VmaPurpose::Other, flags),
+        Err(VmaError::OutOfSpace)
+    );
+    assert_eq!
+    (
+        memory::allocate_vma(0x1000, 1 << 63, VmaPurpose::Other, flags),
+        Err(VmaError::OutOfSpace)
+    );
+}
@@ -0,0 +1,129 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(korat_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use korat_os::interrupts::{ self, IrqStatus, WORK_QUEUE_SIZE };
+use korat_os::time::{ self, Duration };
+
+use bootloader::{ entry_point, BootInfo };
+use core::panic::PanicInfo;
+use core::sync::atomic::{ AtomicBool, AtomicUsize, Ordering };
+
+entry_point!(main);
+
+fn main( boot_info: &'static BootInfo ) -> !
+{
+    korat_os::init();
+    korat_os::init_memory(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic( info: &PanicInfo ) -> !
+{
+    korat_os::test_panic_handler(info)
+}
+
+//  An IRQ line without a device.
+const IRQ: u8 = 5;
+
+static SUM: AtomicUsize = AtomicUsize::new(0);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+//------------------------------------------------------------------------------
+//  Adds `data` to `SUM`, and records whether interrupts were enabled.
+//------------------------------------------------------------------------------
+fn add( data: usize )
+{
+    ENABLED.store(interrupts_enabled(), Ordering::Relaxed);
+    SUM.fetch_add(data, Ordering::Relaxed);
+}
+
+fn interrupts_enabled() -> bool
+{
+    x86_64::instructions::interrupts::are_enabled()
+}
+
+//------------------------------------------------------------------------------
+//  An IRQ handler that defers its work.
+//------------------------------------------------------------------------------
+fn deferring_handler() -> IrqStatus
+{
+    interrupts::queue_work(add, 7);
+    IrqStatus::Handled
+}
+
+#[test_case]
+fn work_runs_later()
+{
+    SUM.store(0, Ordering::Relaxed);
+    let before = interrupts::work_stats();
+
+    assert!(interrupts::queue_work(add, 1));
+    assert!(interrupts::queue_work(add, 2));
+    assert!(interrupts::has_pending_work());
+    assert_eq!(SUM.load(Ordering::Relaxed), 0);
+
+    interrupts::run_pending_work();
+    assert!(!interrupts::has_pending_work());
+    assert_eq!(SUM.load(Ordering::Relaxed), 3);
+    assert!(ENABLED.load(Ordering::Relaxed));
+
+    let after = interrupts::work_stats();
+    assert_eq!(after.queued, before.queued + 2);
+    assert_eq!(after.run, before.run + 2);
+    assert_eq!(after.dropped, before.dropped);
+}
+
+#[test_case]
+fn interrupt_work_runs_when_idle()
+{
+    SUM.store(0, Ordering::Relaxed);
+    ENABLED.store(false, Ordering::Relaxed);
+    let id = interrupts::register_irq_handler(IRQ, deferring_handler)
+        .unwrap();
+
+    unsafe
+    {
+        core::arch::asm!("int {}", const interrupts::IRQ_VECTOR_BASE + IRQ)
+    };
+    assert!(interrupts::has_pending_work());
+
+    //  Sleeping is a safe point.
+    time::sleep(Duration::from_millis(1));
+    assert!(!interrupts::has_pending_work());
+    assert_eq!(SUM.load(Ordering::Relaxed), 7);
+    assert!(ENABLED.load(Ordering::Relaxed));
+
+    assert!(interrupts::unregister_irq_handler(id));
+}
+
+#[test_case]
+fn full_queue_drops_work()
+{
+    SUM.store(0, Ordering::Relaxed);
+    let before = interrupts::work_stats();
+
+    for _ in 0..WORK_QUEUE_SIZE
+    {
+        assert!(interrupts::queue_work(add, 1));
+    }
+    assert!(!interrupts::queue_work(add, 1));
+
+    interrupts::run_pending_work();
+    assert_eq!(SUM.load(Ordering::Relaxed), WORK_QUEUE_SIZE);
+
+    let after = interrupts::work_stats();
+    let queued = WORK_QUEUE_SIZE as u64;
+    assert_eq!(after.queued, before.queued + queued);
+    assert_eq!(after.run, before.run + queued);
+    assert_eq!(after.dropped, before.dropped + 1);
+
+    //  The slots are free again.
+    assert!(interrupts::queue_work(add, 1));
+    interrupts::run_pending_work();
+}